@@ -0,0 +1,280 @@
+// TBS Crossfire (CRSF) serial protocol, between a receiver and a flight
+// controller. This is based on the protocol as implemented by Betaflight and
+// ExpressLRS, as there is no public specification.
+//
+// The serial link is 420000 baud 8N1, not inverted, and full duplex: the
+// receiver sends channels and link statistics, and the flight controller
+// sends telemetry back.
+#![no_std]
+
+use embedded_hal::serial::Write;
+use heapless::Vec;
+use rc_output::{ Link, RcOutput };
+
+pub mod telemetry;
+
+pub use telemetry::{ Frame, Parser, Telemetry };
+
+// Every frame starts with the address of the device it's for, then the length
+// of the rest of the frame: the type, the payload and the CRC.
+pub const ADDRESS_FLIGHT_CONTROLLER: u8 = 0xc8;
+pub const ADDRESS_RADIO_TRANSMITTER: u8 = 0xea;
+pub const ADDRESS_RECEIVER: u8 = 0xec;
+pub const ADDRESS_TRANSMITTER: u8 = 0xee;
+
+pub const MAX_FRAME_SIZE: usize = 64;
+pub const MAX_PAYLOAD_SIZE: usize = MAX_FRAME_SIZE - 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    Gps = 0x02,
+    Battery = 0x08,
+    LinkStatistics = 0x14,
+    RcChannelsPacked = 0x16,
+    Attitude = 0x1e,
+}
+
+// The CRC covers the type and the payload. It's CRC-8/DVB-S2.
+#[derive(Clone, Copy)]
+struct Crc8(u8);
+
+impl Crc8 {
+    const POLYNOMIAL: u8 = 0xd5;
+
+    fn update(&mut self, value: u8) {
+        let mut crc = self.0 ^ value;
+        for _ in 0..8 {
+            if (crc & 0x80) != 0 {
+                crc = (crc << 1) ^ Self::POLYNOMIAL;
+            } else {
+                crc <<= 1;
+            }
+        }
+
+        self.0 = crc;
+    }
+}
+
+pub fn send_frame<Out: Write<u8>>(out: &mut Out, frame_type: FrameType, payload: &[u8]) -> nb::Result<(), Out::Error> {
+    let mut crc = Crc8(0);
+
+    out.write(ADDRESS_FLIGHT_CONTROLLER)?;
+    out.write(payload.len() as u8 + 2)?;
+
+    let mut write = |c| {
+        crc.update(c);
+        out.write(c)
+    };
+
+    write(frame_type as u8)?;
+    for byte in payload {
+        write(*byte)?;
+    }
+
+    out.write(crc.0)?;
+    out.flush()?;
+    Ok(())
+}
+
+// RC channels are 16 channels of 11 bits each, packed least significant bit
+// first, exactly like SBUS, with the same values for the reference positions.
+pub use sbus::{ CHANNELS, LOW, NEUTRAL, HIGH, scale };
+
+/// Send the channels. The values use the whole range of a u16, like
+/// `RcOutput::send`, and any channels left out are sent as neutral.
+pub fn send_channels<Out: Write<u8>>(out: &mut Out, values: &[u16]) -> nb::Result<(), Out::Error> {
+    send_frame(out, FrameType::RcChannelsPacked, &sbus::pack(values))
+}
+
+/// The quality of the radio link, for the flight controller's OSD and failsafe.
+/// RSSI is in -dBm, i.e. 50 is -50dBm, and link quality is a percentage.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkStatistics {
+    pub uplink_rssi_1: u8,
+    pub uplink_rssi_2: u8,
+    pub uplink_link_quality: u8,
+    pub uplink_snr: i8,
+    pub active_antenna: u8,
+    pub rf_mode: u8,
+    pub uplink_tx_power: u8,
+    pub downlink_rssi: u8,
+    pub downlink_link_quality: u8,
+    pub downlink_snr: i8,
+}
+
+const LINK_STATISTICS_SIZE: usize = 10;
+
+impl LinkStatistics {
+    pub fn to_bytes(&self) -> [u8; LINK_STATISTICS_SIZE] {
+        [
+            self.uplink_rssi_1,
+            self.uplink_rssi_2,
+            self.uplink_link_quality,
+            self.uplink_snr as u8,
+            self.active_antenna,
+            self.rf_mode,
+            self.uplink_tx_power,
+            self.downlink_rssi,
+            self.downlink_link_quality,
+            self.downlink_snr as u8,
+        ]
+    }
+}
+
+pub fn send_link_statistics<Out: Write<u8>>(out: &mut Out, statistics: &LinkStatistics) -> nb::Result<(), Out::Error> {
+    send_frame(out, FrameType::LinkStatistics, &statistics.to_bytes())
+}
+
+/// CRSF as an RcOutput: the channels, then the link statistics. There's no
+/// failsafe flag: the flight controller sees the link quality drop, and the
+/// receiver sends its failsafe positions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encoder;
+
+impl RcOutput for Encoder {
+    fn send<W: Write<u8>>(&self, out: &mut W, link: &Link, values: &[u16]) -> nb::Result<(), W::Error> {
+        let statistics = LinkStatistics {
+            uplink_rssi_1: link.rssi,
+            uplink_rssi_2: link.rssi,
+            uplink_link_quality: link.quality,
+            ..Default::default()
+        };
+        send_channels(out, values)?;
+        send_link_statistics(out, &statistics)
+    }
+}
+
+pub struct CrsfBuffer(pub Vec<u8, MAX_FRAME_SIZE>);
+
+impl Write<u8> for CrsfBuffer {
+    type Error = u8;
+
+    /// Writes a single word to the serial interface
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.0.push(word)?;
+        Ok(())
+    }
+
+    /// Ensures that none of the previously written words are still buffered
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl Default for CrsfBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CrsfBuffer {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn encode_channels(&mut self, values: &[u16]) {
+        self.0.clear();
+        send_channels(self, values).unwrap();
+    }
+
+    pub fn encode_link_statistics(&mut self, statistics: &LinkStatistics) {
+        self.0.clear();
+        send_link_statistics(self, statistics).unwrap();
+    }
+}
+
+// An RC channels frame is 26 bytes, which takes 0.6mS at 420000 baud.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sbus::{ CHANNEL_BITS, CHANNEL_BYTES as CHANNELS_SIZE };
+
+    fn parse(bytes: &[u8]) -> Frame {
+        let mut parser = Parser::new();
+        let mut frame = None;
+        for byte in bytes {
+            match parser.parse(*byte) {
+                Ok(parsed) => frame = Some(parsed),
+                Err(nb::Error::WouldBlock) => {},
+                Err(nb::Error::Other(error)) => panic!("{:?}", error),
+            }
+        }
+        frame.unwrap()
+    }
+
+    fn channel(payload: &[u8], channel: usize) -> u16 {
+        let bit = channel * CHANNEL_BITS;
+        let byte = bit / 8;
+        let mut bits = 0u32;
+        for (i, b) in payload[byte..].iter().take(3).enumerate() {
+            bits |= (*b as u32) << (i * 8);
+        }
+        ((bits >> (bit % 8)) & 0x7ff) as u16
+    }
+
+    #[test]
+    fn crc() {
+        // Check value for CRC-8/DVB-S2
+        let mut crc = Crc8(0);
+        for byte in b"123456789" {
+            crc.update(*byte);
+        }
+        assert_eq!(crc.0, 0xbc);
+    }
+
+    #[test]
+    fn channels_frame() {
+        let values = [0, 0x8000, 0xffff, 0x4000];
+        let mut buffer = CrsfBuffer::new();
+        buffer.encode_channels(&values);
+
+        assert_eq!(buffer.0[0], ADDRESS_FLIGHT_CONTROLLER);
+        assert_eq!(buffer.0[1] as usize, CHANNELS_SIZE + 2);
+        assert_eq!(buffer.0.len(), CHANNELS_SIZE + 4);
+
+        let frame = parse(&buffer.0);
+        assert_eq!(frame.frame_type, FrameType::RcChannelsPacked as u8);
+        assert_eq!(channel(&frame.payload, 0), LOW);
+        assert_eq!(channel(&frame.payload, 1), NEUTRAL);
+        assert_eq!(channel(&frame.payload, 2), HIGH);
+        assert_eq!(channel(&frame.payload, 3), scale(0x4000));
+        for i in 4..CHANNELS {
+            assert_eq!(channel(&frame.payload, i), NEUTRAL);
+        }
+    }
+
+    #[test]
+    fn link_statistics_frame() {
+        let statistics = LinkStatistics {
+            uplink_rssi_1: 60,
+            uplink_rssi_2: 61,
+            uplink_link_quality: 95,
+            uplink_snr: -5,
+            ..Default::default()
+        };
+        let mut buffer = CrsfBuffer::new();
+        buffer.encode_link_statistics(&statistics);
+
+        let frame = parse(&buffer.0);
+        assert_eq!(frame.frame_type, FrameType::LinkStatistics as u8);
+        assert_eq!(&frame.payload[..], &statistics.to_bytes());
+        assert_eq!(frame.payload[3], 0xfb);
+    }
+
+    #[test]
+    fn encoder_sends_channels_and_link_statistics() {
+        use rc_output::LinkState;
+
+        let mut buffer = rc_output::FrameBuffer::new();
+        let link = Link { state: LinkState::Hold, quality: 80, rssi: 66 };
+        buffer.encode(&Encoder, &link, &[0xffff]);
+        assert_eq!(buffer.0.len(), CHANNELS_SIZE + 4 + LINK_STATISTICS_SIZE + 4);
+
+        let (channels, statistics) = buffer.0.split_at(CHANNELS_SIZE + 4);
+        assert_eq!(channel(&parse(channels).payload, 0), HIGH);
+        let frame = parse(statistics);
+        assert_eq!(frame.frame_type, FrameType::LinkStatistics as u8);
+        assert_eq!(frame.payload[..3], [66, 66, 80]);
+    }
+}
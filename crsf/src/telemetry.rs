@@ -0,0 +1,336 @@
+// Parsing frames from the flight controller, one byte at a time, e.g. from
+// the serial receive interrupt. Multi-byte fields are big-endian.
+
+use core::convert::TryFrom;
+
+use heapless::Vec;
+
+use crate::{
+    Crc8, FrameType, MAX_PAYLOAD_SIZE,
+    ADDRESS_FLIGHT_CONTROLLER, ADDRESS_RADIO_TRANSMITTER, ADDRESS_RECEIVER, ADDRESS_TRANSMITTER,
+};
+
+/// A frame whose CRC has been checked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub frame_type: u8,
+    pub payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The length wasn't long enough for a type and a CRC, or too long for a frame
+    Length(u8),
+    Crc { expected: u8, received: u8 },
+    /// A frame we don't know how to interpret
+    Unsupported(u8),
+    /// The payload was too short for the type of frame
+    Size { frame_type: u8, size: usize },
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Address,
+    Length,
+    Type,
+    Payload,
+    Crc,
+}
+
+pub struct Parser {
+    state: State,
+    length: usize,
+    crc: Crc8,
+    frame_type: u8,
+    payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Parser {
+            state: State::Address,
+            length: 0,
+            crc: Crc8(0),
+            frame_type: 0,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Feed the next byte from the serial port to the parser.
+    ///
+    /// Returns `WouldBlock` until a whole frame has been received. Bytes are
+    /// discarded until a known address is seen.
+    pub fn parse(&mut self, byte: u8) -> nb::Result<Frame, Error> {
+        match self.state {
+            State::Address => {
+                match byte {
+                    ADDRESS_FLIGHT_CONTROLLER | ADDRESS_RADIO_TRANSMITTER |
+                    ADDRESS_RECEIVER | ADDRESS_TRANSMITTER => self.state = State::Length,
+                    _ => {},
+                }
+            },
+
+            State::Length => {
+                if byte < 2 || byte as usize > MAX_PAYLOAD_SIZE + 2 {
+                    self.state = State::Address;
+                    return Err(nb::Error::Other(Error::Length(byte)));
+                }
+                self.length = byte as usize - 2;
+                self.crc = Crc8(0);
+                self.payload.clear();
+                self.state = State::Type;
+            },
+
+            State::Type => {
+                self.crc.update(byte);
+                self.frame_type = byte;
+                self.state = if self.length == 0 { State::Crc } else { State::Payload };
+            },
+
+            State::Payload => {
+                self.crc.update(byte);
+                // Can't overflow: the length has already been checked
+                let _ = self.payload.push(byte);
+                if self.payload.len() == self.length {
+                    self.state = State::Crc;
+                }
+            },
+
+            State::Crc => {
+                self.state = State::Address;
+                if byte != self.crc.0 {
+                    return Err(nb::Error::Other(Error::Crc { expected: self.crc.0, received: byte }));
+                }
+
+                return Ok(Frame {
+                    frame_type: self.frame_type,
+                    payload: self.payload.clone(),
+                });
+            },
+        }
+
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Battery {
+    /// In units of 0.1V
+    pub voltage: u16,
+    /// In units of 0.1A
+    pub current: u16,
+    /// Used capacity in mAh, only 24 bits
+    pub capacity: u32,
+    /// Percentage
+    pub remaining: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Attitude {
+    /// All in units of 0.0001 radians
+    pub pitch: i16,
+    pub roll: i16,
+    pub yaw: i16,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Gps {
+    /// In units of 0.0000001 degrees
+    pub latitude: i32,
+    pub longitude: i32,
+    /// In units of 0.1 km/h
+    pub ground_speed: u16,
+    /// In units of 0.01 degrees
+    pub heading: u16,
+    /// In meters, offset by 1000, so 1000 is sea level
+    pub altitude: u16,
+    pub satellites: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Telemetry {
+    Battery(Battery),
+    Attitude(Attitude),
+    Gps(Gps),
+}
+
+// Read big-endian fields from the front of a payload
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&self.0[..N]);
+        self.0 = &self.0[N..];
+        bytes
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.take())
+    }
+
+    fn i16(&mut self) -> i16 {
+        i16::from_be_bytes(self.take())
+    }
+
+    fn u24(&mut self) -> u32 {
+        let [a, b, c] = self.take::<3>();
+        u32::from_be_bytes([0, a, b, c])
+    }
+
+    fn i32(&mut self) -> i32 {
+        i32::from_be_bytes(self.take())
+    }
+}
+
+const GPS_SIZE: usize = 15;
+const BATTERY_SIZE: usize = 8;
+const ATTITUDE_SIZE: usize = 6;
+
+impl TryFrom<&Frame> for Telemetry {
+    type Error = Error;
+
+    fn try_from(frame: &Frame) -> Result<Telemetry, Error> {
+        let size = match frame.frame_type {
+            t if t == FrameType::Gps as u8 => GPS_SIZE,
+            t if t == FrameType::Battery as u8 => BATTERY_SIZE,
+            t if t == FrameType::Attitude as u8 => ATTITUDE_SIZE,
+            t => return Err(Error::Unsupported(t)),
+        };
+
+        if frame.payload.len() < size {
+            return Err(Error::Size { frame_type: frame.frame_type, size: frame.payload.len() });
+        }
+
+        let mut fields = Fields(&frame.payload);
+        Ok(match frame.frame_type {
+            t if t == FrameType::Gps as u8 => Telemetry::Gps(Gps {
+                latitude: fields.i32(),
+                longitude: fields.i32(),
+                ground_speed: fields.u16(),
+                heading: fields.u16(),
+                altitude: fields.u16(),
+                satellites: fields.u8(),
+            }),
+            t if t == FrameType::Battery as u8 => Telemetry::Battery(Battery {
+                voltage: fields.u16(),
+                current: fields.u16(),
+                capacity: fields.u24(),
+                remaining: fields.u8(),
+            }),
+            _ => Telemetry::Attitude(Attitude {
+                pitch: fields.i16(),
+                roll: fields.i16(),
+                yaw: fields.i16(),
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ send_frame, CrsfBuffer };
+
+    fn parse(bytes: &[u8]) -> Option<Result<Telemetry, Error>> {
+        let mut parser = Parser::new();
+        let mut result = None;
+        for byte in bytes {
+            match parser.parse(*byte) {
+                Ok(frame) => result = Some(Telemetry::try_from(&frame)),
+                Err(nb::Error::WouldBlock) => {},
+                Err(nb::Error::Other(error)) => result = Some(Err(error)),
+            }
+        }
+        result
+    }
+
+    fn frame(frame_type: FrameType, payload: &[u8]) -> CrsfBuffer {
+        let mut buffer = CrsfBuffer::new();
+        send_frame(&mut buffer, frame_type, payload).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn battery() {
+        let buffer = frame(FrameType::Battery, &[0x00, 0xa8, 0x01, 0x2c, 0x00, 0x04, 0xb0, 75]);
+        assert_eq!(parse(&buffer.0), Some(Ok(Telemetry::Battery(Battery {
+            voltage: 168,
+            current: 300,
+            capacity: 1200,
+            remaining: 75,
+        }))));
+    }
+
+    #[test]
+    fn attitude() {
+        let buffer = frame(FrameType::Attitude, &[0x03, 0xe8, 0xfc, 0x18, 0x7a, 0xb7]);
+        assert_eq!(parse(&buffer.0), Some(Ok(Telemetry::Attitude(Attitude {
+            pitch: 1000,
+            roll: -1000,
+            yaw: 31415,
+        }))));
+    }
+
+    #[test]
+    fn gps() {
+        let mut payload = Vec::<u8, GPS_SIZE>::new();
+        payload.extend_from_slice(&515_000_000i32.to_be_bytes()).unwrap();
+        payload.extend_from_slice(&(-1_250_000i32).to_be_bytes()).unwrap();
+        payload.extend_from_slice(&123u16.to_be_bytes()).unwrap();
+        payload.extend_from_slice(&9000u16.to_be_bytes()).unwrap();
+        payload.extend_from_slice(&1050u16.to_be_bytes()).unwrap();
+        payload.push(9).unwrap();
+
+        let buffer = frame(FrameType::Gps, &payload);
+        assert_eq!(parse(&buffer.0), Some(Ok(Telemetry::Gps(Gps {
+            latitude: 515_000_000,
+            longitude: -1_250_000,
+            ground_speed: 123,
+            heading: 9000,
+            altitude: 1050,
+            satellites: 9,
+        }))));
+    }
+
+    #[test]
+    fn skips_noise_between_frames() {
+        let buffer = frame(FrameType::Attitude, &[0, 1, 0, 2, 0, 3]);
+        let mut bytes = Vec::<u8, 32>::new();
+        bytes.extend_from_slice(&[0x00, 0x55]).unwrap();
+        bytes.extend_from_slice(&buffer.0).unwrap();
+        assert_eq!(parse(&bytes), Some(Ok(Telemetry::Attitude(Attitude { pitch: 1, roll: 2, yaw: 3 }))));
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut buffer = frame(FrameType::Attitude, &[0, 1, 0, 2, 0, 3]);
+        buffer.0[4] ^= 0x10;
+        match parse(&buffer.0) {
+            Some(Err(Error::Crc { .. })) => {},
+            other => panic!("expected a CRC error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_short_payload() {
+        let buffer = frame(FrameType::Battery, &[0, 1, 2]);
+        assert_eq!(parse(&buffer.0), Some(Err(Error::Size { frame_type: FrameType::Battery as u8, size: 3 })));
+    }
+
+    #[test]
+    fn unsupported_frame() {
+        let buffer = frame(FrameType::LinkStatistics, &[0; 10]);
+        assert_eq!(parse(&buffer.0), Some(Err(Error::Unsupported(FrameType::LinkStatistics as u8))));
+    }
+}
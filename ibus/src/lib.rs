@@ -0,0 +1,149 @@
+// FlySky i-BUS, as understood by Betaflight, INAV and ArduPilot. There is no
+// public specification; this follows what FlySky's receivers send.
+//
+// There are two buses. The servo bus is one way, at 115200 baud 8N1, not
+// inverted: the receiver sends a frame of channels every 7mS. The sensor bus
+// is half duplex, on a single wire, and is in the sensors module.
+#![no_std]
+
+use embedded_hal::serial::Write;
+use rc_output::{ Link, RcOutput };
+
+pub mod sensors;
+
+pub use sensors::Sensors;
+
+// Each frame starts with its length, and the command for channels
+const LENGTH: u8 = 0x20;
+const COMMAND: u8 = 0x40;
+
+// Then 14 channels, each a little endian pulse width in µS
+pub const CHANNELS: usize = 14;
+
+// The values commonly used for the reference positions, in µS
+
+/// Low (-100%)
+pub const LOW: u16 = 1000;
+/// Neutral position (0%)
+pub const NEUTRAL: u16 = 1500;
+/// High (100%)
+pub const HIGH: u16 = 2000;
+
+/// Scale a value which uses the whole range of a u16 to the range LOW..=HIGH
+pub fn scale(value: u16) -> u16 {
+    LOW + ((value as u32 * (HIGH - LOW) as u32 + 0x7fff) / 0xffff) as u16
+}
+
+// And finally a little endian checksum, which both buses use: 0xffff less the
+// sum of all the bytes before it
+pub(crate) fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xffff, |checksum: u16, byte| checksum.wrapping_sub(*byte as u16))
+}
+
+pub const FRAME_SIZE: usize = LENGTH as usize;
+
+/// Send a frame. The values use the whole range of a u16, like `RcOutput::send`.
+/// Any channels left out are sent as neutral, and any after the 14th are left
+/// out.
+pub fn send<Out: Write<u8>>(out: &mut Out, values: &[u16]) -> nb::Result<(), Out::Error> {
+    let mut sum: u16 = 0xffff;
+    let mut write = |byte: u8| {
+        sum = sum.wrapping_sub(byte as u16);
+        out.write(byte)
+    };
+
+    write(LENGTH)?;
+    write(COMMAND)?;
+    for channel in 0..CHANNELS {
+        let value = values.get(channel).map_or(NEUTRAL, |value| scale(*value));
+        for byte in &value.to_le_bytes() {
+            write(*byte)?;
+        }
+    }
+
+    for byte in &sum.to_le_bytes() {
+        out.write(*byte)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// i-BUS as an RcOutput. There's no failsafe flag: the receiver sends its
+/// failsafe positions, and the flight controller can tell from them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encoder;
+
+impl RcOutput for Encoder {
+    fn send<W: Write<u8>>(&self, out: &mut W, _: &Link, values: &[u16]) -> nb::Result<(), W::Error> {
+        send(out, values)
+    }
+}
+
+// The whole frame is 32 bytes, which takes 2.8mS at 115200 baud.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::Vec;
+    use rc_output::{ FrameBuffer, LinkState };
+
+    const LINK: Link = Link { state: LinkState::Live, quality: 100, rssi: 50 };
+
+    fn encode(link: &Link, values: &[u16]) -> FrameBuffer {
+        let mut buffer = FrameBuffer::new();
+        buffer.encode(&Encoder, link, values);
+        buffer
+    }
+
+    fn channel(frame: &[u8], channel: usize) -> u16 {
+        u16::from_le_bytes([frame[2 + 2 * channel], frame[3 + 2 * channel]])
+    }
+
+    #[test]
+    fn scales_reference_values() {
+        assert_eq!(scale(0), LOW);
+        assert_eq!(scale(0x8000), NEUTRAL);
+        assert_eq!(scale(0xffff), HIGH);
+    }
+
+    #[test]
+    fn frame_layout() {
+        let buffer = encode(&LINK, &[0, 0x8000, 0xffff]);
+        let frame = &buffer.0;
+        assert_eq!(frame.len(), FRAME_SIZE);
+        assert_eq!(frame[..4], [0x20, 0x40, 0xe8, 0x03]);
+        assert_eq!(channel(frame, 1), NEUTRAL);
+        assert_eq!(channel(frame, 2), HIGH);
+        for i in 3..CHANNELS {
+            assert_eq!(channel(frame, i), NEUTRAL);
+        }
+    }
+
+    #[test]
+    fn extra_channels_are_left_out() {
+        let values: Vec<u16, 16> = (0..16).map(|i| i * 0x1000).collect();
+        let buffer = encode(&LINK, &values);
+        assert_eq!(buffer.0.len(), FRAME_SIZE);
+        for (i, value) in values[..CHANNELS].iter().enumerate() {
+            assert_eq!(channel(&buffer.0, i), scale(*value));
+        }
+    }
+
+    #[test]
+    fn checksum_covers_the_frame() {
+        let buffer = encode(&LINK, &[0x1234, 0xfedc]);
+        let frame = &buffer.0;
+        let sum: u32 = frame[..FRAME_SIZE - 2].iter().map(|byte| *byte as u32).sum();
+        let checksum = u16::from_le_bytes([frame[FRAME_SIZE - 2], frame[FRAME_SIZE - 1]]);
+        assert_eq!(checksum as u32, 0xffff - sum);
+        assert_eq!(checksum, super::checksum(&frame[..FRAME_SIZE - 2]));
+    }
+
+    #[test]
+    fn the_link_state_makes_no_difference() {
+        let values = [0x4000; CHANNELS];
+        let live = encode(&LINK, &values);
+        let failsafe = encode(&Link { state: LinkState::FailSafe, ..LINK }, &values);
+        assert_eq!(live.0, failsafe.0);
+    }
+}
@@ -0,0 +1,232 @@
+// The i-BUS sensor bus: half duplex, on a single wire, at 115200 baud 8N1.
+// Whatever's in charge of the bus, usually a receiver, polls each address in
+// turn, and the sensor with that address replies. Here it's the other way
+// round: the sensors answer the polls, so this receiver can report its own
+// voltage and link quality.
+//
+// Every message is its length, including the length and the checksum, then a
+// command and an address, any payload, and a checksum like the servo bus's.
+// Requests have no payload. For each address the master first discovers
+// whether there's a sensor there, which it confirms by echoing the request,
+// then asks what type it is, and then polls its value.
+
+use heapless::Vec;
+
+use crate::checksum;
+
+const DISCOVER: u8 = 0x80;
+const TYPE: u8 = 0x90;
+const VALUE: u8 = 0xa0;
+const COMMAND_MASK: u8 = 0xf0;
+const ADDRESS_MASK: u8 = 0x0f;
+
+const REQUEST_SIZE: usize = 4;
+const MAX_MESSAGE_SIZE: usize = 32;
+
+// Sensors here have two byte values
+const VALUE_SIZE: u8 = 2;
+pub const MAX_REPLY_SIZE: usize = 4 + VALUE_SIZE as usize;
+
+pub type Reply = Vec<u8, MAX_REPLY_SIZE>;
+
+// Some sensor types, as FlySky's receivers and transmitters know them
+
+/// The receiver's supply, in units of 0.01V
+pub const INTERNAL_VOLTAGE: u8 = 0x00;
+/// In units of 0.01V
+pub const EXTERNAL_VOLTAGE: u8 = 0x03;
+/// In -dBm
+pub const RSSI: u8 = 0xfc;
+/// The percentage of packets lost
+pub const ERROR_RATE: u8 = 0xfe;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sensor {
+    pub kind: u8,
+    pub value: u16,
+}
+
+/// Sensors with consecutive addresses, from `first`
+pub struct Sensors<const N: usize> {
+    first: u8,
+    sensors: [Sensor; N],
+    message: Vec<u8, MAX_MESSAGE_SIZE>,
+    // The line is shared, so the UART hears its own replies, which are skipped
+    echo: usize,
+}
+
+impl<const N: usize> Sensors<N> {
+    /// The sensors are at `first`, `first + 1`, ... Address 0 is the master's.
+    pub fn new(first: u8, kinds: [u8; N]) -> Self {
+        Sensors {
+            first,
+            sensors: kinds.map(|kind| Sensor { kind, value: 0 }),
+            message: Vec::new(),
+            echo: 0,
+        }
+    }
+
+    pub fn sensors(&self) -> &[Sensor; N] {
+        &self.sensors
+    }
+
+    pub fn set(&mut self, index: usize, value: u16) {
+        if let Some(sensor) = self.sensors.get_mut(index) {
+            sensor.value = value;
+        }
+    }
+
+    /// A byte from the bus, and the reply to send once it's completed a
+    /// request for one of these sensors. The next bytes received are
+    /// expected to be the reply, and are ignored.
+    pub fn receive(&mut self, byte: u8) -> Option<Reply> {
+        if self.echo > 0 {
+            self.echo -= 1;
+            return None;
+        }
+
+        let _ = self.message.push(byte);
+        loop {
+            // Skip to something which could be the length of a message
+            match self.message.first() {
+                Some(length) if (REQUEST_SIZE..=MAX_MESSAGE_SIZE).contains(&(*length as usize)) => {},
+                Some(_) => {
+                    self.message.remove(0);
+                    continue;
+                },
+                None => return None,
+            }
+
+            let length = self.message[0] as usize;
+            if self.message.len() < length {
+                return None;
+            }
+
+            // If the checksum's wrong, the length was noise, and the message
+            // may start in the bytes after it
+            let (body, sum) = self.message[..length].split_at(length - 2);
+            if checksum(body) != u16::from_le_bytes([sum[0], sum[1]]) {
+                self.message.remove(0);
+                continue;
+            }
+
+            let reply = self.reply(body);
+            self.message = Vec::from_slice(&self.message[length..]).unwrap_or_default();
+            if let Some(reply) = &reply {
+                self.echo = reply.len();
+            }
+            return reply;
+        }
+    }
+
+    fn reply(&self, body: &[u8]) -> Option<Reply> {
+        if body.len() != REQUEST_SIZE - 2 {
+            return None;
+        }
+
+        let (command, address) = (body[1] & COMMAND_MASK, body[1] & ADDRESS_MASK);
+        let sensor = self.sensors.get(address.checked_sub(self.first)? as usize)?;
+        let mut reply = Reply::new();
+        let _ = match command {
+            DISCOVER => reply.extend_from_slice(&[REQUEST_SIZE as u8, body[1]]),
+            TYPE => reply.extend_from_slice(&[6, body[1], sensor.kind, VALUE_SIZE]),
+            VALUE => reply.extend_from_slice(&[6, body[1]]).and(reply.extend_from_slice(&sensor.value.to_le_bytes())),
+            _ => return None,
+        };
+        let _ = reply.extend_from_slice(&checksum(&reply).to_le_bytes());
+        Some(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(command: u8, address: u8) -> [u8; REQUEST_SIZE] {
+        let body = [REQUEST_SIZE as u8, command | address];
+        let sum = checksum(&body).to_le_bytes();
+        [body[0], body[1], sum[0], sum[1]]
+    }
+
+    fn receive_all<const N: usize>(sensors: &mut Sensors<N>, bytes: &[u8]) -> Option<Reply> {
+        let mut reply = None;
+        for byte in bytes {
+            if let Some(received) = sensors.receive(*byte) {
+                reply = Some(received);
+            }
+        }
+        reply
+    }
+
+    fn sensors() -> Sensors<2> {
+        let mut sensors = Sensors::new(1, [INTERNAL_VOLTAGE, ERROR_RATE]);
+        sensors.set(0, 512);
+        sensors.set(1, 3);
+        sensors
+    }
+
+    #[test]
+    fn discover_type_and_value() {
+        let discover = request(DISCOVER, 2);
+        assert_eq!(receive_all(&mut sensors(), &discover).unwrap(), discover);
+
+        let reply = receive_all(&mut sensors(), &request(TYPE, 2)).unwrap();
+        assert_eq!(reply[..4], [6, 0x92, ERROR_RATE, 2]);
+        assert_eq!(u16::from_le_bytes([reply[4], reply[5]]), checksum(&reply[..4]));
+
+        let reply = receive_all(&mut sensors(), &request(VALUE, 1)).unwrap();
+        assert_eq!(reply[..4], [6, 0xa1, 0x00, 0x02]);
+        assert_eq!(u16::from_le_bytes([reply[4], reply[5]]), 0xffff - (6 + 0xa1 + 0x02));
+    }
+
+    #[test]
+    fn skips_its_own_replies() {
+        let mut sensors = sensors();
+        let reply = receive_all(&mut sensors, &request(VALUE, 1)).unwrap();
+        assert_eq!(receive_all(&mut sensors, &reply), None);
+        sensors.set(0, 1200);
+        let reply = receive_all(&mut sensors, &request(VALUE, 1)).unwrap();
+        assert_eq!(reply[2..4], 1200u16.to_le_bytes());
+    }
+
+    #[test]
+    fn ignores_other_addresses() {
+        let mut sensors = sensors();
+        for address in [0, 3, 15] {
+            for command in [DISCOVER, TYPE, VALUE] {
+                assert_eq!(receive_all(&mut sensors, &request(command, address)), None);
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_bad_checksums_and_unknown_commands() {
+        let mut sensors = sensors();
+        let mut bad = request(VALUE, 1);
+        bad[2] ^= 0x01;
+        assert_eq!(receive_all(&mut sensors, &bad), None);
+        assert_eq!(receive_all(&mut sensors, &request(0xb0, 1)), None);
+        assert!(receive_all(&mut sensors, &request(VALUE, 1)).is_some());
+    }
+
+    #[test]
+    fn other_sensors_replies_are_ignored() {
+        let mut sensors = sensors();
+        let mut other = Reply::new();
+        other.extend_from_slice(&[6, 0xa3, 0x34, 0x12]).unwrap();
+        let sum = checksum(&other).to_le_bytes();
+        other.extend_from_slice(&sum).unwrap();
+        // Noise, then another sensor's reply, then a request for one of these
+        assert_eq!(receive_all(&mut sensors, &[0x00, 0xff, 0x55]), None);
+        assert_eq!(receive_all(&mut sensors, &other), None);
+        assert_eq!(receive_all(&mut sensors, &request(DISCOVER, 1)).unwrap(), request(DISCOVER, 1));
+    }
+
+    #[test]
+    fn resyncs_after_noise_which_looks_like_a_length() {
+        let mut sensors = sensors();
+        assert_eq!(receive_all(&mut sensors, &[REQUEST_SIZE as u8 + 1]), None);
+        let reply = receive_all(&mut sensors, &request(VALUE, 1)).unwrap();
+        assert_eq!(reply[..4], [6, 0xa1, 0x00, 0x02]);
+    }
+}
@@ -0,0 +1,205 @@
+// Pulse position modulation, or CPPM: the channels one after another on a
+// single wire, for older flight controllers and simulators. Each channel
+// starts with a short pulse, and lasts as long as that channel's servo pulse
+// would. After the last channel comes one more pulse, and then the sync gap,
+// until the frame's up, which is how the flight controller finds the first.
+//
+// Times are in µS. The frame is a sequence of steps, each the line active for
+// a while, and then idle until the step's up, which is what a timer does in
+// PWM mode, reloaded at the end of each step.
+#![no_std]
+
+pub const CHANNELS: usize = 8;
+
+// The values commonly used for the reference positions, in µS
+
+/// Low (-100%)
+pub const LOW: u16 = 1000;
+/// Neutral position (0%)
+pub const NEUTRAL: u16 = 1500;
+/// High (100%)
+pub const HIGH: u16 = 2000;
+
+/// Scale a value which uses the whole range of a u16 to the range LOW..=HIGH
+pub fn scale(value: u16) -> u16 {
+    LOW + ((value as u32 * (HIGH - LOW) as u32 + 0x7fff) / 0xffff) as u16
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Polarity {
+    /// Idle low, with high pulses
+    Positive,
+    /// Idle high, with low pulses, which most flight controllers expect
+    Negative,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// The whole frame, unless the channels and the sync gap need longer
+    pub frame: u16,
+    /// The pulse at the start of each channel
+    pub pulse: u16,
+    /// The shortest the gap after the last channel can be
+    pub sync: u16,
+    pub polarity: Polarity,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { frame: 22_500, pulse: 300, sync: 4_000, polarity: Polarity::Negative }
+    }
+}
+
+impl Config {
+    /// The line's level while it's pulsing
+    pub fn active_level(&self) -> bool {
+        self.polarity == Polarity::Positive
+    }
+}
+
+/// The line is active for `active`, and then idle until `period` is up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub active: u16,
+    pub period: u16,
+}
+
+/// A step for each channel, then the sync gap
+pub type Frame = [Step; CHANNELS + 1];
+
+/// The steps of a frame of these values. Any channels left out are sent as
+/// neutral, and any after the eighth are left out.
+pub fn frame(values: &[u16], config: &Config) -> Frame {
+    let pulse = Step { active: config.pulse, period: config.pulse };
+    let mut frame = [pulse; CHANNELS + 1];
+    let mut elapsed: u32 = 0;
+    for (channel, step) in frame[..CHANNELS].iter_mut().enumerate() {
+        let width = values.get(channel).map_or(NEUTRAL, |value| scale(*value));
+        step.period = width.max(config.pulse + 1);
+        elapsed += step.period as u32;
+    }
+
+    let sync = (config.frame as u32).saturating_sub(elapsed).max(config.pulse as u32 + config.sync as u32);
+    frame[CHANNELS].period = sync.min(u16::MAX as u32) as u16;
+    frame
+}
+
+/// Plays frame after frame, one step at a time
+pub struct Generator {
+    config: Config,
+    values: [u16; CHANNELS],
+    frame: Frame,
+    next: usize,
+}
+
+impl Generator {
+    /// Neutral until there are some values
+    pub fn new(config: Config) -> Self {
+        let values = [0x8000; CHANNELS];
+        Generator { config, values, frame: frame(&values, &config), next: 0 }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The values for the next frame, so a frame is never half old values and
+    /// half new
+    pub fn set(&mut self, values: &[u16]) {
+        for (channel, value) in self.values.iter_mut().enumerate() {
+            *value = values.get(channel).copied().unwrap_or(0x8000);
+        }
+    }
+
+    /// The next step. The timer's registers are preloaded, so this is the
+    /// step after the one it's just started.
+    pub fn step(&mut self) -> Step {
+        if self.next == 0 {
+            self.frame = frame(&self.values, &self.config);
+        }
+        let step = self.frame[self.next];
+        self.next = (self.next + 1) % self.frame.len();
+        step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    // The line's level, and how long it's there for
+    fn waveform(steps: &[Step], config: &Config) -> Vec<(bool, u16)> {
+        let active = config.active_level();
+        steps.iter().flat_map(|step| [(active, step.active), (!active, step.period - step.active)]).collect()
+    }
+
+    fn length(frame: &Frame) -> u32 {
+        frame.iter().map(|step| step.period as u32).sum()
+    }
+
+    #[test]
+    fn scales_reference_values() {
+        assert_eq!(scale(0), LOW);
+        assert_eq!(scale(0x8000), NEUTRAL);
+        assert_eq!(scale(0xffff), HIGH);
+    }
+
+    #[test]
+    fn neutral_frame() {
+        let config = Config::default();
+        let frame = frame(&[], &config);
+        for step in frame[..CHANNELS].iter() {
+            assert_eq!(*step, Step { active: 300, period: 1500 });
+        }
+        assert_eq!(frame[CHANNELS], Step { active: 300, period: 22_500 - 8 * 1500 });
+
+        let levels = waveform(&frame, &config);
+        assert_eq!(levels[..4], [(false, 300), (true, 1200), (false, 300), (true, 1200)]);
+        assert_eq!(levels[levels.len() - 1], (true, 10_500 - 300));
+    }
+
+    #[test]
+    fn channels_are_pulse_positions() {
+        let config = Config { polarity: Polarity::Positive, ..Config::default() };
+        let values = [0, 0xffff, 0x8000, 0x4000, 0, 0, 0, 0, 0xffff];
+        let frame = frame(&values, &config);
+        let widths: Vec<u16> = frame[..CHANNELS].iter().map(|step| step.period).collect();
+        assert_eq!(widths, [1000, 2000, 1500, 1250, 1000, 1000, 1000, 1000]);
+        assert_eq!(length(&frame), 22_500);
+        assert_eq!(waveform(&frame, &config)[..2], [(true, 300), (false, 700)]);
+    }
+
+    #[test]
+    fn frame_length_is_constant() {
+        let config = Config::default();
+        for value in (0..=0xffff).step_by(0x111) {
+            assert_eq!(length(&frame(&[value; CHANNELS], &config)), 22_500);
+        }
+    }
+
+    #[test]
+    fn the_sync_gap_stretches_the_frame() {
+        let config = Config { frame: 18_000, ..Config::default() };
+        let frame = frame(&[0xffff; CHANNELS], &config);
+        assert_eq!(frame[CHANNELS].period, 300 + 4000);
+        assert_eq!(length(&frame), 8 * 2000 + 4300);
+    }
+
+    #[test]
+    fn new_values_wait_for_the_next_frame() {
+        let mut generator = Generator::new(Config::default());
+        assert_eq!(generator.step(), Step { active: 300, period: 1500 });
+        generator.set(&[0; CHANNELS]);
+        let rest: Vec<Step> = (0..CHANNELS).map(|_| generator.step()).collect();
+        assert!(rest[..CHANNELS - 1].iter().all(|step| step.period == 1500));
+        assert_eq!(rest[CHANNELS - 1].period, 10_500);
+
+        let next: Vec<Step> = (0..=CHANNELS).map(|_| generator.step()).collect();
+        assert!(next[..CHANNELS].iter().all(|step| step.period == 1000));
+        assert_eq!(next[CHANNELS].period, 14_500);
+    }
+}
@@ -0,0 +1,269 @@
+// Authentication of the transmitter's messages, so the receiver only obeys
+// the transmitter it's bound to, and can't be fooled by a recording of it.
+//
+// Each message is followed by a tag: SipHash-2-4 of the message, keyed with
+// the key shared when binding, truncated to 32 bits. SipHash only needs 64 bit
+// additions, rotations and xors, which are cheap enough even on a Cortex-M0.
+//
+// The correlation id must increase from one message to the next, so a
+// message can't be replayed. The transmitter keeps increasing it even when
+// it's switched off and on again, by starting each session from a higher
+// value, and binds again with a new key rather than let the sessions wrap.
+// The receiver stores the last session it heard, so recordings of earlier
+// ones are still replays after it's switched off and on again.
+
+use core::mem::size_of;
+
+use crate::{ Transmitter, PAYLOAD_SIZE, VARIANT_MAX_SIZE, channels::PACKED_SIZE };
+
+pub type Key = [u8; 16];
+
+pub const TAG_SIZE: usize = 4;
+
+// Channel values, with their tag, must fit in a payload
+const _: () = assert!(
+    size_of::<u8>() + size_of::<u32>() + VARIANT_MAX_SIZE + PACKED_SIZE + TAG_SIZE <= PAYLOAD_SIZE);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The message wasn't a message at all
+    Decode,
+    /// The tag was missing or didn't match, so the message isn't from our transmitter
+    Tag,
+    /// The message was older than the last one accepted
+    Replay,
+}
+
+// SipHash-2-4, as described by Aumasson and Bernstein
+struct SipHash {
+    v: [u64; 4],
+}
+
+impl SipHash {
+    fn new(key: &Key) -> Self {
+        let mut k0 = [0; 8];
+        let mut k1 = [0; 8];
+        k0.copy_from_slice(&key[..8]);
+        k1.copy_from_slice(&key[8..]);
+        let k0 = u64::from_le_bytes(k0);
+        let k1 = u64::from_le_bytes(k1);
+        SipHash {
+            v: [
+                k0 ^ 0x736f_6d65_7073_6575,
+                k1 ^ 0x646f_7261_6e64_6f6d,
+                k0 ^ 0x6c79_6765_6e65_7261,
+                k1 ^ 0x7465_6462_7974_6573,
+            ],
+        }
+    }
+
+    fn round(&mut self) {
+        let v = &mut self.v;
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v[3] ^= m;
+        self.round();
+        self.round();
+        self.v[0] ^= m;
+    }
+
+    fn hash(key: &Key, message: &[u8]) -> u64 {
+        let mut hash = SipHash::new(key);
+        let mut chunks = message.chunks_exact(8);
+        for chunk in &mut chunks {
+            let mut m = [0; 8];
+            m.copy_from_slice(chunk);
+            hash.compress(u64::from_le_bytes(m));
+        }
+
+        // The last block has the rest of the message, and its length in the top byte
+        let mut last = [0; 8];
+        last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+        last[7] = message.len() as u8;
+        hash.compress(u64::from_le_bytes(last));
+
+        hash.v[2] ^= 0xff;
+        for _ in 0..4 {
+            hash.round();
+        }
+        hash.v[0] ^ hash.v[1] ^ hash.v[2] ^ hash.v[3]
+    }
+}
+
+fn tag(key: &Key, message: &[u8]) -> [u8; TAG_SIZE] {
+    let mut tag = [0; TAG_SIZE];
+    tag.copy_from_slice(&SipHash::hash(key, message).to_le_bytes()[..TAG_SIZE]);
+    tag
+}
+
+/// Signs messages on the transmitter, and checks them on the receiver
+pub struct Authenticator {
+    key: Key,
+    last_correlation_id: Option<u32>,
+}
+
+impl Authenticator {
+    pub fn new(key: Key) -> Self {
+        Authenticator { key, last_correlation_id: None }
+    }
+
+    /// For a receiver which last heard the transmitter in `session`, so
+    /// messages from earlier sessions are replays
+    pub fn resume(key: Key, session: u8) -> Self {
+        Authenticator { key, last_correlation_id: session_start(session).checked_sub(1) }
+    }
+
+    /// Encode the message, followed by its tag
+    pub fn sign<'a>(&self, message: &Transmitter, buffer: &'a mut [u8; PAYLOAD_SIZE]) -> postcard::Result<&'a mut [u8]> {
+        let size = message.encode(buffer)?.len();
+        if size + TAG_SIZE > PAYLOAD_SIZE {
+            return Err(postcard::Error::SerializeBufferFull);
+        }
+
+        let tag = tag(&self.key, &buffer[..size]);
+        buffer[size..size + TAG_SIZE].copy_from_slice(&tag);
+        Ok(&mut buffer[..size + TAG_SIZE])
+    }
+
+    /// Check the tag and the correlation id, and decode the message
+    pub fn verify(&mut self, payload: &[u8]) -> Result<Transmitter, Error> {
+        // The nRF24 doesn't tell us the length of the payload, so the tag
+        // follows however much of it the message took
+        let (message, rest) = postcard::take_from_bytes::<Transmitter>(payload).map_err(|_| Error::Decode)?;
+        let size = payload.len() - rest.len();
+        if rest.len() < TAG_SIZE || rest[..TAG_SIZE] != tag(&self.key, &payload[..size]) {
+            return Err(Error::Tag);
+        }
+
+        if let Some(last) = self.last_correlation_id {
+            if message.correlation_id <= last {
+                return Err(Error::Replay);
+            }
+        }
+
+        self.last_correlation_id = Some(message.correlation_id);
+        Ok(message)
+    }
+}
+
+/// The first correlation id of a session. The transmitter counts sessions,
+/// and each can be 2^24 messages long, or nearly two days at 100 messages a second.
+pub fn session_start(session: u8) -> u32 {
+    (session as u32) << 24
+}
+
+/// The session a correlation id is from
+pub fn session(correlation_id: u32) -> u8 {
+    (correlation_id >> 24) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ TransmitterMessage, CHANNELS, channels::Packed };
+
+    const KEY: Key = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    fn signed(authenticator: &Authenticator, correlation_id: u32) -> [u8; PAYLOAD_SIZE] {
+        let mut buffer = [0; PAYLOAD_SIZE];
+        let message = Transmitter::new(correlation_id, TransmitterMessage::ChannelValues(Packed::new(&[0x1234; CHANNELS], 0)));
+        authenticator.sign(&message, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn siphash_test_vectors() {
+        // From the reference implementation
+        assert_eq!(SipHash::hash(&KEY, &[]), 0x726f_db47_dd0e_0e31);
+        let message: [u8; 15] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14];
+        assert_eq!(SipHash::hash(&KEY, &message), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn accepts_signed_messages() {
+        let transmitter = Authenticator::new(KEY);
+        let mut receiver = Authenticator::new(KEY);
+        for correlation_id in 1..10 {
+            let message = receiver.verify(&signed(&transmitter, correlation_id)).unwrap();
+            assert_eq!(message.correlation_id, correlation_id);
+        }
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let mut key = KEY;
+        key[15] ^= 1;
+        let transmitter = Authenticator::new(key);
+        let mut receiver = Authenticator::new(KEY);
+        assert_eq!(receiver.verify(&signed(&transmitter, 1)), Err(Error::Tag));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let transmitter = Authenticator::new(KEY);
+        let mut receiver = Authenticator::new(KEY);
+        let mut payload = signed(&transmitter, 1);
+        payload[7] ^= 0x80;
+        assert_eq!(receiver.verify(&payload), Err(Error::Tag));
+        assert_eq!(receiver.verify(&[0; PAYLOAD_SIZE]), Err(Error::Tag));
+        assert_eq!(receiver.verify(&[0xff; 3]), Err(Error::Decode));
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let transmitter = Authenticator::new(KEY);
+        let mut receiver = Authenticator::new(KEY);
+        let first = signed(&transmitter, 5);
+        receiver.verify(&first).unwrap();
+        receiver.verify(&signed(&transmitter, 6)).unwrap();
+        assert_eq!(receiver.verify(&first), Err(Error::Replay));
+        assert_eq!(receiver.verify(&signed(&transmitter, 6)), Err(Error::Replay));
+    }
+
+    #[test]
+    fn new_session_follows_old() {
+        let transmitter = Authenticator::new(KEY);
+        let mut receiver = Authenticator::new(KEY);
+        receiver.verify(&signed(&transmitter, session_start(3) + 1_000_000)).unwrap();
+        receiver.verify(&signed(&transmitter, session_start(4))).unwrap();
+        assert_eq!(session(session_start(4) + 1_000_000), 4);
+
+        // The sessions never wrap, so a recording from the first one is never
+        // taken for one from after the last
+        let mut receiver = Authenticator::new(KEY);
+        receiver.verify(&signed(&transmitter, session_start(255) + 10)).unwrap();
+        assert_eq!(receiver.verify(&signed(&transmitter, session_start(0) + 11)), Err(Error::Replay));
+        receiver.verify(&signed(&transmitter, session_start(255) + 11)).unwrap();
+    }
+
+    #[test]
+    fn resumes_from_the_stored_session() {
+        let transmitter = Authenticator::new(KEY);
+        let mut receiver = Authenticator::resume(KEY, 4);
+        assert_eq!(receiver.verify(&signed(&transmitter, session_start(3) + 10)), Err(Error::Replay));
+        // The receiver may have restarted in the middle of the session
+        receiver.verify(&signed(&transmitter, session_start(4) + 10)).unwrap();
+
+        let mut receiver = Authenticator::resume(KEY, 0);
+        receiver.verify(&signed(&transmitter, 1)).unwrap();
+    }
+
+    #[test]
+    fn signed_message_fits() {
+        let authenticator = Authenticator::new(KEY);
+        let mut buffer = [0; PAYLOAD_SIZE];
+        let message = Transmitter::new(u32::MAX, TransmitterMessage::ChannelValues(Packed::new(&[u16::MAX; CHANNELS], 1)));
+        assert!(authenticator.sign(&message, &mut buffer).unwrap().len() <= PAYLOAD_SIZE);
+    }
+}
@@ -0,0 +1,300 @@
+// Binding pairs a transmitter with a receiver, so each pair has its own
+// radio address, and a transmitter only controls its own model.
+//
+// The transmitter generates a random address, hop seed and key, and
+// broadcasts them on the well known bind address. They're sent in the clear,
+// so bind well away from anyone who shouldn't have the key. A receiver in bind mode acknowledges
+// them, and keeps listening on the bind address until the transmitter stops
+// broadcasting, so it can't miss the acknowledgement. Then both move to the
+// new address.
+
+use core::mem::size_of;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::{ auth::Key, random::XorShift32, ReceiverMessage, TransmitterMessage };
+
+pub const BIND_ADDRESS: [u8; 5] = [ b'R', b'C', b'B', b'N', b'D' ];
+/// The channel binding happens on, which the hop sequence leaves out
+pub const BIND_FREQUENCY: u8 = 80;
+
+/// Identifies a receiver, and so the model it's installed in. The receiver
+/// reports it when it binds, and in its link telemetry, so the transmitter
+/// can tell whether the model selected is the one it's talking to.
+pub type ModelId = u8;
+
+/// Everything a transmitter and receiver share once they're bound
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BindInfo {
+    pub address: [u8; 5],
+    pub hop_seed: u32,
+    /// For authenticating the transmitter's messages
+    pub key: Key,
+}
+
+impl BindInfo {
+    pub const SIZE: usize = 5 * size_of::<u8>() + size_of::<u32>() + size_of::<Key>();
+
+    /// Generate new bind info from whatever entropy the transmitter can find,
+    /// e.g. noise from the ADC. The key is the entropy itself.
+    pub fn generate(entropy: [u32; 4]) -> Self {
+        let mut random = XorShift32::new(entropy.iter().fold(0, |seed, word| seed ^ word));
+        let mut address = [0; 5];
+        loop {
+            for byte in address.iter_mut() {
+                // Avoid bytes which look like the preamble, or like noise
+                *byte = loop {
+                    let candidate = random.next_u32() as u8;
+                    match candidate {
+                        0x00 | 0x55 | 0xaa | 0xff => {},
+                        _ => break candidate,
+                    }
+                };
+            }
+
+            if address != BIND_ADDRESS {
+                break;
+            }
+        }
+
+        let mut key = [0; 16];
+        for (bytes, word) in key.chunks_exact_mut(4).zip(entropy.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+
+        BindInfo { address, hop_seed: random.next_u32(), key }
+    }
+}
+
+/// The transmitter's side of binding
+pub struct TxBinder {
+    info: BindInfo,
+    // The model ID of the receiver which acknowledged
+    acknowledged: Option<ModelId>,
+}
+
+impl TxBinder {
+    pub fn new(info: BindInfo) -> Self {
+        TxBinder { info, acknowledged: None }
+    }
+
+    pub fn info(&self) -> BindInfo {
+        self.info
+    }
+
+    /// The message to broadcast on the bind address
+    pub fn message(&self) -> TransmitterMessage {
+        TransmitterMessage::Bind(self.info)
+    }
+
+    /// Handle a message from a receiver. Returns true once a receiver has
+    /// acknowledged the bind info.
+    pub fn acknowledge(&mut self, message: &ReceiverMessage) -> bool {
+        if let ReceiverMessage::Bound(info, model_id) = message {
+            if *info == self.info {
+                self.acknowledged = Some(*model_id);
+            }
+        }
+        self.acknowledged.is_some()
+    }
+
+    /// The model ID of the receiver which acknowledged
+    pub fn model_id(&self) -> Option<ModelId> {
+        self.acknowledged
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RxBindState {
+    /// Waiting for a transmitter on the bind address
+    Listening,
+    /// Acknowledging a transmitter, until it stops broadcasting
+    Acknowledging(BindInfo),
+    Bound(BindInfo),
+}
+
+/// The receiver's side of binding. Time is in ticks of the receiver's timer.
+pub struct RxBinder {
+    state: RxBindState,
+    last_bind: u32,
+}
+
+impl RxBinder {
+    /// Ticks after the last bind message before the receiver moves to the new address
+    pub const LINGER: u32 = 50;
+
+    pub fn listening() -> Self {
+        RxBinder { state: RxBindState::Listening, last_bind: 0 }
+    }
+
+    pub fn bound(info: BindInfo) -> Self {
+        RxBinder { state: RxBindState::Bound(info), last_bind: 0 }
+    }
+
+    pub fn state(&self) -> RxBindState {
+        self.state
+    }
+
+    /// The address to listen on
+    pub fn address(&self) -> [u8; 5] {
+        match self.state {
+            RxBindState::Bound(info) => info.address,
+            _ => BIND_ADDRESS,
+        }
+    }
+
+    /// Handle a message received at tick `now`.
+    pub fn receive(&mut self, now: u32, message: &TransmitterMessage) {
+        if let TransmitterMessage::Bind(info) = message {
+            match self.state {
+                RxBindState::Listening | RxBindState::Acknowledging(_) => {
+                    self.state = RxBindState::Acknowledging(*info);
+                    self.last_bind = now;
+                },
+                RxBindState::Bound(_) => {},
+            }
+        }
+    }
+
+    /// The acknowledgement payload, while acknowledging
+    pub fn ack(&self, model_id: ModelId) -> Option<ReceiverMessage> {
+        match self.state {
+            RxBindState::Acknowledging(info) => Some(ReceiverMessage::Bound(info, model_id)),
+            _ => None,
+        }
+    }
+
+    /// Move the clock on to tick `now`. Returns the bind info, once, when
+    /// binding completes, so the receiver can store it and change address.
+    pub fn update(&mut self, now: u32) -> Option<BindInfo> {
+        match self.state {
+            RxBindState::Acknowledging(info) if now.wrapping_sub(self.last_bind) >= Self::LINGER => {
+                self.state = RxBindState::Bound(info);
+                Some(info)
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ Receiver, Transmitter, PAYLOAD_SIZE, channels::Packed };
+
+    #[test]
+    fn generated_addresses_differ() {
+        let a = BindInfo::generate([1, 0, 0, 0]);
+        let b = BindInfo::generate([2, 0, 0, 0]);
+        assert_ne!(a.address, b.address);
+        assert_ne!(a.hop_seed, b.hop_seed);
+        assert_eq!(BindInfo::generate([1, 0, 0, 0]), a);
+    }
+
+    // Just enough of a pair of nRF24s to bind: a receiver hears the transmitter
+    // if they're on the same address, and its reply is whatever acknowledgement
+    // payload it had ready.
+    struct SimulatedReceiver {
+        binder: RxBinder,
+        ack: Option<[u8; PAYLOAD_SIZE]>,
+        received: u32,
+    }
+
+    impl SimulatedReceiver {
+        fn new(binder: RxBinder) -> Self {
+            SimulatedReceiver { binder, ack: None, received: 0 }
+        }
+
+        fn air(&mut self, now: u32, address: [u8; 5], payload: &[u8]) -> Option<[u8; PAYLOAD_SIZE]> {
+            if address != self.binder.address() {
+                return None;
+            }
+
+            let ack = self.ack.take();
+            let message = Transmitter::decode(payload).unwrap();
+            self.binder.receive(now, &message.body);
+            if let TransmitterMessage::ChannelValues(_) = message.body {
+                self.received += 1;
+            }
+
+            if let Some(reply) = self.binder.ack(42) {
+                let mut buffer = [0; PAYLOAD_SIZE];
+                Receiver::new(message.correlation_id, reply).encode(&mut buffer).unwrap();
+                self.ack = Some(buffer);
+            }
+            ack
+        }
+    }
+
+    fn transmit(address: [u8; 5], body: TransmitterMessage, receiver: &mut SimulatedReceiver, now: u32)
+        -> Option<ReceiverMessage> {
+        let mut buffer = [0; PAYLOAD_SIZE];
+        let payload = Transmitter::new(now, body).encode(&mut buffer).unwrap();
+        receiver.air(now, address, payload).map(|ack| Receiver::decode(&ack).unwrap().body)
+    }
+
+    #[test]
+    fn bind_then_control() {
+        let info = BindInfo::generate([0xdead_beef, 0, 0, 0]);
+        let mut tx = TxBinder::new(info);
+        let mut receiver = SimulatedReceiver::new(RxBinder::listening());
+
+        // Bind
+        let mut now = 0;
+        let mut bound = false;
+        while !bound {
+            assert!(now < 10, "the receiver never acknowledged");
+            if let Some(ack) = transmit(BIND_ADDRESS, tx.message(), &mut receiver, now) {
+                bound = tx.acknowledge(&ack);
+            }
+            assert_eq!(receiver.binder.update(now), None);
+            now += 1;
+        }
+        assert_eq!(receiver.binder.state(), RxBindState::Acknowledging(info));
+        assert_eq!(tx.model_id(), Some(42));
+
+        // The transmitter has moved to the new address, and the receiver follows
+        // once the bind messages stop
+        let mut stored = None;
+        for _ in 0..=RxBinder::LINGER {
+            transmit(info.address, TransmitterMessage::ChannelValues(Packed::new(&[0; crate::CHANNELS], 0)), &mut receiver, now);
+            stored = stored.or(receiver.binder.update(now));
+            now += 1;
+        }
+        assert_eq!(stored, Some(info));
+        assert_eq!(receiver.binder.state(), RxBindState::Bound(info));
+
+        receiver.received = 0;
+        for _ in 0..10 {
+            transmit(info.address, TransmitterMessage::ChannelValues(Packed::new(&[0; crate::CHANNELS], 0)), &mut receiver, now);
+            now += 1;
+        }
+        assert_eq!(receiver.received, 10);
+    }
+
+    #[test]
+    fn ignores_other_transmitters() {
+        let info = BindInfo::generate([1, 0, 0, 0]);
+        let other = BindInfo::generate([2, 0, 0, 0]);
+        let mut receiver = SimulatedReceiver::new(RxBinder::bound(info));
+
+        // Neither a bind broadcast nor another transmitter's control messages get through
+        transmit(BIND_ADDRESS, TransmitterMessage::Bind(other), &mut receiver, 0);
+        transmit(other.address, TransmitterMessage::ChannelValues(Packed::new(&[0; crate::CHANNELS], 0)), &mut receiver, 1);
+        assert_eq!(receiver.received, 0);
+        assert_eq!(receiver.binder.state(), RxBindState::Bound(info));
+
+        transmit(info.address, TransmitterMessage::ChannelValues(Packed::new(&[0; crate::CHANNELS], 0)), &mut receiver, 2);
+        assert_eq!(receiver.received, 1);
+    }
+
+    #[test]
+    fn ignores_other_acknowledgements() {
+        let mut tx = TxBinder::new(BindInfo::generate([1, 0, 0, 0]));
+        assert!(!tx.acknowledge(&ReceiverMessage::Bound(BindInfo::generate([2, 0, 0, 0]), 1)));
+        assert!(!tx.acknowledge(&ReceiverMessage::Link(Default::default())));
+        assert_eq!(tx.model_id(), None);
+        assert!(tx.acknowledge(&ReceiverMessage::Bound(BindInfo::generate([1, 0, 0, 0]), 3)));
+        assert_eq!(tx.model_id(), Some(3));
+    }
+}
@@ -0,0 +1,270 @@
+// The channel values are packed into 12 bits each, least significant bit
+// first, after a flags byte. The most significant bits of each value are sent,
+// and the receiver stretches them back over the whole range of a u16.
+//
+// All sixteen channels won't fit in a payload along with the tag, and most of
+// them are switches and pots which hardly ever move. So the first FAST
+// channels, where the sticks go, are in every message, and the slow ones are
+// sent a group at a time: a group which has changed as soon as possible, and
+// every group in turn every other message, in case one was lost. The flags
+// byte says which group a message carries, and the receiver keeps the rest as
+// they were.
+
+use core::ops::Range;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::{ Value, CHANNELS };
+
+const BITS: usize = 12;
+const MASK: u32 = (1 << BITS) - 1;
+
+/// The channels in every message
+pub const FAST: usize = 8;
+/// The slow channels in each message
+pub const GROUP: usize = 4;
+pub const GROUPS: usize = (CHANNELS - FAST) / GROUP;
+
+const SIZE: usize = (FAST + GROUP) * BITS / 8;
+pub const PACKED_SIZE: usize = 1 + SIZE;
+
+// The flags byte has the group in its bottom bits. The rest are reserved,
+// and must be clear.
+const GROUP_FLAGS: u8 = 0x03;
+
+const _: () = assert!(FAST + GROUPS * GROUP == CHANNELS);
+const _: () = assert!(GROUPS <= GROUP_FLAGS as usize + 1);
+
+/// The flags byte isn't one this firmware understands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Invalid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Packed {
+    flags: u8,
+    bits: [u8; SIZE],
+}
+
+impl Packed {
+    /// The fast channels, and the `group`th group of slow ones
+    pub fn new(values: &[Value; CHANNELS], group: usize) -> Self {
+        let group = group % GROUPS;
+        let mut bits = [0; SIZE];
+        let sent = values[..FAST].iter().chain(values[FAST..][slow(group)].iter());
+        let mut accumulator: u32 = 0;
+        let mut count = 0;
+        let mut index = 0;
+        for value in sent {
+            accumulator |= (*value as u32 >> (16 - BITS)) << count;
+            count += BITS;
+            while count >= 8 {
+                bits[index] = accumulator as u8;
+                index += 1;
+                accumulator >>= 8;
+                count -= 8;
+            }
+        }
+        Packed { flags: group as u8, bits }
+    }
+
+    /// The group of slow channels this carries
+    pub fn group(&self) -> Result<usize, Invalid> {
+        match self.flags as usize {
+            group if group < GROUPS => Ok(group),
+            _ => Err(Invalid),
+        }
+    }
+
+    /// Update the fast channels, and the slow ones this carries, leaving the
+    /// rest as they were
+    pub fn unpack(&self, values: &mut [Value; CHANNELS]) -> Result<(), Invalid> {
+        let group = self.group()?;
+        let (fast, rest) = values.split_at_mut(FAST);
+        let received = fast.iter_mut().chain(rest[slow(group)].iter_mut());
+        let mut accumulator: u32 = 0;
+        let mut count = 0;
+        let mut bytes = self.bits.iter();
+        for value in received {
+            while count < BITS {
+                accumulator |= (*bytes.next().unwrap_or(&0) as u32) << count;
+                count += 8;
+            }
+            *value = stretch(accumulator & MASK);
+            accumulator >>= BITS;
+            count -= BITS;
+        }
+        Ok(())
+    }
+}
+
+// Which of the slow channels are in a group
+fn slow(group: usize) -> Range<usize> {
+    group * GROUP..(group + 1) * GROUP
+}
+
+// Repeat the top bits in the bottom, so 0 and full scale survive
+fn stretch(packed: u32) -> Value {
+    ((packed << (16 - BITS)) | (packed >> (2 * BITS - 16))) as Value
+}
+
+/// What a value is once it's been packed and unpacked
+pub fn quantize(value: Value) -> Value {
+    stretch(value as u32 >> (16 - BITS))
+}
+
+/// Chooses which group of slow channels goes in each message
+#[derive(Debug, Default)]
+pub struct Packer {
+    // The slow channels, as they were last sent
+    sent: [Value; CHANNELS - FAST],
+    // The next group to refresh
+    next: usize,
+    refresh: bool,
+}
+
+impl Packer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pack(&mut self, values: &[Value; CHANNELS]) -> Packed {
+        let changed = (0..GROUPS).find(|group| {
+            values[FAST..][slow(*group)].iter().zip(self.sent[slow(*group)].iter())
+                .any(|(value, sent)| quantize(*value) != quantize(*sent))
+        });
+
+        let group = match changed {
+            Some(group) if !self.refresh => group,
+            _ => self.next,
+        };
+        if group == self.next {
+            self.next = (self.next + 1) % GROUPS;
+        }
+        self.refresh = !self.refresh;
+
+        self.sent[slow(group)].copy_from_slice(&values[FAST..][slow(group)]);
+        Packed::new(values, group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::XorShift32;
+
+    fn random_values(random: &mut XorShift32) -> [Value; CHANNELS] {
+        let mut values = [0; CHANNELS];
+        for value in values.iter_mut() {
+            *value = random.next_u32() as Value;
+        }
+        values
+    }
+
+    #[test]
+    fn every_value_of_every_channel() {
+        for channel in 0..CHANNELS {
+            let group = channel.saturating_sub(FAST) / GROUP;
+            for packed in 0..=MASK {
+                let mut values = [0x8000; CHANNELS];
+                values[channel] = (packed << (16 - BITS)) as Value;
+                let mut unpacked = [0; CHANNELS];
+                Packed::new(&values, group).unpack(&mut unpacked).unwrap();
+                assert_eq!(unpacked[channel], quantize(values[channel]), "channel {}", channel);
+                assert_eq!(unpacked[channel] >> (16 - BITS), packed as Value);
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_group_is_unpacked() {
+        let mut random = XorShift32::new(21);
+        for group in 0..GROUPS {
+            let values = random_values(&mut random);
+            let mut unpacked = [0; CHANNELS];
+            Packed::new(&values, group).unpack(&mut unpacked).unwrap();
+            for (channel, (value, unpacked)) in values.iter().zip(unpacked.iter()).enumerate() {
+                if channel < FAST || slow(group).contains(&(channel - FAST)) {
+                    assert_eq!(*unpacked, quantize(*value), "channel {}", channel);
+                } else {
+                    assert_eq!(*unpacked, 0, "channel {}", channel);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quantizing() {
+        assert_eq!(quantize(0), 0);
+        assert_eq!(quantize(0xffff), 0xffff);
+        assert_eq!(quantize(0x8000), 0x8008);
+        // Within a step of where it started, and the same when quantized again
+        for value in 0..=0xffff {
+            assert!((quantize(value) as i32 - value as i32).abs() < 1 << (16 - BITS));
+            assert_eq!(quantize(quantize(value)), quantize(value));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let mut packed = Packed::new(&[0; CHANNELS], 1);
+        assert_eq!(packed.group(), Ok(1));
+        for flags in GROUPS as u8..=u8::MAX {
+            packed.flags = flags;
+            let mut values = [0x8000; CHANNELS];
+            assert_eq!(packed.unpack(&mut values), Err(Invalid));
+            assert_eq!(values, [0x8000; CHANNELS]);
+        }
+    }
+
+    #[test]
+    fn changes_go_first() {
+        let mut packer = Packer::new();
+        let mut values = [0x8000; CHANNELS];
+        for _ in 0..2 * GROUPS {
+            packer.pack(&values);
+        }
+
+        for channel in FAST..CHANNELS {
+            values[channel] ^= 0x1000;
+            let groups = [packer.pack(&values).group().unwrap(), packer.pack(&values).group().unwrap()];
+            assert!(groups.contains(&((channel - FAST) / GROUP)), "channel {}", channel);
+        }
+    }
+
+    #[test]
+    fn every_group_is_refreshed() {
+        let mut packer = Packer::new();
+        let mut values: [Value; CHANNELS] = [0x8000; CHANNELS];
+        let mut last_sent = [0; GROUPS];
+        for message in 1..=200 {
+            // The first group never stops changing
+            values[FAST] = values[FAST].wrapping_add(0x1000);
+            let group = packer.pack(&values).group().unwrap();
+            last_sent[group] = message;
+            if message > 2 * GROUPS {
+                assert!(last_sent.iter().all(|sent| message - sent < 2 * GROUPS), "at {}", message);
+            }
+        }
+    }
+
+    #[test]
+    fn the_receiver_catches_up() {
+        let mut random = XorShift32::new(21);
+        let mut packer = Packer::new();
+        let mut received = [0x8000; CHANNELS];
+        for _ in 0..100 {
+            let values = random_values(&mut random);
+            for _ in 0..2 * GROUPS {
+                packer.pack(&values).unpack(&mut received).unwrap();
+            }
+            assert_eq!(received, values.map(quantize));
+        }
+    }
+
+    #[test]
+    fn size() {
+        let mut buffer = [0; 32];
+        let packed = Packed::new(&[0xffff; CHANNELS], GROUPS - 1);
+        assert_eq!(postcard::to_slice(&packed, &mut buffer).unwrap().len(), PACKED_SIZE);
+    }
+}
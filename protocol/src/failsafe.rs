@@ -0,0 +1,195 @@
+// What a receiver should output when the link to the transmitter drops.
+//
+// Time is measured in ticks of the receiver's timer, 100 per second, and
+// passed in rather than read from hardware so this can be tested on the host.
+
+use crate::{ Value, CHANNELS };
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkState {
+    /// Packets are arriving, and the output is the last values received
+    Live,
+    /// Packets have stopped recently, and the last values received are held
+    Hold,
+    /// Packets stopped a while ago, or have never arrived, and the output is
+    /// the failsafe positions
+    FailSafe,
+}
+
+/// The throttle's channel, in the usual AETR order
+pub const THROTTLE: usize = 2;
+
+/// Where the outputs go until the transmitter says otherwise: centered, except
+/// the throttle, which is closed, so the motors stop
+pub const DEFAULT_POSITIONS: [Value; CHANNELS] = {
+    let mut positions = [0x8000; CHANNELS];
+    positions[THROTTLE] = 0;
+    positions
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Ticks without a packet before holding the last values
+    pub hold_timeout: u32,
+    /// Ticks without a packet before switching to the failsafe positions
+    pub failsafe_timeout: u32,
+    pub positions: [Value; CHANNELS],
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            hold_timeout: 10,
+            failsafe_timeout: 100,
+            positions: DEFAULT_POSITIONS,
+        }
+    }
+}
+
+pub struct Failsafe {
+    config: Config,
+    last_packet: Option<u32>,
+    values: [Value; CHANNELS],
+    state: LinkState,
+}
+
+impl Failsafe {
+    pub fn new(config: Config) -> Self {
+        Failsafe {
+            values: config.positions,
+            config,
+            last_packet: None,
+            state: LinkState::FailSafe,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The transmitter's failsafe positions, which replace the defaults
+    pub fn set_positions(&mut self, positions: [Value; CHANNELS]) {
+        self.config.positions = positions;
+    }
+
+    /// A valid packet arrived at tick `now`.
+    pub fn packet(&mut self, now: u32, values: [Value; CHANNELS]) {
+        self.last_packet = Some(now);
+        self.values = values;
+        self.state = LinkState::Live;
+    }
+
+    /// Move the clock on to tick `now`, returning the resulting state.
+    pub fn update(&mut self, now: u32) -> LinkState {
+        self.state = match self.last_packet {
+            None => LinkState::FailSafe,
+            Some(last) => {
+                let elapsed = now.wrapping_sub(last);
+                if elapsed >= self.config.failsafe_timeout {
+                    LinkState::FailSafe
+                } else if elapsed >= self.config.hold_timeout {
+                    LinkState::Hold
+                } else {
+                    LinkState::Live
+                }
+            }
+        };
+
+        self.state
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// The values to output in the current state
+    pub fn values(&self) -> [Value; CHANNELS] {
+        match self.state {
+            LinkState::Live | LinkState::Hold => self.values,
+            LinkState::FailSafe => self.config.positions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSITIONS: [Value; CHANNELS] = [0, 0x8000, 0x8000, 0x8000, 0x8000, 0x8000, 0x8000, 0x8000,
+                                          0x8000, 0x8000, 0x8000, 0x8000, 0x8000, 0x8000, 0x8000, 0xffff];
+    const VALUES: [Value; CHANNELS] = [0x1000, 0x2000, 0x3000, 0x4000, 0x5000, 0x6000, 0x7000, 0x8000,
+                                       0x9000, 0xa000, 0xb000, 0xc000, 0xd000, 0xe000, 0xf000, 0xffff];
+
+    fn failsafe() -> Failsafe {
+        Failsafe::new(Config { hold_timeout: 10, failsafe_timeout: 100, positions: POSITIONS })
+    }
+
+    #[test]
+    fn failsafe_until_first_packet() {
+        let mut failsafe = failsafe();
+        assert_eq!(failsafe.update(0), LinkState::FailSafe);
+        assert_eq!(failsafe.update(1000), LinkState::FailSafe);
+        assert_eq!(failsafe.values(), POSITIONS);
+    }
+
+    #[test]
+    fn the_default_closes_the_throttle() {
+        let mut failsafe = Failsafe::new(Config::default());
+        failsafe.packet(0, [0xffff; CHANNELS]);
+        assert_eq!(failsafe.update(1000), LinkState::FailSafe);
+        let values = failsafe.values();
+        assert_eq!(values[THROTTLE], 0);
+        assert!(values.iter().enumerate().all(|(channel, value)| channel == THROTTLE || *value == 0x8000));
+    }
+
+    #[test]
+    fn live_while_packets_arrive() {
+        let mut failsafe = failsafe();
+        for now in (0..1000).step_by(5) {
+            failsafe.packet(now, VALUES);
+            assert_eq!(failsafe.update(now + 4), LinkState::Live);
+            assert_eq!(failsafe.values(), VALUES);
+        }
+    }
+
+    #[test]
+    fn hold_then_failsafe() {
+        let mut failsafe = failsafe();
+        failsafe.packet(50, VALUES);
+        assert_eq!(failsafe.update(59), LinkState::Live);
+        assert_eq!(failsafe.update(60), LinkState::Hold);
+        assert_eq!(failsafe.values(), VALUES);
+        assert_eq!(failsafe.update(149), LinkState::Hold);
+        assert_eq!(failsafe.update(150), LinkState::FailSafe);
+        assert_eq!(failsafe.values(), POSITIONS);
+    }
+
+    #[test]
+    fn recovers_when_packets_resume() {
+        let mut failsafe = failsafe();
+        failsafe.packet(0, VALUES);
+        assert_eq!(failsafe.update(500), LinkState::FailSafe);
+
+        let values = [0x5000; CHANNELS];
+        failsafe.packet(501, values);
+        assert_eq!(failsafe.state(), LinkState::Live);
+        assert_eq!(failsafe.update(502), LinkState::Live);
+        assert_eq!(failsafe.values(), values);
+    }
+
+    #[test]
+    fn positions_from_transmitter() {
+        let mut failsafe = failsafe();
+        failsafe.set_positions(VALUES);
+        assert_eq!(failsafe.update(0), LinkState::FailSafe);
+        assert_eq!(failsafe.values(), VALUES);
+    }
+
+    #[test]
+    fn survives_counter_wrap() {
+        let mut failsafe = failsafe();
+        failsafe.packet(u32::MAX - 2, VALUES);
+        assert_eq!(failsafe.update(2), LinkState::Live);
+        assert_eq!(failsafe.update(20), LinkState::Hold);
+    }
+}
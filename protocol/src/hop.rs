@@ -0,0 +1,328 @@
+// Frequency hopping. The transmitter sends each message on the next channel of
+// a pseudo-random sequence, generated from the hop seed it shared when it was
+// bound. The channel comes from the correlation id, so the receiver can find
+// its place in the sequence from any message it receives.
+//
+// The receiver follows the sequence by itself while messages are missing. If
+// too many are missing it scans: it waits on one channel long enough for the
+// transmitter to visit every channel in the sequence, then tries the next.
+
+use crate::{ bind::BIND_FREQUENCY, random::XorShift32 };
+
+/// The nRF24 can tune 0..=125, i.e. 2400MHz to 2525MHz
+pub const RADIO_MAX_CHANNEL: u8 = 125;
+/// But the ISM band stops at 2483.5MHz
+pub const MAX_CHANNEL: u8 = 83;
+/// The length of the sequence. It's a power of two, so the sequence carries on
+/// smoothly when the correlation id wraps.
+pub const HOPS: usize = 16;
+
+/// Channels to keep out of the hop sequence, of those the radio can tune
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Blacklist(u128);
+
+impl Blacklist {
+    pub const fn new() -> Self {
+        Blacklist(0)
+    }
+
+    pub const fn with(self, channel: u8) -> Self {
+        assert!(channel <= RADIO_MAX_CHANNEL, "the radio can't tune that channel");
+        Blacklist(self.0 | 1 << channel)
+    }
+
+    /// The channels from `first` to `last`, as far as the radio goes
+    pub const fn with_range(self, first: u8, last: u8) -> Self {
+        let mut blacklist = self;
+        let mut channel = first;
+        while channel <= last && channel <= RADIO_MAX_CHANNEL {
+            blacklist = blacklist.with(channel);
+            channel = match channel.checked_add(1) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        blacklist
+    }
+
+    pub fn contains(&self, channel: u8) -> bool {
+        channel <= RADIO_MAX_CHANNEL && self.0 & (1 << channel) != 0
+    }
+}
+
+/// The channels both ends leave out. The transmitter and receiver must agree.
+pub const BLACKLIST: Blacklist = Blacklist::new().with(BIND_FREQUENCY);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HopSequence {
+    channels: [u8; HOPS],
+}
+
+impl HopSequence {
+    pub fn new(seed: u32, blacklist: &Blacklist) -> Self {
+        let mut allowed = [0; MAX_CHANNEL as usize + 1];
+        let mut count = 0;
+        for channel in (0..=MAX_CHANNEL).filter(|channel| !blacklist.contains(*channel)) {
+            allowed[count] = channel;
+            count += 1;
+        }
+
+        // Nothing left to hop between, so ignore the blacklist
+        if count == 0 {
+            return Self::new(seed, &Blacklist::new());
+        }
+
+        // Shuffle just as much of the allowed channels as we need
+        let mut random = XorShift32::new(seed);
+        for i in 0..HOPS.min(count) {
+            let j = i + random.below((count - i) as u32) as usize;
+            allowed.swap(i, j);
+        }
+
+        // If too much is blacklisted, channels repeat
+        let mut channels = [0; HOPS];
+        for (i, channel) in channels.iter_mut().enumerate() {
+            *channel = allowed[i % count];
+        }
+        HopSequence { channels }
+    }
+
+    /// The channel for the message with this correlation id
+    pub fn channel(&self, correlation_id: u32) -> u8 {
+        self.channels[correlation_id as usize % HOPS]
+    }
+
+    pub fn channels(&self) -> &[u8; HOPS] {
+        &self.channels
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// The time between messages, in whatever units the receiver measures time
+    pub period: u32,
+    /// How many messages in a row can be missed before scanning
+    pub lost: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HopState {
+    /// Following the transmitter
+    Synced,
+    /// Waiting on each channel in turn for the transmitter
+    Scanning,
+}
+
+/// The receiver's side of hopping. Call `update` several times a period, and
+/// `packet` whenever a message arrives, and listen on the channel they return.
+pub struct RxHopper {
+    sequence: HopSequence,
+    config: Config,
+    state: HopState,
+    // The correlation id of the last message, or while scanning, the one we're waiting for
+    correlation_id: u32,
+    // When the last message arrived, or while scanning, when we got to this channel
+    since: u32,
+    // How many periods after the last message we're listening for
+    offset: u32,
+}
+
+impl RxHopper {
+    pub fn new(sequence: HopSequence, config: Config, now: u32) -> Self {
+        RxHopper { sequence, config, state: HopState::Scanning, correlation_id: 0, since: now, offset: 0 }
+    }
+
+    pub fn state(&self) -> HopState {
+        self.state
+    }
+
+    /// The channel to listen on
+    pub fn channel(&self) -> u8 {
+        self.sequence.channel(self.correlation_id.wrapping_add(self.offset))
+    }
+
+    /// A message arrived. The next will be on the next channel.
+    pub fn packet(&mut self, now: u32, correlation_id: u32) -> u8 {
+        self.state = HopState::Synced;
+        self.correlation_id = correlation_id;
+        self.since = now;
+        self.offset = 1;
+        self.channel()
+    }
+
+    /// Returns a new channel, if it's time to change
+    pub fn update(&mut self, now: u32) -> Option<u8> {
+        let channel = self.channel();
+        let elapsed = now.wrapping_sub(self.since);
+        match self.state {
+            HopState::Synced => {
+                // Listen for each message from half a period before it's due,
+                // until half a period after
+                let offset = (elapsed.saturating_add(self.config.period / 2) / self.config.period).max(1);
+                if offset > self.config.lost {
+                    self.state = HopState::Scanning;
+                    self.correlation_id = self.correlation_id.wrapping_add(self.offset);
+                    self.since = now;
+                    self.offset = 0;
+                } else {
+                    self.offset = offset;
+                }
+            },
+
+            // Wait for the transmitter to go all the way round the sequence, and then some
+            HopState::Scanning => {
+                if elapsed >= (HOPS as u32 + 1) * self.config.period {
+                    self.correlation_id = self.correlation_id.wrapping_add(1);
+                    self.since = now;
+                }
+            },
+        }
+
+        if self.channel() != channel { Some(self.channel()) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: u32 = 100;
+    const CONFIG: Config = Config { period: PERIOD, lost: 5 };
+
+    #[test]
+    fn sequence_is_deterministic() {
+        let a = HopSequence::new(1234, &BLACKLIST);
+        assert_eq!(a, HopSequence::new(1234, &BLACKLIST));
+        assert_ne!(a, HopSequence::new(1235, &BLACKLIST));
+    }
+
+    #[test]
+    fn sequence_avoids_blacklist() {
+        // Wi-Fi channel 6
+        let blacklist = Blacklist::new().with_range(26, 48);
+        for seed in 0..100 {
+            let sequence = HopSequence::new(seed, &blacklist);
+            for channel in sequence.channels() {
+                assert!(*channel <= MAX_CHANNEL);
+                assert!(!blacklist.contains(*channel));
+            }
+
+            // All different
+            let mut seen = Blacklist::new();
+            for channel in sequence.channels() {
+                assert!(!seen.contains(*channel));
+                seen = seen.with(*channel);
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_repeats_when_mostly_blacklisted() {
+        let blacklist = Blacklist::new().with_range(0, 79);
+        let sequence = HopSequence::new(1, &blacklist);
+        for channel in sequence.channels() {
+            assert!((80..=MAX_CHANNEL).contains(channel));
+        }
+    }
+
+    #[test]
+    fn blacklist_stops_at_the_radios_last_channel() {
+        let blacklist = Blacklist::new().with_range(120, 255);
+        assert!((120..=RADIO_MAX_CHANNEL).all(|channel| blacklist.contains(channel)));
+        assert!(!blacklist.contains(119));
+        assert!(!blacklist.contains(200));
+        assert!(!Blacklist::new().contains(255));
+    }
+
+    #[test]
+    #[should_panic]
+    fn blacklist_rejects_channels_the_radio_cant_tune() {
+        let _ = Blacklist::new().with(200);
+    }
+
+    // A transmitter and a receiver, and the time, for the receiver's clock
+    struct Link {
+        sequence: HopSequence,
+        receiver: RxHopper,
+        now: u32,
+        correlation_id: u32,
+    }
+
+    impl Link {
+        fn new(seed: u32) -> Self {
+            let sequence = HopSequence::new(seed, &BLACKLIST);
+            Link { receiver: RxHopper::new(sequence.clone(), CONFIG, 0), sequence, now: 0, correlation_id: 1000 }
+        }
+
+        // Send `count` messages, `period` apart, the ones which `lost` picks never arrive
+        fn run(&mut self, count: u32, period: u32, lost: impl Fn(u32) -> bool) -> u32 {
+            let mut received = 0;
+            for _ in 0..count {
+                for _ in 0..10 {
+                    self.now += period / 10;
+                    self.receiver.update(self.now);
+                }
+
+                self.correlation_id = self.correlation_id.wrapping_add(1);
+                let channel = self.sequence.channel(self.correlation_id);
+                if channel == self.receiver.channel() && !lost(self.correlation_id) {
+                    self.receiver.packet(self.now, self.correlation_id);
+                    received += 1;
+                }
+            }
+            received
+        }
+    }
+
+    #[test]
+    fn follows_when_synced() {
+        let mut link = Link::new(42);
+        link.run((HOPS as u32 + 1) * HOPS as u32, PERIOD, |_| false);
+        assert_eq!(link.receiver.state(), HopState::Synced);
+        assert_eq!(link.run(1000, PERIOD, |_| false), 1000);
+    }
+
+    #[test]
+    fn tolerates_clock_drift() {
+        let mut link = Link::new(42);
+        link.run((HOPS as u32 + 1) * HOPS as u32, PERIOD, |_| false);
+        assert_eq!(link.run(1000, PERIOD + 10, |_| false), 1000);
+        assert_eq!(link.run(1000, PERIOD - 10, |_| false), 1000);
+    }
+
+    #[test]
+    fn keeps_hopping_through_losses() {
+        let mut link = Link::new(7);
+        link.run((HOPS as u32 + 1) * HOPS as u32, PERIOD, |_| false);
+
+        // Lose 4 in every 10, which is less than CONFIG.lost in a row
+        let received = link.run(1000, PERIOD, |id| id % 10 < 4);
+        assert_eq!(received, 600);
+        assert_eq!(link.receiver.state(), HopState::Synced);
+    }
+
+    #[test]
+    fn scans_and_reacquires() {
+        let mut link = Link::new(99);
+        link.run((HOPS as u32 + 1) * HOPS as u32, PERIOD, |_| false);
+
+        // The transmitter goes away
+        assert_eq!(link.run(100, PERIOD, |_| true), 0);
+        assert_eq!(link.receiver.state(), HopState::Scanning);
+
+        // And comes back after a pause
+        link.now += 12345;
+        link.run((HOPS as u32 + 1) * 2, PERIOD, |_| false);
+        assert_eq!(link.receiver.state(), HopState::Synced);
+        assert_eq!(link.run(100, PERIOD, |_| false), 100);
+    }
+
+    #[test]
+    fn scan_skips_a_jammed_channel() {
+        let mut link = Link::new(5);
+        let jammed = link.receiver.channel();
+        let sequence = link.sequence.clone();
+        link.run((HOPS as u32 + 1) * 3, PERIOD, |id| sequence.channel(id) == jammed);
+        assert_eq!(link.receiver.state(), HopState::Synced);
+    }
+}
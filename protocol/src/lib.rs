@@ -1,6 +1,185 @@
 #![no_std]
 
+use core::mem::size_of;
+
+use serde::{ Deserialize, Serialize };
+
+pub mod auth;
+pub mod bind;
+pub mod channels;
+pub mod failsafe;
+pub mod hop;
+pub mod quality;
+pub mod random;
+pub mod telemetry;
+
+use bind::{ BindInfo, ModelId };
+use channels::{ Packed, PACKED_SIZE };
+use telemetry::{ Attitude, Battery, Gps, Link };
+
 pub const FREQUENCY : u8 = 76;
-pub const TX_ADDRESS : [u8;5] = [ 'R' as u8, 'C' as u8, 'T' as u8, 'X' as u8, 0x00 ];
-pub const RX_ADDRESS : [u8;5] = [ 'R' as u8, 'C' as u8, 'R' as u8, 'X' as u8, 0x00 ];
+pub const TX_ADDRESS : [u8;5] = [ b'R', b'C', b'T', b'X', 0x00 ];
+pub const RX_ADDRESS : [u8;5] = [ b'R', b'C', b'R', b'X', 0x00 ];
+
+// Messages are serialized with postcard, and each one must fit in a single nRF24 payload.
+pub const PAYLOAD_SIZE: usize = 32;
+
+// Bump this whenever the layout of a message changes, so a receiver can
+// ignore a transmitter running incompatible firmware rather than misinterpret it.
+pub const VERSION: u8 = 4;
+
+pub const CHANNELS: usize = 16;
+
+/// A channel value, using the whole range of a u16. The receiver scales it
+/// to whatever its output protocol needs.
+pub type Value = u16;
+
+// postcard encodes integers in their natural size, and enum variants as
+// varints, which take a single byte for fewer than 128 variants.
+const VARIANT_MAX_SIZE: usize = 1;
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+/// Every message from the transmitter to the receiver
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Transmitter {
+    pub version: u8,
+    /// Incremented for every message sent, so the receiver can count the ones it missed
+    pub correlation_id: u32,
+    pub body: TransmitterMessage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TransmitterMessage {
+    ChannelValues(Packed),
+    /// Broadcast on the bind address
+    Bind(BindInfo),
+    /// The positions for the receiver to output when the link is lost. Sent
+    /// every so often, and instead of the channel values until the
+    /// transmitter is sure it's talking to the right model.
+    Failsafe(Packed),
+}
+
+impl TransmitterMessage {
+    pub const MAX_SIZE: usize = VARIANT_MAX_SIZE + max(PACKED_SIZE, BindInfo::SIZE);
+}
+
+impl Transmitter {
+    pub const MAX_SIZE: usize = size_of::<u8>() + size_of::<u32>() + TransmitterMessage::MAX_SIZE;
+
+    pub fn new(correlation_id: u32, body: TransmitterMessage) -> Self {
+        Transmitter { version: VERSION, correlation_id, body }
+    }
+
+    pub fn encode<'a>(&self, buffer: &'a mut [u8; PAYLOAD_SIZE]) -> postcard::Result<&'a mut [u8]> {
+        postcard::to_slice(self, buffer)
+    }
+
+    pub fn decode(payload: &[u8]) -> postcard::Result<Self> {
+        postcard::from_bytes(payload)
+    }
+}
+
+const _: () = assert!(Transmitter::MAX_SIZE <= PAYLOAD_SIZE);
+
+/// Every message from the receiver to the transmitter, sent in an
+/// acknowledgement payload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Receiver {
+    pub version: u8,
+    /// The last message received from the transmitter
+    pub correlation_id: u32,
+    pub body: ReceiverMessage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ReceiverMessage {
+    Link(Link),
+    Battery(Battery),
+    Attitude(Attitude),
+    Gps(Gps),
+    /// Acknowledges a bind message, with the receiver's model ID
+    Bound(BindInfo, ModelId),
+}
+
+impl ReceiverMessage {
+    pub const MAX_SIZE: usize = VARIANT_MAX_SIZE +
+        max(max(Link::SIZE, Battery::SIZE), max(max(Attitude::SIZE, Gps::SIZE), BindInfo::SIZE + size_of::<ModelId>()));
+}
+
+impl Receiver {
+    pub const MAX_SIZE: usize = size_of::<u8>() + size_of::<u32>() + ReceiverMessage::MAX_SIZE;
+
+    pub fn new(correlation_id: u32, body: ReceiverMessage) -> Self {
+        Receiver { version: VERSION, correlation_id, body }
+    }
+
+    pub fn encode<'a>(&self, buffer: &'a mut [u8; PAYLOAD_SIZE]) -> postcard::Result<&'a mut [u8]> {
+        postcard::to_slice(self, buffer)
+    }
+
+    pub fn decode(payload: &[u8]) -> postcard::Result<Self> {
+        postcard::from_bytes(payload)
+    }
+}
+
+const _: () = assert!(Receiver::MAX_SIZE <= PAYLOAD_SIZE);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Transmitter) -> usize {
+        let mut buffer = [0; PAYLOAD_SIZE];
+        let size = message.encode(&mut buffer).unwrap().len();
+        assert_eq!(Transmitter::decode(&buffer[..size]).unwrap(), message);
+        size
+    }
+
+    #[test]
+    fn channel_values_round_trip() {
+        let mut values = [0x8000; CHANNELS];
+        values[..4].copy_from_slice(&[0, 0x7fff, 0x8000, 0xffff]);
+        round_trip(Transmitter::new(1, TransmitterMessage::ChannelValues(Packed::new(&values, 0))));
+    }
+
+    #[test]
+    fn largest_message_fits() {
+        let size = round_trip(Transmitter::new(u32::MAX, TransmitterMessage::ChannelValues(Packed::new(&[u16::MAX; CHANNELS], 1))));
+        assert!(size <= Transmitter::MAX_SIZE);
+        let bind = BindInfo { address: [0xff; 5], hop_seed: u32::MAX, key: [0xff; 16] };
+        let size = round_trip(Transmitter::new(u32::MAX, TransmitterMessage::Bind(bind)));
+        assert_eq!(size, Transmitter::MAX_SIZE);
+    }
+
+    #[test]
+    fn telemetry_round_trip() {
+        let messages = [
+            ReceiverMessage::Link(Link { quality: 100, rssi: 50, voltage: 5100, model_id: 0xff }),
+            ReceiverMessage::Battery(Battery { voltage: 168, current: 123, capacity: 0xffffff, remaining: 50 }),
+            ReceiverMessage::Attitude(Attitude { pitch: -1, roll: i16::MAX, yaw: i16::MIN }),
+            ReceiverMessage::Gps(Gps {
+                latitude: i32::MIN, longitude: i32::MAX,
+                ground_speed: u16::MAX, heading: u16::MAX, altitude: u16::MAX, satellites: u8::MAX
+            }),
+            ReceiverMessage::Bound(BindInfo { address: [0xff; 5], hop_seed: u32::MAX, key: [0xff; 16] }, 0xff),
+        ];
+
+        for body in messages.iter() {
+            let message = Receiver::new(u32::MAX, body.clone());
+            let mut buffer = [0; PAYLOAD_SIZE];
+            let size = message.encode(&mut buffer).unwrap().len();
+            assert!(size <= Receiver::MAX_SIZE);
+            assert_eq!(Receiver::decode(&buffer[..size]).unwrap(), message);
+        }
+    }
 
+    #[test]
+    fn version_is_first_byte() {
+        let mut buffer = [0; PAYLOAD_SIZE];
+        Transmitter::new(0, TransmitterMessage::ChannelValues(Packed::new(&[0; CHANNELS], 0))).encode(&mut buffer).unwrap();
+        assert_eq!(buffer[0], VERSION);
+    }
+}
@@ -0,0 +1,90 @@
+// The nRF24 can't measure signal strength, so the quality of the link is
+// estimated from the messages which were missed, according to their
+// correlation ids.
+
+// A bigger jump than this, about 11 minutes of messages, is the transmitter
+// starting a new session, rather than messages going missing
+const MAX_GAP: u32 = 1 << 16;
+
+/// The number of messages missed between the last message and this one
+pub fn missed(last_correlation_id: u32, correlation_id: u32) -> u32 {
+    let gap = correlation_id.wrapping_sub(last_correlation_id).wrapping_sub(1);
+    if gap < MAX_GAP { gap } else { 0 }
+}
+
+#[derive(Debug, Default)]
+pub struct LinkQuality {
+    last_correlation_id: u32,
+    missed_messages: u32,
+    quality: u8,
+}
+
+impl LinkQuality {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Calculate the quality over the period since the last update, from the
+    /// receiver's running totals.
+    pub fn update(&mut self, last_correlation_id: u32, missed_messages: u32) -> u8 {
+        let expected = last_correlation_id.wrapping_sub(self.last_correlation_id);
+        let missed = missed_messages.wrapping_sub(self.missed_messages).min(expected);
+        self.quality = if expected == 0 {
+            0
+        } else {
+            ((expected - missed) as u64 * 100 / expected as u64) as u8
+        };
+
+        self.last_correlation_id = last_correlation_id;
+        self.missed_messages = missed_messages;
+        self.quality
+    }
+
+    /// The percentage of messages received
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    /// A stand in for RSSI, in -dBm, from -50dBm for a perfect link down to
+    /// -130dBm for no link at all.
+    pub fn rssi(&self) -> u8 {
+        130 - (self.quality as u16 * 80 / 100) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perfect_link() {
+        let mut quality = LinkQuality::new();
+        assert_eq!(quality.update(100, 0), 100);
+        assert_eq!(quality.update(200, 0), 100);
+        assert_eq!(quality.rssi(), 50);
+    }
+
+    #[test]
+    fn lossy_link() {
+        let mut quality = LinkQuality::new();
+        quality.update(100, 5);
+        assert_eq!(quality.update(200, 30), 75);
+    }
+
+    #[test]
+    fn no_link() {
+        let mut quality = LinkQuality::new();
+        quality.update(100, 0);
+        assert_eq!(quality.update(100, 0), 0);
+        assert_eq!(quality.rssi(), 130);
+    }
+
+    #[test]
+    fn missed_messages() {
+        assert_eq!(missed(10, 11), 0);
+        assert_eq!(missed(10, 15), 4);
+        assert_eq!(missed(u32::MAX, 1), 1);
+        // A new session
+        assert_eq!(missed(1000, 1 << 24), 0);
+    }
+}
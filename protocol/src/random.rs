@@ -0,0 +1,28 @@
+// A small, fast pseudo-random number generator, for when the same seed must
+// give the same sequence on the transmitter and the receiver. It's not
+// cryptographically secure.
+
+/// Marsaglia's xorshift32
+#[derive(Debug, Clone)]
+pub struct XorShift32(u32);
+
+impl XorShift32 {
+    pub fn new(seed: u32) -> Self {
+        // Zero is the one state xorshift can't leave
+        XorShift32(if seed == 0 { 0x9e37_79b9 } else { seed })
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// A number in the range 0..bound
+    pub fn below(&mut self, bound: u32) -> u32 {
+        ((self.next_u32() as u64 * bound as u64) >> 32) as u32
+    }
+}
@@ -0,0 +1,167 @@
+// Telemetry sent from the receiver back to the transmitter, in the nRF24
+// auto-acknowledgement payloads. Only one message fits in each payload, so
+// the receiver takes turns with the telemetry it has.
+//
+// The units are those used by CRSF, so telemetry from a flight controller
+// can be forwarded without losing precision.
+
+use core::mem::size_of;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::{ bind::ModelId, ReceiverMessage };
+
+/// The receiver's view of the link
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Link {
+    /// The percentage of messages received
+    pub quality: u8,
+    /// A stand in for RSSI, in -dBm
+    pub rssi: u8,
+    /// The receiver's supply voltage in mV
+    pub voltage: u16,
+    /// Which model the receiver is in
+    pub model_id: ModelId,
+}
+
+impl Link {
+    pub const SIZE: usize = 2 * size_of::<u8>() + size_of::<u16>() + size_of::<ModelId>();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Battery {
+    /// In units of 0.1V
+    pub voltage: u16,
+    /// In units of 0.1A
+    pub current: u16,
+    /// Used capacity in mAh
+    pub capacity: u32,
+    /// Percentage
+    pub remaining: u8,
+}
+
+impl Battery {
+    pub const SIZE: usize = 2 * size_of::<u16>() + size_of::<u32>() + size_of::<u8>();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Attitude {
+    /// All in units of 0.0001 radians
+    pub pitch: i16,
+    pub roll: i16,
+    pub yaw: i16,
+}
+
+impl Attitude {
+    pub const SIZE: usize = 3 * size_of::<i16>();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Gps {
+    /// In units of 0.0000001 degrees
+    pub latitude: i32,
+    pub longitude: i32,
+    /// In units of 0.1 km/h
+    pub ground_speed: u16,
+    /// In units of 0.01 degrees
+    pub heading: u16,
+    /// In meters, offset by 1000, so 1000 is sea level
+    pub altitude: u16,
+    pub satellites: u8,
+}
+
+impl Gps {
+    pub const SIZE: usize = 2 * size_of::<i32>() + 3 * size_of::<u16>() + size_of::<u8>();
+}
+
+/// The latest telemetry. The receiver collects it to send, and the
+/// transmitter collects it as it arrives.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Telemetry {
+    pub link: Link,
+    pub battery: Option<Battery>,
+    pub attitude: Option<Attitude>,
+    pub gps: Option<Gps>,
+    next: usize,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The next message to send. The link is sent every other time, and the
+    /// flight controller's telemetry, if there is any, in between.
+    pub fn next_message(&mut self) -> ReceiverMessage {
+        self.next = (self.next + 1) % 6;
+        let message = match self.next {
+            1 => self.battery.clone().map(ReceiverMessage::Battery),
+            3 => self.attitude.clone().map(ReceiverMessage::Attitude),
+            5 => self.gps.clone().map(ReceiverMessage::Gps),
+            _ => None,
+        };
+
+        message.unwrap_or_else(|| ReceiverMessage::Link(self.link.clone()))
+    }
+
+    pub fn update(&mut self, message: ReceiverMessage) {
+        match message {
+            ReceiverMessage::Link(link) => self.link = link,
+            ReceiverMessage::Battery(battery) => self.battery = Some(battery),
+            ReceiverMessage::Attitude(attitude) => self.attitude = Some(attitude),
+            ReceiverMessage::Gps(gps) => self.gps = Some(gps),
+            ReceiverMessage::Bound(..) => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_only() {
+        let mut telemetry = Telemetry::new();
+        telemetry.link.quality = 90;
+        for _ in 0..10 {
+            assert_eq!(telemetry.next_message(), ReceiverMessage::Link(telemetry.link.clone()));
+        }
+    }
+
+    #[test]
+    fn takes_turns() {
+        let mut telemetry = Telemetry::new();
+        telemetry.battery = Some(Battery { voltage: 168, ..Default::default() });
+        telemetry.gps = Some(Gps { satellites: 8, ..Default::default() });
+
+        let mut links = 0;
+        let mut batteries = 0;
+        let mut gps = 0;
+        for _ in 0..60 {
+            match telemetry.next_message() {
+                ReceiverMessage::Link(_) => links += 1,
+                ReceiverMessage::Battery(_) => batteries += 1,
+                ReceiverMessage::Gps(_) => gps += 1,
+                ReceiverMessage::Attitude(_) | ReceiverMessage::Bound(..) => panic!("unexpected message"),
+            }
+        }
+
+        assert_eq!((links, batteries, gps), (40, 10, 10));
+    }
+
+    #[test]
+    fn update_collects_messages() {
+        let mut sent = Telemetry::new();
+        sent.link = Link { quality: 80, rssi: 66, voltage: 5000, model_id: 7 };
+        sent.attitude = Some(Attitude { pitch: 1, roll: 2, yaw: 3 });
+
+        let mut received = Telemetry::new();
+        for _ in 0..6 {
+            received.update(sent.next_message());
+        }
+
+        assert_eq!(received.link, sent.link);
+        assert_eq!(received.attitude, sent.attitude);
+        assert_eq!(received.battery, None);
+    }
+}
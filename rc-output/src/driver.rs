@@ -0,0 +1,228 @@
+// An output which owns its serial port and never blocks on it, like the SUMD
+// driver, for any RcOutput. A frame is written as far as the port will accept,
+// and the rest can be written later, e.g. from the transmit interrupt.
+
+use embedded_hal::serial::Write;
+use heapless::Vec;
+
+use crate::{ Link, RcOutput, MAX_CHANNELS, MAX_FRAME_SIZE };
+
+pub struct FrameBuffer(pub Vec<u8, MAX_FRAME_SIZE>);
+
+impl Write<u8> for FrameBuffer {
+    type Error = u8;
+
+    /// Writes a single word to the serial interface
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.0.push(word)?;
+        Ok(())
+    }
+
+    /// Ensures that none of the previously written words are still buffered
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// A frame from `output`, or nothing if it doesn't fit
+    pub fn encode<O: RcOutput>(&mut self, output: &O, link: &Link, values: &[u16]) {
+        self.0.clear();
+        if output.send(self, link, values).is_err() {
+            self.0.clear();
+        }
+    }
+}
+
+pub struct Driver<W, O> {
+    out: W,
+    output: O,
+    // The latest values, until they're in a frame
+    next: Option<(Link, Vec<u16, MAX_CHANNELS>)>,
+    frame: FrameBuffer,
+    // The next byte of the frame to write. The frame is complete when this is its length.
+    position: usize,
+}
+
+impl<W: Write<u8>, O: RcOutput> Driver<W, O> {
+    pub fn new(out: W, output: O) -> Self {
+        Driver { out, output, next: None, frame: FrameBuffer::new(), position: 0 }
+    }
+
+    pub fn release(self) -> W {
+        self.out
+    }
+
+    /// The serial port, e.g. to enable or disable its transmit interrupt.
+    pub fn writer(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    /// Switch protocols. A frame which is in progress is finished first.
+    pub fn set_output(&mut self, output: O) {
+        self.output = output;
+    }
+
+    /// True while part of a frame is still to be written
+    pub fn is_sending(&self) -> bool {
+        self.position < self.frame.0.len()
+    }
+
+    /// Send a frame with these values, after any frame which is already in
+    /// progress, writing as much as the port will accept. Values which are
+    /// still waiting for a frame are replaced.
+    ///
+    /// Returns `WouldBlock` if the port fills up; call `poll` until it returns
+    /// `Ok` to write the rest.
+    pub fn send(&mut self, link: Link, values: &[u16]) -> nb::Result<(), W::Error> {
+        let values = &values[..values.len().min(MAX_CHANNELS)];
+        self.next = Some((link, Vec::from_slice(values).unwrap_or_default()));
+        self.poll()
+    }
+
+    /// Write the rest of the frame in progress, then a frame of the latest
+    /// values, if they haven't been sent yet.
+    ///
+    /// Returns `Ok` when there's nothing left to write, otherwise
+    /// `WouldBlock`.
+    pub fn poll(&mut self) -> nb::Result<(), W::Error> {
+        loop {
+            while self.is_sending() {
+                self.out.write(self.frame.0[self.position])?;
+                self.position += 1;
+            }
+
+            match self.next.take() {
+                Some((link, values)) => {
+                    self.frame.encode(&self.output, &link, &values);
+                    self.position = 0;
+                },
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LinkState;
+
+    // A serial port which refuses every nth write
+    struct FlakyPort {
+        written: Vec<u8, 1024>,
+        every: usize,
+        count: usize,
+    }
+
+    impl FlakyPort {
+        fn new(every: usize) -> Self {
+            FlakyPort { written: Vec::new(), every, count: 0 }
+        }
+    }
+
+    impl Write<u8> for FlakyPort {
+        type Error = ();
+
+        fn write(&mut self, word: u8) -> nb::Result<(), ()> {
+            self.count += 1;
+            if self.count.is_multiple_of(self.every) {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.written.push(word).map_err(|_| nb::Error::Other(()))
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    // A frame is a header, the state, and the top byte of each value
+    struct Test(u8);
+
+    impl RcOutput for Test {
+        fn send<W: Write<u8>>(&self, out: &mut W, link: &Link, values: &[u16]) -> nb::Result<(), W::Error> {
+            out.write(self.0)?;
+            out.write(link.state as u8)?;
+            for value in values {
+                out.write((value >> 8) as u8)?;
+            }
+            Ok(())
+        }
+    }
+
+    const LIVE: Link = Link { state: LinkState::Live, quality: 100, rssi: 50 };
+
+    #[test]
+    fn send_without_blocking() {
+        let mut driver = Driver::new(FlakyPort::new(usize::MAX), Test(0xaa));
+        assert_eq!(driver.send(LIVE, &[0x1000, 0x2000]), Ok(()));
+        assert!(!driver.is_sending());
+        assert_eq!(driver.release().written, [0xaa, 0, 0x10, 0x20]);
+    }
+
+    #[test]
+    fn poll_resumes_frame() {
+        let mut driver = Driver::new(FlakyPort::new(3), Test(0xaa));
+        assert_eq!(driver.send(LIVE, &[0x1000, 0x2000, 0x3000]), Err(nb::Error::WouldBlock));
+        assert!(driver.is_sending());
+        while driver.poll() != Ok(()) {}
+        assert_eq!(driver.release().written, [0xaa, 0, 0x10, 0x20, 0x30]);
+    }
+
+    #[test]
+    fn frames_never_corrupted() {
+        let mut driver = Driver::new(FlakyPort::new(4), Test(0xaa));
+        for i in 0..10 {
+            let _ = driver.send(LIVE, &[i << 8, 0xff00 - (i << 8)]);
+        }
+        while driver.poll() != Ok(()) {}
+
+        let written = driver.release().written;
+        assert_eq!(written.len() % 4, 0);
+        assert!(written.len() > 4);
+        for frame in written.chunks(4) {
+            assert_eq!(frame[..2], [0xaa, 0]);
+            assert_eq!(frame[2] as u16 + frame[3] as u16, 0xff);
+        }
+    }
+
+    #[test]
+    fn switching_outputs() {
+        let mut driver = Driver::new(FlakyPort::new(3), Test(0xaa));
+        let _ = driver.send(LIVE, &[0x1000]);
+        driver.set_output(Test(0x55));
+        let failsafe = Link { state: LinkState::FailSafe, ..LIVE };
+        let _ = driver.send(failsafe, &[0x2000]);
+        while driver.poll() != Ok(()) {}
+        assert_eq!(driver.release().written, [0xaa, 0, 0x10, 0x55, 2, 0x20]);
+    }
+
+    #[test]
+    fn extra_channels_are_left_out() {
+        let mut driver = Driver::new(FlakyPort::new(usize::MAX), Test(0xaa));
+        assert_eq!(driver.send(LIVE, &[0x0100; MAX_CHANNELS + 8]), Ok(()));
+        assert_eq!(driver.release().written.len(), 2 + MAX_CHANNELS);
+    }
+
+    #[test]
+    fn frames_which_dont_fit_are_dropped() {
+        let mut buffer = FrameBuffer::new();
+        buffer.encode(&Test(0xaa), &LIVE, &[0; MAX_FRAME_SIZE]);
+        assert!(buffer.0.is_empty());
+    }
+}
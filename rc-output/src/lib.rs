@@ -0,0 +1,43 @@
+// The serial protocols a receiver can talk to a flight controller with all
+// come down to the same thing: a frame of channel values, and what the link's
+// doing, written to a UART. Each protocol's encoder implements RcOutput, so the
+// firmware can use whichever one it's configured for, and change its mind.
+#![no_std]
+
+use embedded_hal::serial::Write;
+
+use protocol::quality::LinkQuality;
+
+mod driver;
+
+pub use driver::{ Driver, FrameBuffer };
+pub use protocol::failsafe::LinkState;
+
+/// The most channels any of the protocols carries: SUMD's 32
+pub const MAX_CHANNELS: usize = 32;
+/// The most bytes any of the protocols sends at once: a SUMD frame of 32 channels
+pub const MAX_FRAME_SIZE: usize = 69;
+
+/// Everything the protocols can tell the flight controller about the link.
+/// Each uses what it has room for: SUMD and SBUS only have flags for the
+/// state, and CRSF only has the link statistics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Link {
+    pub state: LinkState,
+    /// The percentage of messages received
+    pub quality: u8,
+    /// In -dBm
+    pub rssi: u8,
+}
+
+impl Link {
+    pub fn new(state: LinkState, quality: &LinkQuality) -> Self {
+        Link { state, quality: quality.quality(), rssi: quality.rssi() }
+    }
+}
+
+pub trait RcOutput {
+    /// Write a frame of these values. The values use the whole range of a
+    /// u16, and any channels the protocol has no room for are left out.
+    fn send<W: Write<u8>>(&self, out: &mut W, link: &Link, values: &[u16]) -> nb::Result<(), W::Error>;
+}
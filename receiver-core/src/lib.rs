@@ -0,0 +1,521 @@
+// The receiver's application logic, independent of the board it runs on:
+// binding, following the transmitter's hops, checking its messages, failsafe,
+// and the telemetry to send back. Each board's firmware provides a Radio,
+// calls `receive` from the radio's interrupt, `hop` from a fast timer and
+// `tick` from a 100Hz timer, and sends to the flight controller whatever
+// `tick` returns, in the output protocol it's configured for, or drives servos
+// with it.
+//
+// There are two clocks: `tick` counts the 100Hz ticks, for binding and
+// failsafe, and `now` is in whatever units the board measures the hop
+// period in.
+#![no_std]
+
+pub mod output;
+pub mod radio;
+pub mod servo;
+
+pub use radio::{ Payload, Radio };
+
+use protocol::{
+    Transmitter, TransmitterMessage, Value, CHANNELS, PAYLOAD_SIZE,
+    auth::{ self, Authenticator },
+    bind::{ self, BindInfo, ModelId, RxBinder, RxBindState },
+    failsafe::{ self, Failsafe, LinkState },
+    hop::{ self, HopSequence, RxHopper },
+    quality::{ self, LinkQuality },
+    telemetry::{ Link, Telemetry },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InitStatus {
+    Ok,
+    RadioInitFailed,
+    RadioReceiveFailed
+}
+
+#[derive(Debug, Default)]
+pub struct Status {
+    /// 100Hz ticks since starting
+    pub counter: u32,
+    pub last_correlation_id: u32,
+    pub missed_messages: u32,
+    /// Messages which weren't from our transmitter, or were replayed
+    pub rejected_messages: u32,
+    pub interrupts: u32,
+    pub missed_interrupts: u32,
+}
+
+/// What to do after a tick
+#[derive(Debug, Default)]
+pub struct Tick {
+    /// Channel values for the flight controller
+    pub send: Option<(LinkState, [Value; CHANNELS])>,
+    /// Binding has finished, so store this in flash
+    pub bound: Option<BindInfo>,
+    /// The transmitter has started a new session, so store this in flash too
+    pub session: Option<u8>,
+}
+
+/// A model ID from the microcontroller's unique ID, so each receiver has its own
+pub fn model_id(unique_id: &[u8]) -> ModelId {
+    // FNV-1a, folded down to a byte
+    let hash = unique_id.iter().fold(0x811c_9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193));
+    hash.to_le_bytes().iter().fold(0, |id, byte| id ^ byte)
+}
+
+pub struct Receiver<R> {
+    radio: Option<R>,
+    init: InitStatus,
+    model_id: ModelId,
+    pub status: Status,
+    binder: RxBinder,
+    hop_config: hop::Config,
+    // Only once bound
+    hopper: Option<RxHopper>,
+    authenticator: Option<Authenticator>,
+    // The last session heard from the transmitter, and whether it's been stored
+    session: u8,
+    session_stored: bool,
+    failsafe: Failsafe,
+    // The channels as they were last received, since each message only
+    // carries some of the slow ones
+    values: [Value; CHANNELS],
+    link_quality: LinkQuality,
+    telemetry: Telemetry,
+}
+
+impl<R: Radio> Receiver<R> {
+    /// Start with the bind info from flash, if there is any, and the last
+    /// session heard from the transmitter. The radio should be receiving, on
+    /// whatever channel and address.
+    pub fn new(radio: Result<R, InitStatus>, stored: Option<BindInfo>, session: u8, model_id: ModelId,
+        hop_config: hop::Config, now: u32) -> Self {
+        let (radio, init) = match radio {
+            Ok(radio) => (Some(radio), InitStatus::Ok),
+            Err(init) => (None, init),
+        };
+
+        let mut receiver = Receiver {
+            radio,
+            init,
+            model_id,
+            status: Status::default(),
+            binder: RxBinder::listening(),
+            hop_config,
+            hopper: None,
+            authenticator: None,
+            session,
+            session_stored: true,
+            failsafe: Failsafe::new(failsafe::Config::default()),
+            values: failsafe::Config::default().positions,
+            link_quality: LinkQuality::new(),
+            telemetry: Telemetry::new(),
+        };
+
+        match stored {
+            Some(info) => receiver.bound(&info, now),
+            None => receiver.tune(bind::BIND_FREQUENCY, &bind::BIND_ADDRESS),
+        }
+        receiver
+    }
+
+    fn bound(&mut self, info: &BindInfo, now: u32) {
+        let hopper = RxHopper::new(HopSequence::new(info.hop_seed, &hop::BLACKLIST), self.hop_config, now);
+        self.tune(hopper.channel(), &info.address);
+        self.binder = RxBinder::bound(*info);
+        self.hopper = Some(hopper);
+        self.authenticator = Some(Authenticator::resume(info.key, self.session));
+    }
+
+    fn tune(&mut self, channel: u8, address: &[u8; 5]) {
+        if let Some(radio) = &mut self.radio {
+            let _ = radio.set_channel(channel);
+            let _ = radio.set_address(address);
+        }
+    }
+
+    pub fn init(&self) -> InitStatus {
+        self.init
+    }
+
+    pub fn radio(&self) -> Option<&R> {
+        self.radio.as_ref()
+    }
+
+    pub fn radio_mut(&mut self) -> Option<&mut R> {
+        self.radio.as_mut()
+    }
+
+    pub fn bind_state(&self) -> RxBindState {
+        self.binder.state()
+    }
+
+    pub fn hop_state(&self) -> Option<hop::HopState> {
+        self.hopper.as_ref().map(|hopper| hopper.state())
+    }
+
+    pub fn failsafe(&self) -> &Failsafe {
+        &self.failsafe
+    }
+
+    pub fn link_quality(&self) -> &LinkQuality {
+        &self.link_quality
+    }
+
+    pub fn telemetry(&self) -> &Telemetry {
+        &self.telemetry
+    }
+
+    /// For the flight controller's telemetry
+    pub fn telemetry_mut(&mut self) -> &mut Telemetry {
+        &mut self.telemetry
+    }
+
+    /// Read and act on every message the radio has
+    pub fn receive(&mut self, now: u32) {
+        let mut received = false;
+        loop {
+            let payload = match self.radio.as_mut().map(|radio| radio.read()) {
+                Some(Ok(payload)) => payload,
+                _ => break,
+            };
+            self.process(now, &payload);
+            received = true;
+        }
+
+        if let Some(radio) = &mut self.radio {
+            let _ = radio.clear_interrupts();
+        }
+
+        // The next acknowledgement carries the freshest telemetry, unless we're binding
+        if received {
+            let body = match self.binder.ack(self.model_id) {
+                Some(ack) => ack,
+                None => self.telemetry.next_message(),
+            };
+            let message = protocol::Receiver::new(self.status.last_correlation_id, body);
+            let mut buffer = [0; PAYLOAD_SIZE];
+            if let (Ok(payload), Some(radio)) = (message.encode(&mut buffer), &mut self.radio) {
+                let _ = radio.write_ack(payload);
+            }
+        }
+    }
+
+    fn process(&mut self, now: u32, payload: &Payload) {
+        // Once bound, only messages from our transmitter are accepted
+        let result = match &mut self.authenticator {
+            Some(authenticator) => authenticator.verify(payload),
+            None => Transmitter::decode(payload).map_err(|_| auth::Error::Decode),
+        };
+
+        match result {
+            Ok(Transmitter { version: protocol::VERSION, correlation_id, body }) => {
+                self.status.missed_messages += quality::missed(self.status.last_correlation_id, correlation_id);
+                self.status.last_correlation_id = correlation_id;
+                if self.authenticator.is_some() && auth::session(correlation_id) > self.session {
+                    self.session = auth::session(correlation_id);
+                    self.session_stored = false;
+                }
+                let counter = self.status.counter;
+                match body {
+                    TransmitterMessage::Bind(_) => self.binder.receive(counter, &body),
+                    // While binding, there's no way to know where they're from
+                    _ if self.authenticator.is_none() => {},
+                    _ => {
+                        match body {
+                            TransmitterMessage::ChannelValues(values) => {
+                                if values.unpack(&mut self.values).is_ok() {
+                                    self.failsafe.packet(counter, self.values);
+                                }
+                            },
+                            TransmitterMessage::Failsafe(packed) => {
+                                let mut positions = self.failsafe.config().positions;
+                                if packed.unpack(&mut positions).is_ok() {
+                                    self.failsafe.set_positions(positions);
+                                }
+                            },
+                            TransmitterMessage::Bind(_) => {},
+                        }
+
+                        // The next message will be on the next channel
+                        if let (Some(hopper), Some(radio)) = (&mut self.hopper, &mut self.radio) {
+                            let _ = radio.set_channel(hopper.packet(now, correlation_id));
+                        }
+                    },
+                }
+            },
+
+            // A transmitter running incompatible firmware
+            Ok(_) => {},
+
+            Err(_) => self.status.rejected_messages += 1,
+        }
+    }
+
+    /// Follow the transmitter from channel to channel, or scan for it. Call
+    /// this several times a hop period.
+    pub fn hop(&mut self, now: u32) {
+        if let (Some(hopper), Some(radio)) = (&mut self.hopper, &mut self.radio) {
+            if let Some(channel) = hopper.update(now) {
+                let _ = radio.set_channel(channel);
+            }
+        }
+    }
+
+    /// Call this at 100Hz. `supply` measures the supply voltage in mV, when
+    /// it's needed for telemetry.
+    pub fn tick<F: FnOnce() -> u16>(&mut self, now: u32, supply: F) -> Tick {
+        let mut tick = Tick::default();
+        let counter = self.status.counter;
+
+        if counter.is_multiple_of(100) {
+            self.link_quality.update(self.status.last_correlation_id, self.status.missed_messages);
+            self.telemetry.link = Link {
+                quality: self.link_quality.quality(),
+                rssi: self.link_quality.rssi(),
+                voltage: supply(),
+                model_id: self.model_id,
+            };
+        }
+
+        if counter.is_multiple_of(10) {
+            let state = self.failsafe.update(counter);
+            tick.send = Some((state, self.failsafe.values()));
+        }
+
+        if let Some(info) = self.binder.update(counter) {
+            // A new key, so the transmitter starts counting sessions again
+            self.session = 0;
+            self.session_stored = false;
+            self.bound(&info, now);
+            tick.bound = Some(info);
+        }
+
+        if !self.session_stored {
+            self.session_stored = true;
+            tick.session = Some(self.session);
+        }
+
+        self.status.counter = counter.wrapping_add(1);
+        tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::collections::VecDeque;
+
+    use super::*;
+    use protocol::{ channels::{ self, Packed }, telemetry::Battery, ReceiverMessage };
+
+    const HOP_CONFIG: hop::Config = hop::Config { period: 100, lost: 10 };
+
+    #[derive(Default)]
+    struct FakeRadio {
+        received: VecDeque<Payload>,
+        ack: Option<Payload>,
+        channel: u8,
+        address: [u8; 5],
+    }
+
+    impl Radio for FakeRadio {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<Payload, ()> {
+            self.received.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+
+        fn write_ack(&mut self, payload: &[u8]) -> Result<(), ()> {
+            let mut ack = [0; PAYLOAD_SIZE];
+            ack[..payload.len()].copy_from_slice(payload);
+            self.ack = Some(ack);
+            Ok(())
+        }
+
+        fn set_channel(&mut self, channel: u8) -> Result<(), ()> {
+            self.channel = channel;
+            Ok(())
+        }
+
+        fn set_address(&mut self, address: &[u8; 5]) -> Result<(), ()> {
+            self.address = *address;
+            Ok(())
+        }
+    }
+
+    fn info() -> BindInfo {
+        BindInfo::generate([1, 2, 3, 4])
+    }
+
+    const MODEL_ID: ModelId = 42;
+
+    // Values which come through the packing unchanged
+    fn values() -> [Value; CHANNELS] {
+        core::array::from_fn(|channel| channels::quantize(channel as Value * 0x1000))
+    }
+
+    // The fast channels, and one group of the slow ones
+    fn packed(group: usize) -> Packed {
+        Packed::new(&values(), group)
+    }
+
+    fn bound() -> Receiver<FakeRadio> {
+        Receiver::new(Ok(FakeRadio::default()), Some(info()), 0, MODEL_ID, HOP_CONFIG, 0)
+    }
+
+    fn send(receiver: &mut Receiver<FakeRadio>, authenticator: Option<&Authenticator>, correlation_id: u32, body: TransmitterMessage) {
+        let message = Transmitter::new(correlation_id, body);
+        let mut buffer = [0; PAYLOAD_SIZE];
+        match authenticator {
+            Some(authenticator) => authenticator.sign(&message, &mut buffer).unwrap(),
+            None => message.encode(&mut buffer).unwrap(),
+        };
+        receiver.radio_mut().unwrap().received.push_back(buffer);
+        receiver.receive(correlation_id.wrapping_mul(HOP_CONFIG.period));
+    }
+
+    fn ack(receiver: &Receiver<FakeRadio>) -> ReceiverMessage {
+        protocol::Receiver::decode(&receiver.radio().unwrap().ack.unwrap()).unwrap().body
+    }
+
+    #[test]
+    fn listens_for_bind() {
+        let mut receiver = Receiver::new(Ok(FakeRadio::default()), None, 0, MODEL_ID, HOP_CONFIG, 0);
+        assert_eq!(receiver.radio().unwrap().address, bind::BIND_ADDRESS);
+        assert_eq!(receiver.radio().unwrap().channel, bind::BIND_FREQUENCY);
+
+        send(&mut receiver, None, 1, TransmitterMessage::Bind(info()));
+        assert_eq!(ack(&receiver), ReceiverMessage::Bound(info(), MODEL_ID));
+
+        // Bound once the transmitter stops binding
+        let (mut bound, mut session) = (None, None);
+        for _ in 0..=RxBinder::LINGER {
+            let tick = receiver.tick(0, || 5000);
+            bound = bound.or(tick.bound);
+            session = session.or(tick.session);
+        }
+        assert_eq!(bound, Some(info()));
+        // The transmitter counts its sessions from zero again with the new key
+        assert_eq!(session, Some(0));
+        assert_eq!(receiver.radio().unwrap().address, info().address);
+        assert_eq!(receiver.hop_state(), Some(hop::HopState::Scanning));
+    }
+
+    #[test]
+    fn follows_the_transmitter() {
+        let mut receiver = bound();
+        let authenticator = Authenticator::new(info().key);
+        let hops = HopSequence::new(info().hop_seed, &hop::BLACKLIST);
+
+        send(&mut receiver, Some(&authenticator), 1, TransmitterMessage::ChannelValues(packed(0)));
+        assert_eq!(receiver.hop_state(), Some(hop::HopState::Synced));
+        assert_eq!(receiver.radio().unwrap().channel, hops.channel(2));
+        let unsent = channels::FAST + channels::GROUP;
+        assert_eq!(receiver.failsafe().values()[..unsent], values()[..unsent]);
+        assert!(receiver.failsafe().values()[unsent..].iter().all(|value| *value == 0x8000));
+
+        // The rest of the slow channels come in the next message
+        send(&mut receiver, Some(&authenticator), 2, TransmitterMessage::ChannelValues(packed(1)));
+        assert_eq!(receiver.failsafe().values(), values());
+
+        let tick = receiver.tick(100, || 5000);
+        assert_eq!(tick.send, Some((LinkState::Live, values())));
+    }
+
+    #[test]
+    fn rejects_unauthenticated_messages() {
+        let mut receiver = bound();
+        let mut key = info().key;
+        key[0] ^= 1;
+        let other = Authenticator::new(key);
+
+        send(&mut receiver, Some(&other), 1, TransmitterMessage::ChannelValues(packed(0)));
+        send(&mut receiver, None, 2, TransmitterMessage::ChannelValues(packed(0)));
+        assert_eq!(receiver.status.rejected_messages, 2);
+        assert_eq!(receiver.failsafe().state(), LinkState::FailSafe);
+    }
+
+    #[test]
+    fn rejects_replays_after_restarting() {
+        let mut receiver = bound();
+        let authenticator = Authenticator::new(info().key);
+        send(&mut receiver, Some(&authenticator), auth::session_start(3) + 1, TransmitterMessage::ChannelValues(packed(0)));
+        send(&mut receiver, Some(&authenticator), auth::session_start(4) + 1, TransmitterMessage::ChannelValues(packed(0)));
+        assert_eq!(receiver.tick(0, || 5000).session, Some(4));
+        assert_eq!(receiver.tick(0, || 5000).session, None);
+
+        // Switched off and on again, with the session from flash, the
+        // recording of the earlier session is a replay
+        let mut receiver = Receiver::new(Ok(FakeRadio::default()), Some(info()), 4, MODEL_ID, HOP_CONFIG, 0);
+        send(&mut receiver, Some(&authenticator), auth::session_start(3) + 1, TransmitterMessage::ChannelValues(packed(0)));
+        assert_eq!(receiver.status.rejected_messages, 1);
+        assert_eq!(receiver.failsafe().state(), LinkState::FailSafe);
+
+        send(&mut receiver, Some(&authenticator), auth::session_start(4) + 2, TransmitterMessage::ChannelValues(packed(0)));
+        assert_eq!(receiver.status.rejected_messages, 1);
+        assert_eq!(receiver.tick(0, || 5000).session, None);
+    }
+
+    #[test]
+    fn sends_telemetry() {
+        let mut receiver = bound();
+        let authenticator = Authenticator::new(info().key);
+        receiver.telemetry_mut().battery = Some(Battery { voltage: 111, ..Default::default() });
+        receiver.tick(0, || 4800);
+
+        let mut battery = false;
+        let mut link = false;
+        for id in 1..10 {
+            send(&mut receiver, Some(&authenticator), id, TransmitterMessage::ChannelValues(Packed::new(&[0; CHANNELS], 0)));
+            match ack(&receiver) {
+                ReceiverMessage::Battery(telemetry) => battery = telemetry.voltage == 111,
+                ReceiverMessage::Link(telemetry) => link = telemetry.voltage == 4800 && telemetry.model_id == MODEL_ID,
+                _ => {},
+            }
+        }
+        assert!(battery && link);
+    }
+
+    #[test]
+    fn failsafe_positions_from_transmitter() {
+        let mut receiver = bound();
+        let authenticator = Authenticator::new(info().key);
+        send(&mut receiver, Some(&authenticator), 1, TransmitterMessage::Failsafe(packed(0)));
+        send(&mut receiver, Some(&authenticator), 2, TransmitterMessage::Failsafe(packed(1)));
+        assert_eq!(receiver.hop_state(), Some(hop::HopState::Synced));
+
+        // They're not channel values, so the receiver stays in failsafe
+        let tick = receiver.tick(0, || 0);
+        assert_eq!(tick.send, Some((LinkState::FailSafe, values())));
+    }
+
+    #[test]
+    fn model_ids_differ() {
+        assert_ne!(model_id(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]), model_id(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 13]));
+    }
+
+    #[test]
+    fn failsafe_without_messages() {
+        let mut receiver = bound();
+        let authenticator = Authenticator::new(info().key);
+        send(&mut receiver, Some(&authenticator), 1, TransmitterMessage::ChannelValues(packed(0)));
+
+        let mut last = None;
+        for _ in 0..200 {
+            last = receiver.tick(0, || 0).send.or(last);
+        }
+        assert_eq!(last, Some((LinkState::FailSafe, failsafe::Config::default().positions)));
+    }
+
+    #[test]
+    fn without_a_radio() {
+        let mut receiver: Receiver<FakeRadio> = Receiver::new(Err(InitStatus::RadioInitFailed), None, 0, MODEL_ID, HOP_CONFIG, 0);
+        receiver.receive(0);
+        receiver.hop(0);
+        assert_eq!(receiver.init(), InitStatus::RadioInitFailed);
+        assert_eq!(receiver.tick(0, || 0).send.unwrap().0, LinkState::FailSafe);
+    }
+}
@@ -0,0 +1,164 @@
+// The protocol the flight controller is sent is a setting, rather than built
+// in, so the same firmware suits any flight controller. It's chosen from the
+// console, with a line like `output sbus`, and kept in the store.
+
+use core::str::FromStr;
+
+use embedded_hal::serial::Write;
+use heapless::Vec;
+use serde::{ Deserialize, Serialize };
+
+use rc_output::{ Link, RcOutput };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Protocol {
+    #[default]
+    Sumd,
+    Sbus,
+    Crsf,
+    Ibus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// 8 data bits, no parity, 1 stop bit
+    EightN1,
+    /// 8 data bits, even parity, 2 stop bits
+    EightE2,
+}
+
+/// How to set up the UART
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Serial {
+    pub baudrate: u32,
+    pub framing: Framing,
+    /// The signal is inverted, which needs an inverter outside the MCU
+    pub inverted: bool,
+}
+
+impl Protocol {
+    pub fn serial(self) -> Serial {
+        match self {
+            Protocol::Sumd | Protocol::Ibus => Serial { baudrate: 115_200, framing: Framing::EightN1, inverted: false },
+            Protocol::Sbus => Serial { baudrate: 100_000, framing: Framing::EightE2, inverted: true },
+            Protocol::Crsf => Serial { baudrate: 420_000, framing: Framing::EightN1, inverted: false },
+        }
+    }
+
+    /// Whether the flight controller sends telemetry back
+    pub fn has_telemetry(self) -> bool {
+        self == Protocol::Crsf
+    }
+}
+
+impl RcOutput for Protocol {
+    fn send<W: Write<u8>>(&self, out: &mut W, link: &Link, values: &[u16]) -> nb::Result<(), W::Error> {
+        match self {
+            Protocol::Sumd => sumd::Encoder.send(out, link, values),
+            Protocol::Sbus => sbus::Encoder.send(out, link, values),
+            Protocol::Crsf => crsf::Encoder.send(out, link, values),
+            Protocol::Ibus => ibus::Encoder.send(out, link, values),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unknown;
+
+impl FromStr for Protocol {
+    type Err = Unknown;
+
+    fn from_str(name: &str) -> Result<Self, Unknown> {
+        match name {
+            "sumd" => Ok(Protocol::Sumd),
+            "sbus" => Ok(Protocol::Sbus),
+            "crsf" => Ok(Protocol::Crsf),
+            "ibus" => Ok(Protocol::Ibus),
+            _ => Err(Unknown),
+        }
+    }
+}
+
+const MAX_LINE: usize = 32;
+
+/// Collects lines from the console, a byte at a time, for the protocol they
+/// ask for
+#[derive(Debug, Default)]
+pub struct Console {
+    line: Vec<u8, MAX_LINE>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The protocol, once a whole line asking for one has arrived. Lines
+    /// which are too long are ignored.
+    pub fn receive(&mut self, byte: u8) -> Option<Result<Protocol, Unknown>> {
+        if byte != b'\r' && byte != b'\n' {
+            if self.line.push(byte).is_err() {
+                self.line.clear();
+            }
+            return None;
+        }
+
+        let line = core::str::from_utf8(&self.line).ok().map(str::trim);
+        let result = match line.and_then(|line| line.strip_prefix("output ")) {
+            Some(name) => Some(name.trim().parse()),
+            None if line.is_some_and(|line| !line.is_empty()) => Some(Err(Unknown)),
+            None => None,
+        };
+        self.line.clear();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rc_output::{ FrameBuffer, LinkState };
+
+    fn lines(console: &mut Console, bytes: &[u8]) -> Option<Result<Protocol, Unknown>> {
+        bytes.iter().filter_map(|byte| console.receive(*byte)).last()
+    }
+
+    #[test]
+    fn every_protocol_sends() {
+        let link = Link { state: LinkState::Live, quality: 100, rssi: 50 };
+        let sizes = [(Protocol::Sumd, 3 + 2 * 16 + 2), (Protocol::Sbus, sbus::FRAME_SIZE), (Protocol::Crsf, 26 + 14), (Protocol::Ibus, ibus::FRAME_SIZE)];
+        for (protocol, size) in sizes {
+            let mut buffer = FrameBuffer::new();
+            buffer.encode(&protocol, &link, &[0x8000; protocol::CHANNELS]);
+            assert_eq!(buffer.0.len(), size, "{:?}", protocol);
+        }
+    }
+
+    #[test]
+    fn choosing_from_the_console() {
+        let mut console = Console::new();
+        assert_eq!(lines(&mut console, b"output sbus\r\n"), Some(Ok(Protocol::Sbus)));
+        assert_eq!(lines(&mut console, b"\r\n  output   ibus \n"), Some(Ok(Protocol::Ibus)));
+        assert_eq!(lines(&mut console, b"output ppm\n"), Some(Err(Unknown)));
+        assert_eq!(lines(&mut console, b"hello\n"), Some(Err(Unknown)));
+        assert_eq!(lines(&mut console, b"output crsf"), None);
+        assert_eq!(console.receive(b'\r'), Some(Ok(Protocol::Crsf)));
+    }
+
+    #[test]
+    fn long_lines_are_dropped() {
+        let mut console = Console::new();
+        let mut long = [b'x'; MAX_LINE + 1].to_vec();
+        long.extend_from_slice(b"output sumd\n");
+        assert_eq!(lines(&mut console, &long), Some(Ok(Protocol::Sumd)));
+    }
+
+    #[test]
+    fn round_trips_through_the_store() {
+        let mut buffer = [0; 8];
+        for protocol in [Protocol::Sumd, Protocol::Sbus, Protocol::Crsf, Protocol::Ibus] {
+            let bytes = postcard::to_slice(&protocol, &mut buffer).unwrap();
+            assert_eq!(postcard::from_bytes::<Protocol>(bytes).unwrap(), protocol);
+        }
+    }
+}
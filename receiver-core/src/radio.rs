@@ -0,0 +1,25 @@
+// What the receiver needs from its radio. The firmware implements it for the
+// nRF24L01, so this builds and tests on the host.
+
+use protocol::PAYLOAD_SIZE;
+
+pub type Payload = [u8; PAYLOAD_SIZE];
+
+pub trait Radio {
+    type Error;
+
+    /// Acknowledge the radio's interrupt
+    fn clear_interrupts(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// The next payload received, or `WouldBlock` if there isn't one
+    fn read(&mut self) -> nb::Result<Payload, Self::Error>;
+
+    /// The payload for the next acknowledgement, replacing any which hasn't been sent
+    fn write_ack(&mut self, payload: &[u8]) -> Result<(), Self::Error>;
+
+    fn set_channel(&mut self, channel: u8) -> Result<(), Self::Error>;
+
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), Self::Error>;
+}
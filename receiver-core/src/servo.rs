@@ -0,0 +1,160 @@
+// Servo outputs, for models without a flight controller. Each output follows
+// a channel, with the pulse widths SUMD gives it: 1100µS at one end, 1500µS in
+// the middle, and 1900µS at the other. Analog servos want a pulse every 20mS,
+// at 50Hz, and digital ones every 3mS, at 333Hz.
+//
+// The outputs on a timer share its period, so the timer runs at the fastest
+// rate any of them wants, and an analog output on a digital timer only pulses
+// every sixth period, which is close enough to 50Hz. The firmware asks for the
+// next duty cycles at the end of each period.
+
+use serde::{ Deserialize, Serialize };
+
+use protocol::Value;
+
+// SUMD's values are in eighths of a µS
+const UNITS_PER_US: u32 = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Rate {
+    /// 50Hz
+    #[default]
+    Analog,
+    /// 333Hz
+    Digital,
+}
+
+impl Rate {
+    pub fn frequency(self) -> u32 {
+        match self {
+            Rate::Analog => 50,
+            Rate::Digital => 333,
+        }
+    }
+
+    /// In µS
+    pub fn period(self) -> u32 {
+        1_000_000 / self.frequency()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Output {
+    pub channel: u8,
+    pub rate: Rate,
+}
+
+impl Output {
+    /// An analog servo on `channel`
+    pub const fn new(channel: u8) -> Self {
+        Output { channel, rate: Rate::Analog }
+    }
+}
+
+/// The pulse width for a channel value, in SUMD's units of an eighth of a µS
+pub fn pulse(value: Value) -> u32 {
+    let (low, high) = (sumd::LOW as u32, sumd::HIGH as u32);
+    low + (value as u32 * (high - low) + Value::MAX as u32 / 2) / Value::MAX as u32
+}
+
+/// The outputs on one timer
+pub struct Servos<const N: usize> {
+    outputs: [Output; N],
+    rate: Rate,
+    // Periods of the timer so far
+    period: u32,
+}
+
+impl<const N: usize> Servos<N> {
+    pub fn new(outputs: [Output; N]) -> Self {
+        let rate = if outputs.iter().any(|output| output.rate == Rate::Digital) { Rate::Digital } else { Rate::Analog };
+        Servos { outputs, rate, period: 0 }
+    }
+
+    /// The rate to run the timer at
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    /// The duty cycle of each output for the timer's next period, where `max`
+    /// is the whole period. An output for a channel there's no value for
+    /// doesn't pulse at all.
+    pub fn next(&mut self, values: &[Value], max: u16) -> [u16; N] {
+        let divider = self.rate.frequency() / Rate::Analog.frequency();
+        let analog = self.period.is_multiple_of(divider);
+        self.period = self.period.wrapping_add(1);
+
+        let whole = self.rate.period() * UNITS_PER_US;
+        let mut duties = [0; N];
+        for (duty, output) in duties.iter_mut().zip(self.outputs.iter()) {
+            if output.rate == Rate::Analog && !analog {
+                continue;
+            }
+            if let Some(value) = values.get(output.channel as usize) {
+                *duty = (pulse(*value) * max as u32 / whole) as u16;
+            }
+        }
+        duties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{ failsafe::{ Config, Failsafe }, CHANNELS };
+
+    #[test]
+    fn reference_pulses() {
+        assert_eq!(pulse(0), sumd::LOW as u32);
+        assert_eq!(pulse(0x8000), sumd::NEUTRAL as u32);
+        assert_eq!(pulse(0xffff), sumd::HIGH as u32);
+        assert_eq!(pulse(0x4000) / UNITS_PER_US, 1300);
+
+        let mut last = 0;
+        for value in 0..=Value::MAX {
+            assert!(pulse(value) >= last);
+            last = pulse(value);
+        }
+    }
+
+    #[test]
+    fn duty_cycles() {
+        let values = [0, 0x8000, 0xffff];
+        // A timer counting µS
+        let mut analog = Servos::new([Output::new(0), Output::new(1), Output::new(2), Output::new(3)]);
+        assert_eq!(analog.rate(), Rate::Analog);
+        assert_eq!(analog.next(&values, 20_000), [1100, 1500, 1900, 0]);
+
+        let mut digital = Servos::new([Output { channel: 2, rate: Rate::Digital }]);
+        assert_eq!(digital.rate(), Rate::Digital);
+        assert_eq!(digital.next(&values, 3003), [1900]);
+    }
+
+    #[test]
+    fn analog_outputs_pulse_at_50hz_on_a_digital_timer() {
+        let mut servos = Servos::new([Output::new(0), Output { channel: 1, rate: Rate::Digital }]);
+        assert_eq!(servos.rate(), Rate::Digital);
+        let values = [0x8000; CHANNELS];
+        let mut pulses = [0; 2];
+        for _ in 0..333 {
+            for (pulses, duty) in pulses.iter_mut().zip(servos.next(&values, u16::MAX).iter()) {
+                *pulses += (*duty > 0) as u32;
+            }
+        }
+        assert_eq!(pulses, [56, 333]);
+    }
+
+    #[test]
+    fn failsafe_positions() {
+        let mut positions = [0x8000; CHANNELS];
+        positions[0] = 0;
+        let mut failsafe = Failsafe::new(Config { positions, ..Config::default() });
+        let mut servos = Servos::new([Output::new(0), Output::new(1)]);
+
+        failsafe.packet(0, [0xffff; CHANNELS]);
+        failsafe.update(1);
+        assert_eq!(servos.next(&failsafe.values(), 20_000), [1900, 1900]);
+        failsafe.update(1000);
+        assert_eq!(servos.next(&failsafe.values(), 20_000), [1100, 1500]);
+    }
+}
@@ -1,6 +1,7 @@
 /* Linker script for the STM32F030F4P6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 16K
+  /* The last 2K holds the settings store */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 14K
   RAM : ORIGIN = 0x20000000, LENGTH = 4K
 }
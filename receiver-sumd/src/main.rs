@@ -6,7 +6,7 @@
 extern crate panic_semihosting;
 extern crate nb;
 
-use cortex_m::{ singleton };
+use cortex_m::{ singleton, peripheral::SCB };
 
 use core::{
     default::Default,
@@ -16,7 +16,7 @@ use core::{
 };
 
 use embedded_nrf24l01::{
-    NRF24L01, RxMode, Payload, Configuration, DataRate, CrcMode
+    NRF24L01, Configuration, DataRate, CrcMode
 };
 
 use stm32f0xx_hal::{
@@ -25,10 +25,11 @@ use stm32f0xx_hal::{
     gpio::{ AF5, Alternate,  Input, Output, PullUp, PushPull },
     gpio::{ 
         gpioa::{ 
+            // PA0: bind button, held down at power on
             PA5,  // SCLK
             PA6,  // MISO
             PA7,  // MOSI
-            // PA9,  // TX1: SUMD to flight controller
+            // PA9,  // TX1: SUMD, or the configured protocol, to flight controller
             // PA10, // RX1: telemetry from flight controller
          },
         gpiob::{ 
@@ -59,6 +60,15 @@ use usb_device::{
 
 use usbd_serial;
 
+use protocol::{ 
+    hop,
+    bind::BindInfo,
+    failsafe::LinkState,
+};
+use rc_output::{ Driver, Link };
+use receiver_core::{ model_id, output::{ Console, Framing, Protocol }, InitStatus, Receiver };
+use storage::Store;
+
 type RadioCe = PB0<Output<PushPull>>;
 type RadioCsn = PB1<Output<PushPull>>;
 type RadioIrq = PB10<Input<PullUp>>;
@@ -83,24 +93,27 @@ impl fmt::Write for ConsoleSerial {
     }
 }
 
-#[derive(Debug)]
-pub enum InitStatus {
-    Ok,
-    RadioInitFailed,
-    RadioReceiveFailed
-}
+mod radio;
+mod settings;
 
-pub struct Status {
-    init: InitStatus,
-    counter: u32,
-    last_correlation_id: u32,
-    missed_messages: u32,
-    sumd: Option<Payload>,
-}
+use radio::Nrf24;
+use settings::Flash;
 
-fn sumd_serial_config() -> serial::config::Config {
+// The Cortex-M0 has no cycle counter, so the timer runs four times a message
+// period, and its ticks are the clock for hopping
+const HOPS_PER_TICK: u32 = 4;
+const HOP_CONFIG: hop::Config = hop::Config { period: HOPS_PER_TICK, lost: 10 };
+
+// SBUS is inverted, which needs an inverter outside the MCU
+fn serial_config(output: Protocol) -> serial::config::Config {
+    let serial = output.serial();
     let default : serial::config::Config = Default::default();
-    default.baudrate(115200.bps())
+    let config = default.baudrate(serial.baudrate.bps());
+    match serial.framing {
+        Framing::EightN1 => config,
+        // The parity bit counts as a data bit, so the word length is 9
+        Framing::EightE2 => config.wordlength_9().parity_even().stopbits(serial::config::StopBits::STOP2),
+    }
 }
 
 // type FlightControllerSerial = Serial<USART1, (PA9<Alternate<AF7>>, PA10<Alternate<AF7>>)>;
@@ -109,13 +122,16 @@ fn sumd_serial_config() -> serial::config::Config {
 const APP: () = {
 
     struct Resources {
-        radio: Option<RxMode<Radio>>,
-        status: Status,
+        receiver: Receiver<Nrf24<Radio>>,
+        // Ticks of the timer
+        hops: u32,
         usb_dev: UsbDevice<'static, UsbBusType>,
         usb_serial: ConsoleSerial,
+        console: Console,
         timer: Timer<TIM1>,
         led: PC13<Output<PushPull>>,
-        flight_controller: Tx<USART1>,
+        flight_controller: Driver<Tx<USART1>, Protocol>,
+        store: Store<Flash>,
     }
 
     #[init]
@@ -144,6 +160,15 @@ const APP: () = {
         let mut led =  gpioc.pc13.into_push_pull_output();
         led.set_low().unwrap();
 
+        // Bind if the button is held down, or there's no transmitter to listen to
+        let bind_button = gpioa.pa0.into_pull_up_input();
+        let mut store = settings::open(peripherals.FLASH);
+        let stored = match store.load(settings::BIND) {
+            Some(info) if bind_button.is_high().unwrap() => Some(info),
+            _ => None,
+        };
+        let session = store.load_or_default(settings::SESSION);
+
         
         let usb = USB {
             usb_global: peripherals.OTG_FS_GLOBAL,
@@ -182,144 +207,171 @@ const APP: () = {
             clocks,
         );
 
-        let (radio, status) = match NRF24L01::new(ce, csn, spi) {
+        // The receiver sets the frequency and address
+        let radio = match NRF24L01::new(ce, csn, spi) {
             Ok(mut radio) => {
-                radio.set_frequency(protocol::FREQUENCY).unwrap();
-
                 radio.set_rf(DataRate::R250Kbps, 0).unwrap();
                 radio.set_crc(Some(CrcMode::TwoBytes)).unwrap();
                 radio.set_auto_ack(&[ true; 6 ]).unwrap();
+                radio.set_ack_payloads(true).unwrap();
                 radio.set_auto_retransmit(0b0100, 15).unwrap();
 
-                radio.set_rx_addr(0, &protocol::RX_ADDRESS).unwrap();
                 radio.set_pipes_rx_lengths(&[ None; 6]).unwrap();
                 radio.set_pipes_rx_enable(&[true, false, false, false, false, false]).unwrap();
                 radio.flush_tx().unwrap();
                 radio.flush_rx().unwrap();
 
-                match radio.rx() {
-                    Ok(rx) => {
-                        (Some(rx), InitStatus::Ok)
-                    },
-                    Err(_) => {
-                        (None, InitStatus::RadioReceiveFailed)
-                    }
-                }
+                radio.rx().map(Nrf24).map_err(|_| InitStatus::RadioReceiveFailed)
             },
-            Err(_) => {
-                (None, InitStatus::RadioInitFailed)
-            }
+            Err(_) => Err(InitStatus::RadioInitFailed),
         };
         
         
 
+        let output = store.load_or_default(settings::OUTPUT);
         let flight_controller = Serial::usart1(
             peripherals.USART1, 
             (gpioa.pa9.into_alternate_af7(), gpioa.pa10.into_alternate_af7()),
-            sumd_serial_config(),
+            serial_config(output),
             clocks);
 
         let (flight_controller_tx, _) = flight_controller.split();
 
         // Configure the syst timer to trigger an update every second and enables interrupt
-        let mut timer = Timer::tim1(peripherals.TIM1, 100.hz(), clocks);
+        let mut timer = Timer::tim1(peripherals.TIM1, (100 * HOPS_PER_TICK).hz(), clocks);
         timer.listen(Event::TimeOut);
 
         init::LateResources {
-            radio,
-            status: Status {
-                init: status,
-                sumd: None,
-                counter: 0,
-                last_correlation_id: 0,
-                missed_messages: 0,
-            },
+            receiver: Receiver::new(radio, stored, session, model_id(&unique_id()), HOP_CONFIG, 0),
+            hops: 0,
             usb_dev,
             usb_serial,
+            console: Console::new(),
             timer,
             led,
-            flight_controller: flight_controller_tx,
+            flight_controller: Driver::new(flight_controller_tx, output),
+            store,
  		}
     }
 
-    #[task(resources = [radio, status], spawn=[process])]
+    #[task(resources = [receiver, hops])]
     fn receive(c: receive::Context) {
-        let mut rx = c.resources.radio.take().unwrap();
-        rx.clear_interrupts().unwrap();
-        while match rx.can_read() {
-            Ok(Some(_)) => {
-                match c.spawn.process(rx.read().unwrap()) {
-                    Ok(_) => true,
-                    Err(_) => false
-                }
-            },
-
-            Ok(None) => false,
-            Err(_) => false,
-        } {}
-        *c.resources.radio = Some(rx);
-    }
-
-    #[task(resources=[status])]
-    fn process(c: process::Context, payload: Payload) {
-        c.resources.status.sumd = Some(payload);
+        c.resources.receiver.receive(*c.resources.hops);
     }
 
-    #[task(resources = [status, usb_serial])]
+    #[task(resources = [receiver, usb_serial])]
     fn log_status(c: log_status::Context, can_read: bool, is_full: bool) {
+        let receiver = c.resources.receiver;
         let _ = writeln!(c.resources.usb_serial, 
-            "Tick; init: {:?} last(missed): {}({}), can_read: {}, is_full: {}",
-            c.resources.status.init,
-            c.resources.status.last_correlation_id,
-            c.resources.status.missed_messages,
+            "Tick; init: {:?} last(missed): {}({}), rejected: {}, bind: {:?}, hop: {:?}, can_read: {}, is_full: {}",
+            receiver.init(),
+            receiver.status.last_correlation_id,
+            receiver.status.missed_messages,
+            receiver.status.rejected_messages,
+            receiver.bind_state(),
+            receiver.hop_state(),
             can_read, 
             is_full);
     }
 
-    #[task(binds = TIM1, priority = 1, resources = [ status, timer, led, radio ], 
-        spawn = [ log_status, receive, send_to_flight_controller ])]
+    #[task(binds = TIM1, priority = 1, resources = [ receiver, hops, timer, led ], 
+        spawn = [ log_status, receive, send_to_flight_controller, bound, session ])]
     fn tick(c: tick::Context) {
         c.resources.timer.clear_interrupt(Event::TimeOut);
 
-        let mut rx = c.resources.radio.take().unwrap();
-        let can_read = rx.can_read().unwrap().is_some();
+        *c.resources.hops = c.resources.hops.wrapping_add(1);
+        let now = *c.resources.hops;
+        c.resources.receiver.hop(now);
+        if now % HOPS_PER_TICK != 0 {
+            return;
+        }
+
+        let (can_read, is_full) = match c.resources.receiver.radio_mut() {
+            Some(Nrf24(rx)) => (rx.can_read().unwrap().is_some(), rx.is_full().unwrap()),
+            None => (false, false),
+        };
         if can_read {
-            c.spawn.receive().unwrap();
+            let _ = c.spawn.receive();
         }
 
-        if c.resources.status.counter % 10 == 0 {
-            c.spawn.send_to_flight_controller().unwrap();
+        // No way to measure the supply
+        let counter = c.resources.receiver.status.counter;
+        let tick = c.resources.receiver.tick(now, || 0);
+        if let Some((state, values)) = tick.send {
+            c.spawn.send_to_flight_controller(state, values).unwrap();
+        }
+        if let Some(info) = tick.bound {
+            let _ = c.spawn.bound(info);
+        }
+        if let Some(session) = tick.session {
+            let _ = c.spawn.session(session);
         }
 
-        if c.resources.status.counter % 500 == 0 {
+        if counter % 500 == 0 {
             c.resources.led.toggle().unwrap();
         }
 
-        if c.resources.status.counter % 1000 == 0 {
-            c.spawn.log_status(can_read, rx.is_full().unwrap()).unwrap();
+        if counter % 1000 == 0 {
+            c.spawn.log_status(can_read, is_full).unwrap();
         }
+    }
 
+    // Binding is complete, so remember the transmitter
+    #[task(resources = [store])]
+    fn bound(c: bound::Context, info: BindInfo) {
+        let _ = c.resources.store.save(settings::BIND, &info);
+    }
 
-        c.resources.status.counter += 1;
+    // The transmitter has started a new session, which is once each time it's switched on
+    #[task(resources = [store])]
+    fn session(c: session::Context, session: u8) {
+        let _ = c.resources.store.save(settings::SESSION, &session);
+    }
 
-        *c.resources.radio = Some(rx);
+    // As much of the frame as the UART will take, and the rest from its
+    // transmit interrupt, so nothing waits on the UART
+    #[task(resources = [flight_controller, receiver])]
+    fn send_to_flight_controller(c: send_to_flight_controller::Context,
+        state: LinkState, values: [protocol::Value; protocol::CHANNELS]) {
+        let link = Link::new(state, c.resources.receiver.link_quality());
+        let flight_controller = c.resources.flight_controller;
+        match flight_controller.send(link, &values) {
+            Err(nb::Error::WouldBlock) => flight_controller.writer().listen(),
+            Ok(_) | Err(_) => flight_controller.writer().unlisten(),
+        }
     }
 
-    #[task(resources = [flight_controller, status])]
-    fn send_to_flight_controller(c: send_to_flight_controller::Context) {
-        match c.resources.status.sumd {
-            Some(payload) => {
-                for byte in payload.as_ref() {
-                    c.resources.flight_controller.write(*byte).unwrap();
-                }
-            },
-            None => {}
+    #[task(binds = USART1, resources = [flight_controller])]
+    fn usart1(c: usart1::Context) {
+        let flight_controller = c.resources.flight_controller;
+        match flight_controller.poll() {
+            Err(nb::Error::WouldBlock) => {},
+            Ok(_) | Err(_) => flight_controller.writer().unlisten(),
         }
     }
-    
-    #[task(binds = OTG_FS, resources = [usb_dev, usb_serial])]
+
+    // The console takes `output sumd`, `output sbus`, `output crsf` or `output
+    // ibus`, which is saved, and the receiver restarts to set the UART up for it
+    #[task(binds = OTG_FS, resources = [usb_dev, usb_serial, console, store])]
     fn otg_fs(c: otg_fs::Context) {
-        c.resources.usb_dev.poll(&mut [&mut c.resources.usb_serial.0]);
+        if !c.resources.usb_dev.poll(&mut [&mut c.resources.usb_serial.0]) {
+            return;
+        }
+
+        let mut buffer = [0u8; 64];
+        let count = c.resources.usb_serial.0.read(&mut buffer).unwrap_or(0);
+        for byte in &buffer[..count] {
+            match c.resources.console.receive(*byte) {
+                Some(Ok(output)) => {
+                    let _ = c.resources.store.save(settings::OUTPUT, &output);
+                    SCB::sys_reset();
+                },
+                Some(Err(_)) => {
+                    let _ = writeln!(c.resources.usb_serial, "output sumd|sbus|crsf|ibus");
+                },
+                None => {},
+            }
+        }
     }
     
     extern "C" {
@@ -328,3 +380,9 @@ const APP: () = {
     }
 };
 
+// The chip's 96 bit unique ID, which the model ID comes from
+#[allow(unsafe_code)]
+fn unique_id() -> [u8; 12] {
+    // Safe: it's read only, and always there
+    unsafe { core::ptr::read_volatile(0x1FFF_F7AC as *const [u8; 12]) }
+}
@@ -0,0 +1,40 @@
+// receiver-core's radio is the nRF24L01, in receive mode
+
+use embedded_nrf24l01::{ Configuration, Device, RxMode };
+
+use receiver_core::{ Payload, Radio };
+
+pub struct Nrf24<D: Device>(pub RxMode<D>);
+
+impl<D: Device> Radio for Nrf24<D> {
+    type Error = D::Error;
+
+    fn clear_interrupts(&mut self) -> Result<(), Self::Error> {
+        self.0.clear_interrupts()
+    }
+
+    fn read(&mut self) -> nb::Result<Payload, Self::Error> {
+        match self.0.can_read()? {
+            Some(_) => {
+                let received = self.0.read()?;
+                let mut payload = [0; protocol::PAYLOAD_SIZE];
+                payload[..received.len()].copy_from_slice(&received);
+                Ok(payload)
+            },
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    fn write_ack(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        self.0.flush_tx()?;
+        self.0.write_ack_payload(0, payload)
+    }
+
+    fn set_channel(&mut self, channel: u8) -> Result<(), Self::Error> {
+        self.0.set_frequency(channel)
+    }
+
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), Self::Error> {
+        self.0.set_rx_addr(0, address)
+    }
+}
@@ -0,0 +1,148 @@
+// Settings which survive a power cycle are kept in the store, in the last 2K
+// of flash, which memory.x keeps out of the program's way.
+//
+// The HAL can't write to flash, so this drives the flash controller itself.
+
+use core::{ ptr, slice };
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use stm32f0xx_hal::stm32::FLASH;
+
+use storage::{ Key, Store };
+
+pub const BIND: Key = Key::new(0, 1);
+/// The protocol to send the flight controller
+pub const OUTPUT: Key = Key::new(1, 1);
+/// The last session heard from the transmitter, so recordings of earlier ones
+/// are still rejected after a power cycle
+pub const SESSION: Key = Key::new(2, 1);
+
+const BASE: u32 = 0x0800_0000;
+const SIZE: u32 = 16 * 1024;
+const PAGE_SIZE: u32 = 1024;
+const KEYS: [u32; 2] = [ 0x4567_0123, 0xcdef_89ab ];
+
+/// The STM32F030's flash, a 1K page at a time
+pub struct Flash(FLASH);
+
+#[derive(Debug)]
+pub enum Error {
+    // Writing where it isn't erased
+    Program,
+    WriteProtected,
+}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+impl Flash {
+    fn unlock(&mut self) {
+        if self.0.cr.read().lock().bit_is_set() {
+            for key in KEYS.iter() {
+                self.0.keyr.write(|w| w.fkeyr().bits(*key));
+            }
+        }
+    }
+
+    fn lock(&mut self) {
+        self.0.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        while self.0.sr.read().bsy().bit_is_set() {}
+
+        let sr = self.0.sr.read();
+        let result = if sr.pgerr().bit_is_set() {
+            Err(Error::Program)
+        } else if sr.wrprt().bit_is_set() {
+            Err(Error::WriteProtected)
+        } else {
+            Ok(())
+        };
+        // The flags are cleared by writing ones
+        self.0.sr.write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+        result
+    }
+
+    fn erase_page(&mut self, offset: u32) -> Result<(), Error> {
+        self.0.cr.modify(|_, w| w.per().set_bit());
+        self.0.ar.write(|w| w.far().bits(BASE + offset));
+        self.0.cr.modify(|_, w| w.strt().set_bit());
+        let result = self.wait();
+        self.0.cr.modify(|_, w| w.per().clear_bit());
+        result
+    }
+
+    #[allow(unsafe_code)]
+    fn program(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.0.cr.modify(|_, w| w.pg().set_bit());
+        let mut result = Ok(());
+        for (i, half_word) in bytes.chunks_exact(2).enumerate() {
+            let address = (BASE + offset) as *mut u16;
+            // Safe: the store only writes inside its own pages, which the program is clear of
+            unsafe { ptr::write_volatile(address.add(i), u16::from_le_bytes([half_word[0], half_word[1]])) };
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+        self.0.cr.modify(|_, w| w.pg().clear_bit());
+        result
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    #[allow(unsafe_code)]
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        assert!(offset + bytes.len() as u32 <= SIZE);
+        // Safe: flash is mapped into memory, and nothing writes it while it's read
+        let flash = unsafe { slice::from_raw_parts((BASE + offset) as *const u8, bytes.len()) };
+        bytes.copy_from_slice(flash);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE as usize
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 2;
+    const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        self.unlock();
+        let mut result = Ok(());
+        for page in (from..to).step_by(PAGE_SIZE as usize) {
+            result = self.erase_page(page);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.lock();
+        result
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.unlock();
+        let result = self.program(offset, bytes);
+        self.lock();
+        result
+    }
+}
+
+pub fn open(flash: FLASH) -> Store<Flash> {
+    // Reading can't fail
+    Store::new(Flash(flash), storage::layout::STM32F030X4).unwrap()
+}
@@ -0,0 +1,63 @@
+// A CPPM stream on PA2, from TIM9's first channel in PWM mode, for flight
+// controllers and simulators which only take PPM. The timer counts µS, and its
+// update interrupt loads the step after next into the preloaded registers.
+
+use stm32f1xx_hal::{
+    gpio::{ AF3, Alternate, gpioa::PA2 },
+    rcc::Clocks,
+    stm32::TIM9,
+};
+
+use ppm::{ Config, Generator, Step };
+
+/// The frame length, pulse, sync gap and polarity to use
+pub const CONFIG: Config = Config { frame: 22_500, pulse: 300, sync: 4_000, polarity: ppm::Polarity::Negative };
+
+pub struct Cppm {
+    tim: TIM9,
+    generator: Generator,
+    _pin: PA2<Alternate<AF3>>,
+}
+
+impl Cppm {
+    pub fn new(tim: TIM9, pin: PA2<Alternate<AF3>>, clocks: Clocks) -> Self {
+        // Safe: the RCC registers are only touched to enable the clock
+        #[allow(unsafe_code)]
+        let rcc = unsafe { &*stm32f1xx_hal::stm32::RCC::ptr() };
+        rcc.apb2enr.modify(|_, w| w.tim9en().set_bit());
+
+        let mut cppm = Cppm { tim, generator: Generator::new(CONFIG), _pin: pin };
+        let tim = &cppm.tim;
+        // APB2's timers run at its clock, since it isn't divided
+        tim.psc.write(|w| w.psc().bits((clocks.pclk2().0 / 1_000_000 - 1) as u16));
+        tim.ccmr1_output().write(|w| w.oc1m().pwm_mode1().oc1pe().set_bit());
+        tim.ccer.write(|w| w.cc1p().bit(!CONFIG.active_level()).cc1e().set_bit());
+        tim.cr1.write(|w| w.arpe().set_bit());
+
+        // The first step goes straight into the registers, and the second
+        // waits in the preload for the first to finish
+        let first = cppm.generator.step();
+        cppm.load(first);
+        cppm.tim.egr.write(|w| w.ug().set_bit());
+        let second = cppm.generator.step();
+        cppm.load(second);
+
+        cppm.tim.sr.modify(|_, w| w.uif().clear_bit());
+        cppm.tim.dier.write(|w| w.uie().set_bit());
+        cppm.tim.cr1.modify(|_, w| w.cen().set_bit());
+        cppm
+    }
+
+    fn load(&mut self, step: Step) {
+        self.tim.arr.write(|w| w.arr().bits(step.period - 1));
+        self.tim.ccr1.write(|w| w.ccr().bits(step.active));
+    }
+
+    /// From the timer's update interrupt, with the values for the next frame
+    pub fn update(&mut self, values: &[protocol::Value]) {
+        self.tim.sr.modify(|_, w| w.uif().clear_bit());
+        self.generator.set(values);
+        let step = self.generator.step();
+        self.load(step);
+    }
+}
@@ -0,0 +1,100 @@
+// The flight controller's UART, in whichever protocol the settings ask for:
+// SUMD unless another's been chosen from the console. They all go through the
+// same driver, which writes as much of a frame as the UART will take, and the
+// rest from its transmit interrupt.
+
+use core::convert::TryFrom;
+
+use stm32f1xx_hal::{
+    prelude::*,
+    serial::{ self, Rx, Tx },
+    stm32::USART1,
+};
+
+use protocol::{
+    failsafe::LinkState,
+    quality::LinkQuality,
+    telemetry::{ Attitude, Battery, Gps, Telemetry },
+};
+use crsf::{ self, Parser };
+use rc_output::{ Driver, Link };
+use receiver_core::output::{ Framing, Protocol };
+
+pub type FlightController = Driver<Tx<USART1>, Protocol>;
+
+/// Collects telemetry from the flight controller, to forward to the
+/// transmitter. Only CRSF has any.
+#[derive(Default)]
+pub struct TelemetryParser(Parser);
+
+impl TelemetryParser {
+    pub fn receive(&mut self, byte: u8, telemetry: &mut Telemetry) {
+        if let Ok(frame) = self.0.parse(byte) {
+            match crsf::Telemetry::try_from(&frame) {
+                Ok(crsf::Telemetry::Battery(battery)) => telemetry.battery = Some(Battery {
+                    voltage: battery.voltage,
+                    current: battery.current,
+                    capacity: battery.capacity,
+                    remaining: battery.remaining,
+                }),
+                Ok(crsf::Telemetry::Attitude(attitude)) => telemetry.attitude = Some(Attitude {
+                    pitch: attitude.pitch,
+                    roll: attitude.roll,
+                    yaw: attitude.yaw,
+                }),
+                Ok(crsf::Telemetry::Gps(gps)) => telemetry.gps = Some(Gps {
+                    latitude: gps.latitude,
+                    longitude: gps.longitude,
+                    ground_speed: gps.ground_speed,
+                    heading: gps.heading,
+                    altitude: gps.altitude,
+                    satellites: gps.satellites,
+                }),
+                Err(_) => {},
+            }
+        }
+    }
+}
+
+// SBUS is inverted, and the F4's USARTs can't invert their output, so it needs
+// an inverter outside the MCU.
+pub fn serial_config(protocol: Protocol) -> serial::config::Config {
+    let serial = protocol.serial();
+    let config = serial::config::Config::default().baudrate(serial.baudrate.bps());
+    match serial.framing {
+        Framing::EightN1 => config,
+        // The parity bit counts as a data bit, so the word length is 9
+        Framing::EightE2 => config.wordlength_9().parity_even().stopbits(serial::config::StopBits::STOP2),
+    }
+}
+
+pub fn new(tx: Tx<USART1>, protocol: Protocol) -> FlightController {
+    Driver::new(tx, protocol)
+}
+
+pub fn listen(rx: &mut Rx<USART1>, protocol: Protocol) {
+    if protocol.has_telemetry() {
+        rx.listen();
+    }
+}
+
+pub fn send(flight_controller: &mut FlightController, state: LinkState, values: &[protocol::Value], quality: &LinkQuality) {
+    match flight_controller.send(Link::new(state, quality), values) {
+        // The rest of the frame is written from the transmit interrupt
+        Err(nb::Error::WouldBlock) => flight_controller.writer().listen(),
+        // Otherwise the empty UART would keep interrupting
+        Ok(_) | Err(_) => flight_controller.writer().unlisten(),
+    }
+}
+
+pub fn resume(flight_controller: &mut FlightController) {
+    if !flight_controller.is_sending() {
+        flight_controller.writer().unlisten();
+        return;
+    }
+
+    match flight_controller.poll() {
+        Err(nb::Error::WouldBlock) => {},
+        Ok(_) | Err(_) => flight_controller.writer().unlisten(),
+    }
+}
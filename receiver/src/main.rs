@@ -6,7 +6,7 @@
 extern crate panic_semihosting;
 extern crate nb;
 
-use cortex_m::{ singleton };
+use cortex_m::{ singleton, peripheral::{ DWT, SCB } };
 
 use core::{
     default::Default,
@@ -16,24 +16,29 @@ use core::{
 };
 
 use embedded_nrf24l01::{
-    NRF24L01, RxMode, Payload, Configuration, DataRate, CrcMode
+    NRF24L01, Configuration, DataRate, CrcMode
 };
 
 use stm32f1xx_hal::{
 	self,
     prelude::*,
-    gpio::{ AF5, Alternate,  Edge, ExtiPin, Input, Output, PullUp, PushPull },
+    adc::{ Adc, config::{ AdcConfig, SampleTime } },
+    gpio::{ AF5, Alternate, Analog, Edge, ExtiPin, Input, Output, PullUp, PushPull },
     gpio::{ 
         gpioa::{ 
+            // PA0: bind button, held down at power on
+            PA1,  // Supply voltage, through a divider
+            // PA2: CPPM, with the ppm-output feature, or the i-BUS sensor bus with ibus-sensors
             PA5,  // SCLK
             PA6,  // MISO
             PA7,  // MOSI
             PA4,  // CSN
-            // PA9,  // TX1: SUMD to flight controller
+            // PA9,  // TX1: SUMD, or the configured protocol, to flight controller
             // PA10, // RX1: telemetry from flight controller
          },
         gpiob::{ 
             PB0,  // CE
+            // PB4 to PB9: servos, with the pwm-output feature
             PB10, // IRQ: Note: if you change this pin you must change the EXTI interrupt below
         },
         gpioc::{
@@ -42,12 +47,11 @@ use stm32f1xx_hal::{
     },
     otg_fs::{ USB, UsbBus, UsbBusType },
     serial::{ 
-        self,
         Serial,
-        Tx,
+        Rx,
     },
     spi::{ Mode, Phase, Polarity, Spi },
-    stm32::{ SPI1, TIM2, USART1 },
+    stm32::{ ADC1, SPI1, TIM2, USART1 },
     timer::{ Timer, Event },
     
 };
@@ -61,13 +65,14 @@ use usb_device::{
 use usbd_serial;
 
 use protocol::{ 
-    Transmitter, 
-    TransmitterMessage::*,
+    bind::BindInfo,
+    hop,
+    failsafe::LinkState,
 };
+use receiver_core::{ model_id, output::Console, InitStatus, Receiver };
+use storage::Store;
 
-use sumd::{ self, Sumd };
-
-use postcard;
+use rtfm::{ cyccnt::U32Ext, Mutex };
 
 type RadioCe = PB0<Output<PushPull>>;
 type RadioCsn = PA4<Output<PushPull>>;
@@ -93,42 +98,57 @@ impl fmt::Write for ConsoleSerial {
     }
 }
 
-#[derive(Debug)]
-pub enum InitStatus {
-    Ok,
-    RadioInitFailed,
-    RadioReceiveFailed
-}
+#[cfg(feature = "ppm-output")]
+mod cppm;
+mod flight_controller;
+mod radio;
+#[cfg(feature = "ibus-sensors")]
+mod sensors;
+#[cfg(feature = "pwm-output")]
+mod servos;
+mod settings;
 
-pub struct Status {
-    init: InitStatus,
-    counter: u32,
-    last_correlation_id: u32,
-    missed_messages: u32,
-    values: [protocol::Value; 4],
-    interrupts: u32,
-    missed_interrupts: u32,
-}
+#[cfg(all(feature = "ppm-output", feature = "ibus-sensors"))]
+compile_error!("CPPM and the i-BUS sensor bus both need PA2");
 
-fn sumd_serial_config() -> serial::config::Config {
-    let default : serial::config::Config = Default::default();
-    default.baudrate(115200.bps())
-}
+use flight_controller::{ FlightController, TelemetryParser };
+use radio::Nrf24;
+use settings::Flash;
+
+// PA1 sees a third of the supply voltage, through a 20k/10k divider
+const SUPPLY_DIVIDER: u32 = 3;
+
+// Time is measured in cycles of the 48MHz system clock, and the transmitter
+// sends a message every 10mS
+const HOP_CONFIG: hop::Config = hop::Config { period: 480_000, lost: 10 };
 
 // type FlightControllerSerial = Serial<USART1, (PA9<Alternate<AF7>>, PA10<Alternate<AF7>>)>;
 
-#[rtfm::app(device = stm32f4::stm32f411, peripherals=true)]
+#[rtfm::app(device = stm32f4::stm32f411, peripherals=true, monotonic = rtfm::cyccnt::CYCCNT)]
 const APP: () = {
 
     struct Resources {
-        radio: Option<RxMode<Radio>>,
+        receiver: Receiver<Nrf24<Radio>>,
         irq: RadioIrq,
-        status: Status,
+        telemetry_parser: TelemetryParser,
         usb_dev: UsbDevice<'static, UsbBusType>,
         usb_serial: ConsoleSerial,
+        console: Console,
         timer: Timer<TIM2>,
         led: PC13<Output<PushPull>>,
-        flight_controller: Sumd<Tx<USART1>>,
+        flight_controller: FlightController,
+        flight_controller_rx: Rx<USART1>,
+        supply_adc: Adc<ADC1>,
+        supply_pin: PA1<Analog>,
+        store: Store<Flash>,
+        #[cfg(feature = "pwm-output")]
+        tim4_servos: servos::Tim4Servos,
+        #[cfg(feature = "pwm-output")]
+        tim3_servos: servos::Tim3Servos,
+        #[cfg(feature = "ppm-output")]
+        cppm: cppm::Cppm,
+        #[cfg(feature = "ibus-sensors")]
+        sensor_bus: sensors::SensorBus,
     }
 
     #[init]
@@ -137,6 +157,11 @@ const APP: () = {
 
         // Get access to the device specific peripherals from the peripheral access crate
         let mut peripherals = c.device;
+
+        // The cycle counter is the clock for hopping
+        let mut core = c.core;
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
         // Take ownership over the raw flash and rcc devices and convert them into the corresponding
         // HAL structs
         let rcc = peripherals.RCC.constrain();
@@ -157,6 +182,16 @@ const APP: () = {
         let mut led =  gpioc.pc13.into_push_pull_output();
         led.set_low().unwrap();
 
+        // Bind if the button is held down, or there's no transmitter to listen to
+        let bind_button = gpioa.pa0.into_pull_up_input();
+        let mut store = settings::open(peripherals.FLASH);
+        let stored = match store.load(settings::BIND) {
+            Some(info) if bind_button.is_high().unwrap() => Some(info),
+            _ => None,
+        };
+        let session = store.load_or_default(settings::SESSION);
+        c.schedule.hop_timer(c.start + (HOP_CONFIG.period / 4).cycles()).unwrap();
+
         
         let usb = USB {
             usb_global: peripherals.OTG_FS_GLOBAL,
@@ -200,16 +235,16 @@ const APP: () = {
         irq.trigger_on_edge(&mut peripherals.EXTI, Edge::FALLING);
         irq.enable_interrupt(&mut peripherals.EXTI);
 
-        let (radio, status) = match NRF24L01::new(ce, csn, spi) {
+        // The receiver sets the frequency and address
+        let radio = match NRF24L01::new(ce, csn, spi) {
             Ok(mut radio) => {
-                radio.set_frequency(protocol::FREQUENCY).unwrap();
-
                 radio.set_rf(DataRate::R250Kbps, 0).unwrap();
                 radio.set_crc(Some(CrcMode::TwoBytes)).unwrap();
                 radio.set_auto_ack(&[ true; 6 ]).unwrap();
+                // Telemetry goes back to the transmitter in the acknowledgements
+                radio.set_ack_payloads(true).unwrap();
                 radio.set_auto_retransmit(0b0100, 15).unwrap();
 
-                radio.set_rx_addr(0, &protocol::RX_ADDRESS).unwrap();
                 radio.set_pipes_rx_lengths(&[ None; 6]).unwrap();
                 radio.set_pipes_rx_enable(&[true, false, false, false, false, false]).unwrap();
                 radio.flush_tx().unwrap();
@@ -219,162 +254,260 @@ const APP: () = {
                 radio.set_interrupt_mask(false, false, false).unwrap();
                 radio.clear_interrupts().unwrap();
 
-                match radio.rx() {
-                    Ok(rx) => {
-                        (Some(rx), InitStatus::Ok)
-                    },
-                    Err(_) => {
-                        (None, InitStatus::RadioReceiveFailed)
-                    }
-                }
+                radio.rx().map(Nrf24).map_err(|_| InitStatus::RadioReceiveFailed)
             },
-            Err(_) => {
-                (None, InitStatus::RadioInitFailed)
-            }
+            Err(_) => Err(InitStatus::RadioInitFailed),
         };
         
         
 
+        let output = store.load_or_default(settings::OUTPUT);
         let flight_controller = Serial::usart1(
             peripherals.USART1, 
             (gpioa.pa9.into_alternate_af7(), gpioa.pa10.into_alternate_af7()),
-            sumd_serial_config(),
+            flight_controller::serial_config(output),
             clocks).unwrap();
 
-        let (flight_controller_tx, _) = flight_controller.split();
+        let (flight_controller_tx, mut flight_controller_rx) = flight_controller.split();
+        flight_controller::listen(&mut flight_controller_rx, output);
+
+        let supply_adc = Adc::adc1(peripherals.ADC1, true, AdcConfig::default());
+        let supply_pin = gpioa.pa1.into_analog();
 
         // Configure the syst timer to trigger an update every second and enables interrupt
         let mut timer = Timer::tim2(peripherals.TIM2, 100.hz(), clocks);
         timer.listen(Event::TimeOut);
 
+        #[cfg(feature = "pwm-output")]
+        let tim4_servos = servos::Tim4Servos::new(
+            peripherals.TIM4,
+            (gpiob.pb6.into_alternate_af2(), gpiob.pb7.into_alternate_af2(),
+             gpiob.pb8.into_alternate_af2(), gpiob.pb9.into_alternate_af2()),
+            clocks);
+        #[cfg(feature = "pwm-output")]
+        let tim3_servos = servos::Tim3Servos::new(
+            peripherals.TIM3,
+            (gpiob.pb4.into_alternate_af2(), gpiob.pb5.into_alternate_af2()),
+            clocks);
+
+        #[cfg(feature = "ppm-output")]
+        let cppm = cppm::Cppm::new(peripherals.TIM9, gpioa.pa2.into_alternate_af3(), clocks);
+
+        // The line is shared, so the pin only pulls it low
+        #[cfg(feature = "ibus-sensors")]
+        let sensor_bus = sensors::SensorBus::new(peripherals.USART2, gpioa.pa2.into_alternate_af7().set_open_drain(), clocks);
+
         init::LateResources {
-            radio,
+            receiver: Receiver::new(radio, stored, session, model_id(&unique_id()), HOP_CONFIG, DWT::cycle_count()),
             irq: irq,
-            status: Status {
-                init: status,
-                values: [0; 4],
-                counter: 0,
-                last_correlation_id: 0,
-                missed_messages: 0,
-                interrupts: 0,
-                missed_interrupts: 0,
-            },
+            telemetry_parser: TelemetryParser::default(),
             usb_dev,
             usb_serial,
+            console: Console::new(),
             timer,
             led,
-            flight_controller: Sumd::new(flight_controller_tx),
+            flight_controller: flight_controller::new(flight_controller_tx, output),
+            flight_controller_rx,
+            supply_adc,
+            supply_pin,
+            store,
+            #[cfg(feature = "pwm-output")]
+            tim4_servos,
+            #[cfg(feature = "pwm-output")]
+            tim3_servos,
+            #[cfg(feature = "ppm-output")]
+            cppm,
+            #[cfg(feature = "ibus-sensors")]
+            sensor_bus,
  		}
     }
 
-    #[task(binds = EXTI15_10, priority = 1, 
-        resources = [ irq, status ],
+    // Everything to do with the radio and the outputs runs at priority 2, so
+    // saving to flash, which may erase a 128K sector and take a second or
+    // two, only holds up the console and the status log
+
+    #[task(binds = EXTI15_10, priority = 2, 
+        resources = [ irq, receiver ],
         spawn = [ receive ])]
     fn interrupt(c: interrupt::Context) {
-            c.resources.status.interrupts += 1;
+            c.resources.receiver.status.interrupts += 1;
             c.resources.irq.clear_interrupt_pending_bit();
             match c.spawn.receive() {
                 Ok(_) => {},
                 Err(_) => {
-                    c.resources.status.missed_interrupts += 1;
+                    c.resources.receiver.status.missed_interrupts += 1;
                 }
             };
     }
 
-    #[task(resources = [radio, status], spawn=[process])]
+    #[task(priority = 2, resources = [receiver])]
     fn receive(c: receive::Context) {
-        let mut rx = c.resources.radio.take().unwrap();
-        rx.clear_interrupts().unwrap();
-        while match rx.can_read() {
-            Ok(Some(_)) => {
-                match c.spawn.process(rx.read().unwrap()) {
-                    Ok(_) => true,
-                    Err(_) => false
-                }
-            },
-
-            Ok(None) => false,
-            Err(_) => false,
-        } {}
-        *c.resources.radio = Some(rx);
-    }
-
-    #[task(resources=[status])]
-    fn process(c: process::Context, payload: Payload) {
-        let result : postcard::Result<Transmitter> = postcard::from_bytes(&payload);
-        match result {
-            Ok(Transmitter { correlation_id, body }) => {
-                c.resources.status.missed_messages += 
-                    correlation_id - c.resources.status.last_correlation_id - 1;
-                c.resources.status.last_correlation_id = correlation_id;
-                match body {
-                    ChannelValues(values) => {
-                        c.resources.status.values = values;
-                    }
-                }
-            },
-
-            Err(_) => {}
-
-        }
+        c.resources.receiver.receive(DWT::cycle_count());
     }
 
-    #[task(resources = [status, usb_serial])]
-    fn log_status(c: log_status::Context, can_read: bool, is_full: bool) {
-        let _ = writeln!(c.resources.usb_serial, 
-            "Tick; init: {:?} last(missed): {}({}), interrupts(missed): {}({}), can_read: {}, is_full: {}",
-            c.resources.status.init,
-            c.resources.status.last_correlation_id,
-            c.resources.status.missed_messages,
-            c.resources.status.interrupts,
-            c.resources.status.missed_interrupts,
-            can_read, 
-            is_full);
-        let _ = writeln!(c.resources.usb_serial, "channels: {:?}", c.resources.status.values);
+    #[task(resources = [receiver, usb_serial, flight_controller])]
+    fn log_status(mut c: log_status::Context, can_read: bool, is_full: bool) {
+        let usb_serial = c.resources.usb_serial;
+        c.resources.receiver.lock(|receiver| {
+            let _ = writeln!(usb_serial, 
+                "Tick; init: {:?} last(missed): {}({}), rejected: {}, interrupts(missed): {}({}), can_read: {}, is_full: {}",
+                receiver.init(),
+                receiver.status.last_correlation_id,
+                receiver.status.missed_messages,
+                receiver.status.rejected_messages,
+                receiver.status.interrupts,
+                receiver.status.missed_interrupts,
+                can_read, 
+                is_full);
+            let _ = writeln!(usb_serial, "bind: {:?}, hop: {:?}, link: {:?} ({}%), channels: {:?}",
+                receiver.bind_state(),
+                receiver.hop_state(),
+                receiver.failsafe().state(),
+                receiver.link_quality().quality(),
+                receiver.failsafe().values());
+            let _ = writeln!(usb_serial, "telemetry: {:?}", receiver.telemetry());
+        });
+        let output = c.resources.flight_controller.lock(|flight_controller| *flight_controller.output());
+        let _ = writeln!(usb_serial, "output: {:?}", output);
     }
 
-    #[task(binds = TIM2, priority = 1, resources = [ status, timer, led, radio ], 
-        spawn = [ log_status, receive, send_to_flight_controller ])]
+    #[task(binds = TIM2, priority = 2, resources = [ receiver, timer, led, supply_adc, supply_pin ], 
+        spawn = [ log_status, receive, send_to_flight_controller, bound, session ])]
     fn tick(c: tick::Context) {
         c.resources.timer.clear_interrupt(Event::TimeOut);
 
-        let mut rx = c.resources.radio.take().unwrap();
-        let can_read = rx.can_read().unwrap().is_some();
+        // In case the radio's interrupt was missed
+        let (can_read, is_full) = match c.resources.receiver.radio_mut() {
+            Some(Nrf24(rx)) => (rx.can_read().unwrap().is_some(), rx.is_full().unwrap()),
+            None => (false, false),
+        };
         if can_read {
-            c.spawn.receive().unwrap();
+            let _ = c.spawn.receive();
+        }
+
+        let counter = c.resources.receiver.status.counter;
+        let (supply_adc, supply_pin) = (c.resources.supply_adc, c.resources.supply_pin);
+        let tick = c.resources.receiver.tick(DWT::cycle_count(), || {
+            let sample = supply_adc.convert(supply_pin, SampleTime::Cycles_480);
+            (supply_adc.sample_to_millivolts(sample) as u32 * SUPPLY_DIVIDER) as u16
+        });
+
+        if let Some((state, values)) = tick.send {
+            c.spawn.send_to_flight_controller(state, values).unwrap();
         }
 
-        if c.resources.status.counter % 10 == 0 {
-            c.spawn.send_to_flight_controller(c.resources.status.values).unwrap();
+        if let Some(info) = tick.bound {
+            let _ = c.spawn.bound(info);
         }
 
-        if c.resources.status.counter % 500 == 0 {
+        if let Some(session) = tick.session {
+            let _ = c.spawn.session(session);
+        }
+
+        if counter % 500 == 0 {
             c.resources.led.toggle().unwrap();
         }
 
-        if c.resources.status.counter % 1000 == 0 {
-            c.spawn.log_status(can_read, rx.is_full().unwrap()).unwrap();
+        if counter % 1000 == 0 {
+            c.spawn.log_status(can_read, is_full).unwrap();
         }
+    }
 
+    // Binding is complete, so remember the transmitter
+    #[task(resources = [store])]
+    fn bound(c: bound::Context, info: BindInfo) {
+        let _ = c.resources.store.save(settings::BIND, &info);
+    }
+
+    // The transmitter has started a new session, which is once each time it's switched on
+    #[task(resources = [store])]
+    fn session(c: session::Context, session: u8) {
+        let _ = c.resources.store.save(settings::SESSION, &session);
+    }
 
-        c.resources.status.counter += 1;
+    // Follow the transmitter from channel to channel, or scan for it
+    #[task(priority = 2, resources = [receiver], schedule = [hop_timer])]
+    fn hop_timer(c: hop_timer::Context) {
+        c.resources.receiver.hop(DWT::cycle_count());
+        c.schedule.hop_timer(c.scheduled + (HOP_CONFIG.period / 4).cycles()).unwrap();
+    }
+
+    #[task(priority = 2, resources = [flight_controller, receiver])]
+    fn send_to_flight_controller(c: send_to_flight_controller::Context,
+        state: LinkState, values: [protocol::Value; protocol::CHANNELS]) {
+        flight_controller::send(c.resources.flight_controller, state, &values, c.resources.receiver.link_quality());
+    }
 
-        *c.resources.radio = Some(rx);
+    // The servos follow whatever would go to the flight controller, including
+    // the failsafe positions
+    #[cfg(feature = "pwm-output")]
+    #[task(binds = TIM4, priority = 2, resources = [ receiver, tim4_servos ])]
+    fn tim4(c: tim4::Context) {
+        c.resources.tim4_servos.update(&c.resources.receiver.failsafe().values());
     }
 
-    #[task(resources = [flight_controller, status])]
-    fn send_to_flight_controller(c: send_to_flight_controller::Context, values: [protocol::Value; 4]) {
-        c.resources.flight_controller.send(sumd::Status::Live, &values).unwrap();
+    #[cfg(feature = "pwm-output")]
+    #[task(binds = TIM3, priority = 2, resources = [ receiver, tim3_servos ])]
+    fn tim3(c: tim3::Context) {
+        c.resources.tim3_servos.update(&c.resources.receiver.failsafe().values());
+    }
+
+    // The same values as go to the flight controller. A step is at least a
+    // mS, which is how long there is to load the one after.
+    #[cfg(feature = "ppm-output")]
+    #[task(binds = TIM1_BRK_TIM9, priority = 2, resources = [ receiver, cppm ])]
+    fn tim9(c: tim9::Context) {
+        c.resources.cppm.update(&c.resources.receiver.failsafe().values());
+    }
+
+    #[cfg(feature = "ibus-sensors")]
+    #[task(binds = USART2, priority = 2, resources = [receiver, sensor_bus])]
+    fn usart2(c: usart2::Context) {
+        c.resources.sensor_bus.poll(&c.resources.receiver.telemetry().link);
+    }
+
+    #[task(binds = USART1, priority = 2, resources = [flight_controller, flight_controller_rx, receiver, telemetry_parser])]
+    fn usart1(c: usart1::Context) {
+        while let Ok(byte) = c.resources.flight_controller_rx.read() {
+            c.resources.telemetry_parser.receive(byte, c.resources.receiver.telemetry_mut());
+        }
+        flight_controller::resume(c.resources.flight_controller);
     }
     
-    #[task(binds = OTG_FS, resources = [usb_dev, usb_serial])]
+    // The console takes `output sumd`, `output sbus`, `output crsf` or `output
+    // ibus`, which is saved, and the receiver restarts to set the UART up for it
+    #[task(binds = OTG_FS, resources = [usb_dev, usb_serial, console, store])]
     fn otg_fs(c: otg_fs::Context) {
-        c.resources.usb_dev.poll(&mut [&mut c.resources.usb_serial.0]);
+        if !c.resources.usb_dev.poll(&mut [&mut c.resources.usb_serial.0]) {
+            return;
+        }
+
+        let mut buffer = [0u8; 64];
+        let count = c.resources.usb_serial.0.read(&mut buffer).unwrap_or(0);
+        for byte in &buffer[..count] {
+            match c.resources.console.receive(*byte) {
+                Some(Ok(output)) => {
+                    let _ = c.resources.store.save(settings::OUTPUT, &output);
+                    SCB::sys_reset();
+                },
+                Some(Err(_)) => {
+                    let _ = writeln!(c.resources.usb_serial, "output sumd|sbus|crsf|ibus");
+                },
+                None => {},
+            }
+        }
     }
     
+    // Software tasks run from interrupts of peripherals which aren't used
     extern "C" {
-        fn USART2();
-        fn USART3();
+        fn SPI2();
+        fn SPI3();
     }
 };
 
+// The chip's 96 bit unique ID, which the model ID comes from
+#[allow(unsafe_code)]
+fn unique_id() -> [u8; 12] {
+    // Safe: it's read only, and always there
+    unsafe { core::ptr::read_volatile(0x1FFF_7A10 as *const [u8; 12]) }
+}
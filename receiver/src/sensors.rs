@@ -0,0 +1,75 @@
+// The i-BUS sensor bus, half duplex on PA2, USART2's TX pin. The receiver
+// answers for two sensors of its own: its supply voltage and how many
+// messages it's missing.
+
+use stm32f1xx_hal::{
+    prelude::*,
+    gpio::{ AF7, Alternate, gpioa::PA2 },
+    rcc::Clocks,
+    serial::{ self, Event, NoRx, Serial },
+    stm32::USART2,
+};
+
+use ibus::{ sensors, sensors::Reply, Sensors };
+use protocol::telemetry::Link;
+
+const VOLTAGE: usize = 0;
+const ERROR_RATE: usize = 1;
+
+pub struct SensorBus {
+    serial: Serial<USART2, (PA2<Alternate<AF7>>, NoRx)>,
+    sensors: Sensors<2>,
+    // The reply being sent, and how much of it has gone
+    reply: Reply,
+    sent: usize,
+}
+
+impl SensorBus {
+    pub fn new(usart: USART2, pin: PA2<Alternate<AF7>>, clocks: Clocks) -> Self {
+        let config = serial::config::Config::default().baudrate(115200.bps());
+        let mut serial = Serial::usart2(usart, (pin, NoRx), config, clocks).unwrap();
+
+        // The HAL has no half duplex mode, and it can only be selected while
+        // the USART is disabled
+        #[allow(unsafe_code)]
+        let usart = unsafe { &*USART2::ptr() };
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.cr3.modify(|_, w| w.hdsel().set_bit());
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+
+        serial.listen(Event::Rxne);
+        SensorBus {
+            serial,
+            sensors: Sensors::new(1, [sensors::INTERNAL_VOLTAGE, sensors::ERROR_RATE]),
+            reply: Reply::new(),
+            sent: 0,
+        }
+    }
+
+    /// From the USART's interrupt. A reply takes half a mS to send, so it's
+    /// queued, and sent a byte at a time as the USART empties.
+    pub fn poll(&mut self, link: &Link) {
+        self.sensors.set(VOLTAGE, link.voltage / 10);
+        self.sensors.set(ERROR_RATE, 100 - link.quality.min(100) as u16);
+        while let Ok(byte) = self.serial.read() {
+            if let Some(reply) = self.sensors.receive(byte) {
+                self.reply = reply;
+                self.sent = 0;
+            }
+        }
+
+        while let Some(&byte) = self.reply.get(self.sent) {
+            match self.serial.write(byte) {
+                Ok(()) => self.sent += 1,
+                Err(_) => break,
+            }
+        }
+
+        // Otherwise the empty USART would keep interrupting
+        if self.sent < self.reply.len() {
+            self.serial.listen(Event::Txe);
+        } else {
+            self.serial.unlisten(Event::Txe);
+        }
+    }
+}
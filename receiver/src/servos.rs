@@ -0,0 +1,94 @@
+// Servos, for models without a flight controller: TIM4's four channels on PB6
+// to PB9, and TIM3's first two on PB4 and PB5. Each timer interrupts at the
+// end of every period, to set the pulses for the next.
+
+use embedded_hal::PwmPin;
+use stm32f1xx_hal::{
+    prelude::*,
+    gpio::{ AF2, Alternate, gpiob::{ PB4, PB5, PB6, PB7, PB8, PB9 } },
+    pwm::{ self, C1, C2, C3, C4, PwmChannels },
+    rcc::Clocks,
+    stm32::{ tim3::RegisterBlock, TIM3, TIM4 },
+};
+
+use protocol::Value;
+use receiver_core::servo::{ Output, Rate, Servos };
+
+/// Which channel each output follows, and how fast its servo wants pulses.
+/// The outputs on each timer are pulsed at the fastest rate of any of them,
+/// or every sixth period for the analog ones.
+pub const OUTPUTS: [Output; 6] = [
+    Output::new(0),
+    Output::new(1),
+    Output::new(2),
+    Output::new(3),
+    Output::new(4),
+    Output { channel: 5, rate: Rate::Analog },
+];
+
+// The HAL's PWM doesn't use the update interrupt, so it's enabled and cleared
+// here. The PWM channels only touch the compare registers.
+#[allow(unsafe_code)]
+fn registers(tim: *const RegisterBlock) -> &'static RegisterBlock {
+    unsafe { &*tim }
+}
+
+fn listen(tim: *const RegisterBlock) {
+    registers(tim).dier.modify(|_, w| w.uie().set_bit());
+}
+
+fn clear(tim: *const RegisterBlock) {
+    registers(tim).sr.modify(|_, w| w.uif().clear_bit());
+}
+
+pub struct Tim4Servos {
+    servos: Servos<4>,
+    channels: (PwmChannels<TIM4, C1>, PwmChannels<TIM4, C2>, PwmChannels<TIM4, C3>, PwmChannels<TIM4, C4>),
+}
+
+impl Tim4Servos {
+    pub fn new(tim: TIM4, pins: (PB6<Alternate<AF2>>, PB7<Alternate<AF2>>, PB8<Alternate<AF2>>, PB9<Alternate<AF2>>), clocks: Clocks) -> Self {
+        let servos = Servos::new([OUTPUTS[0], OUTPUTS[1], OUTPUTS[2], OUTPUTS[3]]);
+        let mut channels = pwm::tim4(tim, pins, clocks, servos.rate().frequency().hz());
+        channels.0.enable();
+        channels.1.enable();
+        channels.2.enable();
+        channels.3.enable();
+        listen(TIM4::ptr());
+        Tim4Servos { servos, channels }
+    }
+
+    /// From the timer's interrupt
+    pub fn update(&mut self, values: &[Value]) {
+        clear(TIM4::ptr());
+        let duties = self.servos.next(values, self.channels.0.get_max_duty());
+        self.channels.0.set_duty(duties[0]);
+        self.channels.1.set_duty(duties[1]);
+        self.channels.2.set_duty(duties[2]);
+        self.channels.3.set_duty(duties[3]);
+    }
+}
+
+pub struct Tim3Servos {
+    servos: Servos<2>,
+    channels: (PwmChannels<TIM3, C1>, PwmChannels<TIM3, C2>),
+}
+
+impl Tim3Servos {
+    pub fn new(tim: TIM3, pins: (PB4<Alternate<AF2>>, PB5<Alternate<AF2>>), clocks: Clocks) -> Self {
+        let servos = Servos::new([OUTPUTS[4], OUTPUTS[5]]);
+        let mut channels = pwm::tim3(tim, pins, clocks, servos.rate().frequency().hz());
+        channels.0.enable();
+        channels.1.enable();
+        listen(TIM3::ptr());
+        Tim3Servos { servos, channels }
+    }
+
+    /// From the timer's interrupt
+    pub fn update(&mut self, values: &[Value]) {
+        clear(TIM3::ptr());
+        let duties = self.servos.next(values, self.channels.0.get_max_duty());
+        self.channels.0.set_duty(duties[0]);
+        self.channels.1.set_duty(duties[1]);
+    }
+}
@@ -0,0 +1,76 @@
+// Settings which survive a power cycle are kept in the store, in the last two
+// sectors of flash, which are well clear of the program.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use stm32f1xx_hal::{
+    flash::{ self, FlashExt },
+    stm32::FLASH,
+};
+
+use storage::{ Key, Store };
+
+pub const BIND: Key = Key::new(0, 1);
+/// The protocol to send the flight controller
+pub const OUTPUT: Key = Key::new(1, 1);
+/// The last session heard from the transmitter, so recordings of earlier ones
+/// are still rejected after a power cycle
+pub const SESSION: Key = Key::new(2, 1);
+
+// Sectors 5 and up are 128K each, from 128K in
+const SECTOR_SIZE: u32 = 128 * 1024;
+const FIRST_LARGE_SECTOR: u8 = 5;
+
+/// The STM32F411's flash
+pub struct Flash(FLASH);
+
+#[derive(Debug)]
+pub struct Error(flash::Error);
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.0.read()[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    // Erasing a sector this size takes a second or two
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        let mut unlocked = self.0.unlocked();
+        for sector in from / SECTOR_SIZE..to / SECTOR_SIZE {
+            unlocked.erase(FIRST_LARGE_SECTOR + sector as u8 - 1).map_err(Error)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.0.unlocked().program(offset as usize, bytes.iter()).map_err(Error)
+    }
+}
+
+pub fn open(flash: FLASH) -> Store<Flash> {
+    // Reading can't fail
+    Store::new(Flash(flash), storage::layout::STM32F411XE).unwrap()
+}
@@ -0,0 +1,245 @@
+// Futaba S.BUS, as understood by most flight controllers. There is no
+// official specification; this follows the de facto one implemented by
+// Betaflight, INAV and ArduPilot.
+//
+// The serial link is 100000 baud, 8 data bits, even parity and 2 stop bits
+// (8E2), and the signal is inverted: idle is low. The STM32F1 and F4 USARTs
+// can't invert their output, so they need an external inverter, e.g. a single
+// transistor, or a flight controller input which already has one.
+#![no_std]
+
+use embedded_hal::serial::Write;
+use heapless::Vec;
+use rc_output::{ Link, LinkState, RcOutput };
+
+// Each frame starts with a header byte
+const HEADER: u8 = 0x0f;
+
+// Then 16 channels of 11 bits each, packed least significant bit first
+pub const CHANNELS: usize = 16;
+pub const CHANNEL_BITS: usize = 11;
+pub const CHANNEL_BYTES: usize = CHANNELS * CHANNEL_BITS / 8;
+
+// The values commonly used by transmitters for the reference positions
+
+/// Low (-100%), equivalent to 988µs pulse length
+pub const LOW: u16 = 172;
+/// Neutral position (0%), equivalent to 1500µs pulse length
+pub const NEUTRAL: u16 = 992;
+/// High (100%), equivalent to 2012µs pulse length
+pub const HIGH: u16 = 1811;
+
+/// Scale a value which uses the whole range of a u16 to the range LOW..=HIGH
+pub fn scale(value: u16) -> u16 {
+    LOW + ((value as u32 * (HIGH - LOW) as u32 + 0x7fff) / 0xffff) as u16
+}
+
+/// Scale and pack the proportional channels. Any left out are neutral.
+pub fn pack(values: &[u16]) -> [u8; CHANNEL_BYTES] {
+    let mut packed = [0; CHANNEL_BYTES];
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    let mut index = 0;
+    for channel in 0..CHANNELS {
+        let value = match values.get(channel) {
+            Some(value) => scale(*value),
+            None => NEUTRAL,
+        };
+
+        bits |= (value as u32) << count;
+        count += CHANNEL_BITS;
+        while count >= 8 {
+            packed[index] = bits as u8;
+            index += 1;
+            bits >>= 8;
+            count -= 8;
+        }
+    }
+    packed
+}
+
+// Then a flags byte, with two digital channels, 17 and 18, and the link status
+const FLAG_CHANNEL_17: u8 = 0x01;
+const FLAG_CHANNEL_18: u8 = 0x02;
+const FLAG_FRAME_LOST: u8 = 0x04;
+const FLAG_FAILSAFE: u8 = 0x08;
+
+pub const DIGITAL_CHANNELS: usize = 2;
+pub const MAX_CHANNELS: usize = CHANNELS + DIGITAL_CHANNELS;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Live,
+    /// This frame is a repeat, because the receiver missed the latest one
+    FrameLost,
+    /// The receiver has lost the transmitter, and the values are its failsafe positions
+    FailSafe,
+}
+
+impl From<Status> for u8 {
+    fn from(status: Status) -> u8 {
+        match status {
+            Status::Live => 0,
+            Status::FrameLost => FLAG_FRAME_LOST,
+            Status::FailSafe => FLAG_FRAME_LOST | FLAG_FAILSAFE,
+        }
+    }
+}
+
+impl From<LinkState> for Status {
+    fn from(state: LinkState) -> Status {
+        match state {
+            LinkState::Live => Status::Live,
+            LinkState::Hold => Status::FrameLost,
+            LinkState::FailSafe => Status::FailSafe,
+        }
+    }
+}
+
+// And finally a footer byte
+const FOOTER: u8 = 0x00;
+
+pub const FRAME_SIZE: usize = 1 + CHANNEL_BYTES + 1 + 1;
+
+/// Send a frame. The values use the whole range of a u16, like `sumd::send`.
+/// Up to 16 are proportional channels, and any channels left out are sent as
+/// neutral. The 17th and 18th are the digital channels, which are on if the
+/// value is in the upper half of the range.
+pub fn send<Out: Write<u8>>(out: &mut Out, status: Status, values: &[u16]) -> nb::Result<(), Out::Error> {
+    out.write(HEADER)?;
+    for byte in &pack(values) {
+        out.write(*byte)?;
+    }
+
+    let mut flags = u8::from(status);
+    if values.get(CHANNELS).is_some_and(|value| *value >= 0x8000) {
+        flags |= FLAG_CHANNEL_17;
+    }
+    if values.get(CHANNELS + 1).is_some_and(|value| *value >= 0x8000) {
+        flags |= FLAG_CHANNEL_18;
+    }
+    out.write(flags)?;
+
+    out.write(FOOTER)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// SBUS as an RcOutput, with up to 18 channels
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encoder;
+
+impl RcOutput for Encoder {
+    fn send<W: Write<u8>>(&self, out: &mut W, link: &Link, values: &[u16]) -> nb::Result<(), W::Error> {
+        send(out, Status::from(link.state), values)
+    }
+}
+
+pub struct SbusBuffer(pub Vec<u8, FRAME_SIZE>);
+
+impl Write<u8> for SbusBuffer {
+    type Error = u8;
+
+    /// Writes a single word to the serial interface
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.0.push(word)?;
+        Ok(())
+    }
+
+    /// Ensures that none of the previously written words are still buffered
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl Default for SbusBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SbusBuffer {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn encode(&mut self, status: Status, values: &[u16]) {
+        self.0.clear();
+        send(self, status, values).unwrap();
+    }
+}
+
+// The whole frame is 25 bytes, and each byte takes 12 bits on the wire, so
+// it takes 3mS to transmit. Frames are generally sent every 14mS, or every
+// 7mS in "high speed" mode, but flight controllers accept any interval.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(frame: &[u8], channel: usize) -> u16 {
+        let bit = channel * CHANNEL_BITS;
+        let byte = 1 + bit / 8;
+        let bits = frame[byte] as u32 | (frame[byte + 1] as u32) << 8 | (frame[byte + 2] as u32) << 16;
+        ((bits >> (bit % 8)) & 0x7ff) as u16
+    }
+
+    #[test]
+    fn scales_reference_values() {
+        assert_eq!(scale(0), LOW);
+        assert_eq!(scale(0x8000), NEUTRAL);
+        assert_eq!(scale(0xffff), HIGH);
+    }
+
+    #[test]
+    fn frame_layout() {
+        let mut buffer = SbusBuffer::new();
+        buffer.encode(Status::Live, &[0, 0x8000, 0xffff]);
+
+        let frame = &buffer.0;
+        assert_eq!(frame.len(), FRAME_SIZE);
+        assert_eq!(frame[0], HEADER);
+        assert_eq!(frame[FRAME_SIZE - 2], 0);
+        assert_eq!(frame[FRAME_SIZE - 1], FOOTER);
+        assert_eq!(channel(frame, 0), LOW);
+        assert_eq!(channel(frame, 1), NEUTRAL);
+        assert_eq!(channel(frame, 2), HIGH);
+        for i in 3..CHANNELS {
+            assert_eq!(channel(frame, i), NEUTRAL);
+        }
+    }
+
+    #[test]
+    fn all_channels_round_trip() {
+        let values: Vec<u16, CHANNELS> = (0..CHANNELS as u16).map(|i| i * 0x1000).collect();
+        let mut buffer = SbusBuffer::new();
+        buffer.encode(Status::Live, &values);
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(channel(&buffer.0, i), scale(*value));
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let mut values = [0x8000; MAX_CHANNELS];
+        let mut buffer = SbusBuffer::new();
+
+        buffer.encode(Status::FrameLost, &values);
+        assert_eq!(buffer.0[FRAME_SIZE - 2], FLAG_CHANNEL_17 | FLAG_CHANNEL_18 | FLAG_FRAME_LOST);
+
+        values[CHANNELS] = 0;
+        buffer.encode(Status::FailSafe, &values);
+        assert_eq!(buffer.0[FRAME_SIZE - 2], FLAG_CHANNEL_18 | FLAG_FRAME_LOST | FLAG_FAILSAFE);
+    }
+
+    #[test]
+    fn encoder_link_states() {
+        let values = [0x8000; CHANNELS];
+        for (state, flags) in [(LinkState::Live, 0), (LinkState::Hold, FLAG_FRAME_LOST), (LinkState::FailSafe, FLAG_FRAME_LOST | FLAG_FAILSAFE)] {
+            let mut buffer = SbusBuffer::new();
+            Encoder.send(&mut buffer, &Link { state, quality: 0, rssi: 130 }, &values).unwrap();
+            assert_eq!(buffer.0.len(), FRAME_SIZE);
+            assert_eq!(buffer.0[FRAME_SIZE - 2], flags);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// The radio link between the transmitter and the receiver. The nRF24s are
+// modelled as far as the firmware can tell: a packet arrives if the receiver
+// is listening on the right address and channel, and the receiver's
+// acknowledgement carries whatever payload it had ready.
+
+use std::collections::VecDeque;
+
+use protocol::{ random::XorShift32, PAYLOAD_SIZE };
+
+pub type Payload = [u8; PAYLOAD_SIZE];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConfig {
+    /// Packets lost, per thousand
+    pub loss: u32,
+    /// Acknowledgements lost, per thousand
+    pub ack_loss: u32,
+    /// Packets with a bit flipped that the radio's CRC didn't catch, per thousand
+    pub corruption: u32,
+    /// How long packets and acknowledgements take to arrive, in µS
+    pub latency: u32,
+}
+
+/// A radio that's listening
+pub trait Listener {
+    fn address(&self) -> [u8; 5];
+    fn channel(&self) -> u8;
+    /// A packet arrived. Returns the acknowledgement payload, if there is one.
+    fn receive(&mut self, now: u32, payload: Payload) -> Option<Payload>;
+}
+
+struct InFlight {
+    arrives: u32,
+    address: [u8; 5],
+    channel: u8,
+    payload: Payload,
+}
+
+pub struct Air {
+    config: LinkConfig,
+    random: XorShift32,
+    packets: VecDeque<InFlight>,
+    acks: VecDeque<(u32, Payload)>,
+    /// Every packet sent
+    pub sent: u32,
+    /// Every packet heard by the receiver
+    pub delivered: u32,
+}
+
+impl Air {
+    pub fn new(config: LinkConfig, seed: u32) -> Self {
+        Air {
+            config,
+            random: XorShift32::new(seed),
+            packets: VecDeque::new(),
+            acks: VecDeque::new(),
+            sent: 0,
+            delivered: 0,
+        }
+    }
+
+    pub fn config(&mut self) -> &mut LinkConfig {
+        &mut self.config
+    }
+
+    fn chance(&mut self, per_thousand: u32) -> bool {
+        self.random.below(1000) < per_thousand
+    }
+
+    pub fn send(&mut self, now: u32, address: [u8; 5], channel: u8, payload: &[u8]) {
+        self.sent += 1;
+        if self.chance(self.config.loss) {
+            return;
+        }
+
+        let mut packet = [0; PAYLOAD_SIZE];
+        packet[..payload.len()].copy_from_slice(payload);
+        if self.chance(self.config.corruption) {
+            let bit = self.random.below(payload.len() as u32 * 8) as usize;
+            packet[bit / 8] ^= 1 << (bit % 8);
+        }
+
+        self.packets.push_back(InFlight { arrives: now + self.config.latency, address, channel, payload: packet });
+    }
+
+    /// Deliver the packets which have arrived by `now`
+    pub fn deliver<L: Listener>(&mut self, now: u32, listener: &mut L) {
+        while self.packets.front().is_some_and(|packet| packet.arrives <= now) {
+            let packet = self.packets.pop_front().unwrap();
+            if packet.address != listener.address() || packet.channel != listener.channel() {
+                continue;
+            }
+
+            self.delivered += 1;
+            if let Some(ack) = listener.receive(now, packet.payload) {
+                if !self.chance(self.config.ack_loss) {
+                    self.acks.push_back((now + self.config.latency, ack));
+                }
+            }
+        }
+    }
+
+    /// The acknowledgement payloads which have arrived back at the transmitter by `now`
+    pub fn acks(&mut self, now: u32) -> Vec<Payload> {
+        let mut acks = Vec::new();
+        while self.acks.front().is_some_and(|(arrives, _)| *arrives <= now) {
+            acks.push(self.acks.pop_front().unwrap().1);
+        }
+        acks
+    }
+}
@@ -0,0 +1,290 @@
+// A simulation of the whole link, from the transmitter's sticks to the SUMD,
+// or whichever protocol the receiver's switched to, going into the flight
+// controller, for testing on the host without any radios. Time is in µS.
+//
+// The transmitter sends every 10mS, the receiver's timer ticks every 10mS, and
+// its hop timer every 2.5mS, the same as the firmware. The receiver's timer
+// runs half a period out of step with the transmitter.
+
+pub mod air;
+pub mod receiver;
+pub mod transmitter;
+
+pub use air::{ Air, LinkConfig };
+pub use receiver::SimReceiver;
+pub use transmitter::SimTransmitter;
+
+use protocol::{ bind::BindInfo, Value, CHANNELS };
+use transmitter_core::Pairing;
+
+pub const PERIOD: u32 = 10_000;
+const STEP: u32 = 500;
+
+pub struct Simulation {
+    pub air: Air,
+    pub transmitter: SimTransmitter,
+    pub receiver: SimReceiver,
+    /// The stick positions the transmitter sends
+    pub values: [Value; CHANNELS],
+    pub now: u32,
+    decoder: sumd::Decoder,
+}
+
+impl Simulation {
+    pub fn new(config: LinkConfig, transmitter: SimTransmitter, receiver: SimReceiver) -> Self {
+        Simulation {
+            air: Air::new(config, 1),
+            transmitter,
+            receiver,
+            values: [0x8000; CHANNELS],
+            now: 0,
+            decoder: sumd::Decoder::new(),
+        }
+    }
+
+    /// A transmitter and receiver which were bound earlier
+    pub fn bound(config: LinkConfig, seed: u32) -> Self {
+        let info = BindInfo::generate([seed, !seed, seed.rotate_left(8), seed.rotate_right(8)]);
+        let pairing = Pairing { info, model_id: receiver::MODEL_ID };
+        Self::new(config, SimTransmitter::bound(&pairing, 1), SimReceiver::new(Some(info), 0))
+    }
+
+    /// Run for `duration` µS
+    pub fn run(&mut self, duration: u32) {
+        let end = self.now + duration;
+        while self.now < end {
+            self.now += STEP;
+            if self.now.is_multiple_of(PERIOD) {
+                self.transmitter.transmit(self.now, self.values, &mut self.air);
+            }
+
+            self.air.deliver(self.now, &mut self.receiver);
+
+            if self.now.is_multiple_of(PERIOD / 4) {
+                self.receiver.hop(self.now);
+            }
+
+            if self.now % PERIOD == PERIOD / 2 {
+                self.receiver.tick(self.now);
+            }
+        }
+    }
+
+    /// The SUMD frames the flight controller has received since last time
+    pub fn frames(&mut self) -> Vec<sumd::Frame> {
+        let mut frames = Vec::new();
+        for byte in self.receiver.take_output() {
+            match self.decoder.decode(byte) {
+                Ok(frame) => frames.push(frame),
+                Err(nb::Error::WouldBlock) => {},
+                Err(nb::Error::Other(error)) => panic!("bad SUMD from the receiver: {:?}", error),
+            }
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{ channels, failsafe::{ self, LinkState }, hop::HopState };
+    use receiver_core::output::Protocol;
+
+    const SECOND: u32 = 1_000_000;
+
+    // What comes out of the receiver, once the values have been packed
+    fn scaled(values: &[Value]) -> Vec<u16> {
+        values.iter().map(|value| sumd::scale(channels::quantize(*value))).collect()
+    }
+
+    // These first, and the rest centered
+    fn values(first: &[Value]) -> [Value; CHANNELS] {
+        let mut values = [0x8000; CHANNELS];
+        values[..first.len()].copy_from_slice(first);
+        values
+    }
+
+    fn assert_live(frames: &[sumd::Frame], values: &[Value]) {
+        assert!(!frames.is_empty());
+        for frame in frames {
+            assert_eq!(frame.status, sumd::Status::Live);
+            assert_eq!(&frame.values[..], &scaled(values)[..]);
+        }
+    }
+
+    #[test]
+    fn clean_link() {
+        let mut simulation = Simulation::bound(LinkConfig::default(), 1);
+        simulation.run(SECOND);
+        simulation.frames();
+
+        simulation.values = values(&[0, 0x4000, 0xc000, 0xffff]);
+        simulation.run(SECOND);
+        let frames = simulation.frames();
+        assert_eq!(frames.len(), 10);
+        assert_live(&frames[1..], &simulation.values);
+        assert_eq!(simulation.receiver.core.status.rejected_messages, 0);
+
+        // Telemetry comes back
+        assert_eq!(simulation.transmitter.telemetry().link.quality, 100);
+        assert!(simulation.transmitter.core.telemetry_age() < 2);
+    }
+
+    #[test]
+    fn full_stick_travel_is_full_sumd_travel() {
+        use transmitter_core::{ calibration::Axis, input::{ self, SWITCHES }, Calibration, Model, Position };
+
+        // Sticks and pots which don't reach the ends of the ADC's range
+        let calibration = Calibration { axes: [Axis { min: 300, center: 2000, max: 3800 }; input::INPUTS] };
+        let raw = [300, 2000, 3800, 3800, 2000, 300];
+        let mut simulation = Simulation::bound(LinkConfig::default(), 9);
+        simulation.values = transmitter_core::channels(&raw, &[Position::Up; SWITCHES], &calibration, &Model::new(0));
+        simulation.run(SECOND);
+
+        let frames = simulation.frames();
+        let last = frames.last().unwrap();
+        assert_eq!(last.status, sumd::Status::Live);
+        assert_eq!([last.values[0], last.values[2], last.values[3], last.values[5]],
+            [sumd::LOW, sumd::HIGH, sumd::HIGH, sumd::LOW]);
+        // Only the packing's rounding away from the center
+        for center in [last.values[1], last.values[4]] {
+            assert!((sumd::NEUTRAL - 2..=sumd::NEUTRAL + 2).contains(&center));
+        }
+    }
+
+    #[test]
+    fn lossy_link() {
+        let config = LinkConfig { loss: 300, ack_loss: 300, ..Default::default() };
+        let mut simulation = Simulation::bound(config, 2);
+        // Every channel, so the slow ones have to get through too
+        simulation.values = core::array::from_fn(|channel| channel as Value * 0x1111);
+        simulation.run(5 * SECOND);
+
+        assert_live(&simulation.frames()[5..], &simulation.values);
+        assert_eq!(simulation.receiver.core.hop_state().unwrap(), HopState::Synced);
+        let quality = simulation.transmitter.telemetry().link.quality;
+        assert!((55..85).contains(&quality), "quality {}", quality);
+    }
+
+    #[test]
+    fn latency() {
+        let config = LinkConfig { latency: 2_000, ..Default::default() };
+        let mut simulation = Simulation::bound(config, 3);
+        simulation.values = [0x1000; CHANNELS];
+        simulation.run(2 * SECOND);
+        assert_live(&simulation.frames()[5..], &simulation.values);
+        assert_eq!(simulation.transmitter.telemetry().link.quality, 100);
+    }
+
+    #[test]
+    fn failsafe_and_recovery() {
+        let mut simulation = Simulation::bound(LinkConfig::default(), 4);
+        simulation.values = [0xffff; CHANNELS];
+        simulation.run(SECOND);
+        simulation.frames();
+
+        // The transmitter goes out of range
+        simulation.air.config().loss = 1000;
+        simulation.run(3 * SECOND);
+        assert_eq!(simulation.receiver.core.failsafe().state(), LinkState::FailSafe);
+        assert_eq!(simulation.receiver.core.hop_state().unwrap(), HopState::Scanning);
+        let frames = simulation.frames();
+        let last = frames.last().unwrap();
+        assert_eq!(last.status, sumd::Status::FailSafe);
+        assert_eq!(&last.values[..], &scaled(&failsafe::Config::default().positions)[..]);
+        // Nobody set any failsafe positions, so the motors stop
+        assert_eq!(last.values[failsafe::THROTTLE], sumd::scale(0));
+
+        // And comes back
+        simulation.air.config().loss = 0;
+        simulation.run(5 * SECOND);
+        assert_eq!(simulation.receiver.core.failsafe().state(), LinkState::Live);
+        assert_live(&simulation.frames()[45..], &simulation.values);
+    }
+
+    #[test]
+    fn corruption_is_rejected() {
+        let config = LinkConfig { corruption: 200, ..Default::default() };
+        let mut simulation = Simulation::bound(config, 5);
+        simulation.values = values(&[0x1234, 0x2345, 0x3456, 0x4567]);
+        simulation.run(5 * SECOND);
+
+        // Corrupt messages never reach the flight controller
+        assert_live(&simulation.frames()[5..], &simulation.values);
+        assert!(simulation.receiver.core.status.rejected_messages > 50);
+    }
+
+    #[test]
+    fn bind_then_fly() {
+        let info = BindInfo::generate([5, 6, 7, 8]);
+        let mut simulation = Simulation::new(
+            LinkConfig { loss: 100, ..Default::default() },
+            SimTransmitter::binding(info),
+            SimReceiver::new(None, 0));
+        simulation.values = [0x6000; CHANNELS];
+        simulation.run(2 * SECOND);
+        assert!(simulation.transmitter.is_bound());
+        assert!(simulation.receiver.is_bound());
+
+        simulation.frames();
+        simulation.run(2 * SECOND);
+        assert_live(&simulation.frames()[5..], &simulation.values);
+    }
+
+    #[test]
+    fn wrong_model_never_arms() {
+        let info = BindInfo::generate([7, 8, 9, 10]);
+        let mut simulation = Simulation::new(
+            LinkConfig::default(),
+            SimTransmitter::bound(&Pairing { info, model_id: receiver::MODEL_ID + 1 }, 1),
+            SimReceiver::new(Some(info), 0));
+        simulation.values = [0xffff; CHANNELS];
+        simulation.run(2 * SECOND);
+
+        // The link is fine, but the receiver only has the failsafe positions
+        assert_eq!(simulation.transmitter.telemetry().link.quality, 100);
+        assert!(!simulation.transmitter.core.is_armed());
+        let frames = simulation.frames();
+        assert!(!frames.is_empty());
+        assert!(frames.iter().all(|frame| frame.status == sumd::Status::FailSafe));
+    }
+
+    #[test]
+    fn other_transmitter_is_ignored() {
+        let mut simulation = Simulation::bound(LinkConfig::default(), 6);
+        simulation.run(SECOND);
+
+        // Another transmitter, which has somehow learned our address and hop
+        // seed but not the key
+        let mut info = BindInfo::generate([6, !6, 6u32.rotate_left(8), 6u32.rotate_right(8)]);
+        info.key = [0; 16];
+        let mut intruder = SimTransmitter::bound(&Pairing { info, model_id: receiver::MODEL_ID }, 200);
+        let before = simulation.receiver.core.failsafe().values();
+        for _ in 0..100 {
+            simulation.now += PERIOD;
+            intruder.transmit(simulation.now, [0; CHANNELS], &mut simulation.air);
+            simulation.air.deliver(simulation.now, &mut simulation.receiver);
+        }
+        assert_eq!(simulation.receiver.core.failsafe().values(), before);
+        assert!(simulation.receiver.core.status.rejected_messages > 0);
+    }
+
+    #[test]
+    fn switching_protocols() {
+        let mut simulation = Simulation::bound(LinkConfig::default(), 7);
+        simulation.values = values(&[0, 0xffff]);
+        simulation.run(SECOND);
+        assert_live(&simulation.frames()[5..], &simulation.values);
+
+        simulation.receiver.set_output(Protocol::Ibus);
+        simulation.run(SECOND);
+        let output = simulation.receiver.take_output();
+        assert!(!output.is_empty());
+        assert_eq!(output.len() % ibus::FRAME_SIZE, 0);
+        for frame in output.chunks(ibus::FRAME_SIZE) {
+            assert_eq!(frame[..2], [0x20, 0x40]);
+            assert_eq!(u16::from_le_bytes([frame[2], frame[3]]), ibus::LOW);
+            assert_eq!(u16::from_le_bytes([frame[4], frame[5]]), ibus::HIGH);
+        }
+    }
+}
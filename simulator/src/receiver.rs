@@ -0,0 +1,138 @@
+// The receiver's side of the link: the same receiver-core the firmware runs,
+// with a radio which the air delivers to. The output, SUMD unless it's
+// switched, goes to a UART which just collects the bytes.
+
+use std::{ collections::VecDeque, convert::Infallible };
+
+use embedded_hal::serial::Write;
+
+use protocol::{
+    bind::{ BindInfo, ModelId, RxBindState },
+    hop,
+};
+use rc_output::{ Driver, Link };
+use receiver_core::{ output::Protocol, Radio, Receiver };
+
+use crate::air::{ Listener, Payload };
+
+/// Time is in µS, and the transmitter sends a message every 10mS
+pub const HOP_CONFIG: hop::Config = hop::Config { period: 10_000, lost: 10 };
+
+/// Every simulated receiver is in the same model
+pub const MODEL_ID: ModelId = 7;
+
+/// The flight controller's end of the serial port
+#[derive(Default)]
+pub struct Uart(pub Vec<u8>);
+
+impl Write<u8> for Uart {
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.0.push(word);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The receiver's nRF24, as far as receiver-core can tell
+#[derive(Default)]
+pub struct SimRadio {
+    received: VecDeque<Payload>,
+    // Loaded for the next acknowledgement
+    ack: Option<Payload>,
+    channel: u8,
+    address: [u8; 5],
+}
+
+impl Radio for SimRadio {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<Payload, Infallible> {
+        self.received.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+
+    fn write_ack(&mut self, payload: &[u8]) -> Result<(), Infallible> {
+        let mut ack = [0; protocol::PAYLOAD_SIZE];
+        ack[..payload.len()].copy_from_slice(payload);
+        self.ack = Some(ack);
+        Ok(())
+    }
+
+    fn set_channel(&mut self, channel: u8) -> Result<(), Infallible> {
+        self.channel = channel;
+        Ok(())
+    }
+
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), Infallible> {
+        self.address = *address;
+        Ok(())
+    }
+}
+
+pub struct SimReceiver {
+    pub core: Receiver<SimRadio>,
+    pub flight_controller: Driver<Uart, Protocol>,
+}
+
+impl SimReceiver {
+    /// A receiver starting up, with the bind info from flash, if there is any
+    pub fn new(stored: Option<BindInfo>, now: u32) -> Self {
+        SimReceiver {
+            core: Receiver::new(Ok(SimRadio::default()), stored, 0, MODEL_ID, HOP_CONFIG, now),
+            flight_controller: Driver::new(Uart::default(), Protocol::default()),
+        }
+    }
+
+    /// Switch the flight controller to another protocol, from the next frame
+    pub fn set_output(&mut self, protocol: Protocol) {
+        self.flight_controller.set_output(protocol);
+    }
+
+    /// The bytes sent to the flight controller so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.flight_controller.writer().0)
+    }
+
+    /// The hop timer, several times a message period
+    pub fn hop(&mut self, now: u32) {
+        self.core.hop(now);
+    }
+
+    /// The 100Hz timer
+    pub fn tick(&mut self, now: u32) {
+        if let Some((state, values)) = self.core.tick(now, || 5000).send {
+            let link = Link::new(state, self.core.link_quality());
+            let _ = self.flight_controller.send(link, &values);
+        }
+    }
+
+    pub fn is_bound(&self) -> bool {
+        matches!(self.core.bind_state(), RxBindState::Bound(_))
+    }
+
+    fn radio(&mut self) -> &mut SimRadio {
+        self.core.radio_mut().unwrap()
+    }
+}
+
+impl Listener for SimReceiver {
+    fn address(&self) -> [u8; 5] {
+        self.core.radio().unwrap().address
+    }
+
+    fn channel(&self) -> u8 {
+        self.core.radio().unwrap().channel
+    }
+
+    fn receive(&mut self, now: u32, payload: Payload) -> Option<Payload> {
+        // The nRF24 acknowledges with whatever was loaded before this arrived
+        let ack = self.radio().ack.take();
+        self.radio().received.push_back(payload);
+        self.core.receive(now);
+        ack
+    }
+}
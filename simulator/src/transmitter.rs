@@ -0,0 +1,69 @@
+// The transmitter's side of the link: the same transmitter-core the firmware
+// runs, with a link which sends into the simulated air.
+
+use std::{ collections::VecDeque, convert::Infallible };
+
+use protocol::{ bind::BindInfo, telemetry::Telemetry, Value, CHANNELS };
+use transmitter_core::{ Link, Pairing, Payload, Transmitter };
+
+use crate::air::Air;
+
+/// The transmitter's nRF24, as far as transmitter-core can tell. What it
+/// sends waits here until the simulation puts it on the air.
+#[derive(Default)]
+pub struct SimLink {
+    address: [u8; 5],
+    sent: Vec<(u8, Vec<u8>)>,
+    acks: VecDeque<Payload>,
+}
+
+impl Link for SimLink {
+    type Error = Infallible;
+
+    fn send(&mut self, channel: u8, payload: &[u8]) -> Result<Option<Payload>, Infallible> {
+        self.sent.push((channel, payload.to_vec()));
+        Ok(self.acks.pop_front())
+    }
+
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), Infallible> {
+        self.address = *address;
+        Ok(())
+    }
+}
+
+pub struct SimTransmitter {
+    pub core: Transmitter<SimLink>,
+}
+
+impl SimTransmitter {
+    /// A transmitter which is already bound, starting its `session`th session
+    pub fn bound(pairing: &Pairing, session: u8) -> Self {
+        SimTransmitter { core: Transmitter::bound(SimLink::default(), pairing, session) }
+    }
+
+    /// A transmitter with the bind button held down
+    pub fn binding(info: BindInfo) -> Self {
+        SimTransmitter { core: Transmitter::binding(SimLink::default(), info) }
+    }
+
+    pub fn is_bound(&self) -> bool {
+        self.core.is_bound()
+    }
+
+    pub fn telemetry(&self) -> &Telemetry {
+        self.core.telemetry()
+    }
+
+    pub fn transmit(&mut self, now: u32, values: [Value; CHANNELS], air: &mut Air) {
+        // The acknowledgements to earlier messages, which have taken this long to arrive
+        let link = self.core.link_mut();
+        link.acks.extend(air.acks(now));
+
+        let _ = self.core.transmit(values);
+
+        let link = self.core.link_mut();
+        for (channel, payload) in link.sent.drain(..) {
+            air.send(now, link.address, channel, &payload);
+        }
+    }
+}
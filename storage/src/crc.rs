@@ -0,0 +1,47 @@
+// CRC-16/CCITT, the same as SUMD uses, which is plenty for records of a few
+// hundred bytes.
+
+const POLYNOMIAL: u16 = 0x1021;
+
+pub struct Crc16(u16);
+
+impl Crc16 {
+    pub fn new() -> Self {
+        Crc16(0xffff)
+    }
+
+    pub fn update(&mut self, byte: u8) {
+        let mut crc = self.0 ^ (byte as u16) << 8;
+        for _ in 0..8 {
+            if (crc & 0x8000) != 0 {
+                crc = (crc << 1) ^ POLYNOMIAL;
+            } else {
+                crc <<= 1;
+            }
+        }
+        self.0 = crc;
+    }
+
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = Crc16::new();
+    for byte in bytes {
+        crc.update(*byte);
+    }
+    crc.value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        // CRC-16/CCITT-FALSE
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+}
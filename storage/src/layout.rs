@@ -0,0 +1,28 @@
+// Where the store lives in each chip's flash. Offsets are from the start of
+// flash, and each chip's memory.x keeps the program clear of them.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub start: u32,
+    /// The size of the smallest erasable unit, a page or a sector
+    pub page_size: u32,
+    /// Writes are spread over this many pages. At least two.
+    pub pages: u32,
+}
+
+impl Layout {
+    pub const fn end(&self) -> u32 {
+        self.start + self.page_size * self.pages
+    }
+}
+
+/// STM32F103x8: 64K in 1K pages, of which the store has the last 8K. The
+/// store's pages are four flash pages each, so every model memory fits in one.
+pub const STM32F103X8: Layout = Layout { start: 56 * 1024, page_size: 4096, pages: 2 };
+
+/// STM32F030x4: 16K in 1K pages, of which the store has the last 2K
+pub const STM32F030X4: Layout = Layout { start: 14 * 1024, page_size: 1024, pages: 2 };
+
+/// STM32F411xE: 512K in sectors of 16K, 64K and 128K. The sectors have to be
+/// the same size, so the store has the last two, 6 and 7.
+pub const STM32F411XE: Layout = Layout { start: 0x4_0000, page_size: 128 * 1024, pages: 2 };
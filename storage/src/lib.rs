@@ -0,0 +1,537 @@
+// Settings which survive a power cycle, kept in the microcontroller's own
+// flash.
+//
+// Each setting is a record: its key and version, its value serialized with
+// postcard, and a CRC. Saving a setting appends a record to the page being
+// written, and loading it finds the last good one. When the page is full, the
+// latest record for each key is copied to the next page, and the one after
+// that is saved there, so the writes go round all the pages in turn.
+//
+// Power can fail at any time. A record which was being written when it did
+// fails its CRC, so the one before it is used instead. A page is only in use
+// once its header is written, after everything has been copied to it, so
+// losing power while moving to a new page leaves the old one in charge.
+//
+// A page starts with its header:
+//
+//     magic: u16, sequence: u32, crc: u16
+//
+// and the highest sequence number is the page in use. Then the records:
+//
+//     id: u8, version: u8, length: u16, value: [u8; length], crc: u16
+//
+// each padded to the flash's write size, and then erased flash.
+//
+// It's generic over embedded-storage's NorFlash, which the firmware
+// implements for each chip, and the tests for RAM.
+#![no_std]
+
+mod crc;
+pub mod layout;
+#[cfg(test)]
+mod ram;
+
+use embedded_storage::nor_flash::NorFlash;
+use serde::{ de::DeserializeOwned, Serialize };
+
+use crc::{ crc16, Crc16 };
+pub use layout::Layout;
+
+/// The largest serialized value which can be saved
+pub const MAX_SIZE: usize = 512;
+/// Ids run from 0 to this
+pub const MAX_KEYS: usize = 32;
+
+const MAGIC: u16 = 0x5243;
+const PAGE_HEADER: usize = 8;
+const RECORD_HEADER: usize = 4;
+const CRC_SIZE: usize = 2;
+const ERASED: u8 = 0xff;
+// Writes and reads are aligned to at most this
+const MAX_ALIGN: usize = 16;
+const BUFFER_SIZE: usize = RECORD_HEADER + MAX_SIZE + CRC_SIZE + MAX_ALIGN;
+// Records are checked, compared and copied this much at a time, so that only
+// the value being saved or loaded needs a buffer its size
+const CHUNK_SIZE: usize = 2 * MAX_ALIGN;
+
+/// The most flash a value of `length` bytes takes, for working out whether
+/// everything will fit in a page
+pub const fn record_size(length: usize) -> usize {
+    (RECORD_HEADER + length + CRC_SIZE).div_ceil(MAX_ALIGN) * MAX_ALIGN
+}
+
+/// The room for records in each page, which has to hold the latest of each
+pub const fn capacity(layout: &Layout) -> usize {
+    layout.page_size as usize - MAX_ALIGN
+}
+
+/// Identifies a setting. Change the version whenever its type changes, so
+/// an old value isn't misread: it'll load as nothing instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key {
+    id: u8,
+    version: u8,
+}
+
+impl Key {
+    pub const fn new(id: u8, version: u8) -> Self {
+        assert!((id as usize) < MAX_KEYS);
+        Key { id, version }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error<E> {
+    Flash(E),
+    /// The value serializes to more than MAX_SIZE
+    TooBig,
+    /// The latest records don't fit in a page
+    Full,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::Flash(error)
+    }
+}
+
+// What's at an offset in a page
+enum Entry {
+    Record { id: u8, version: u8, length: usize, size: u32, valid: bool },
+    // Erased flash, or the end of the page
+    End,
+    // Something which can't be a record, so the rest of the page can't be trusted
+    Garbage,
+}
+
+// The offset, version and length of the last good record with each id
+type Latest = [Option<(u32, u8, usize)>; MAX_KEYS];
+
+pub struct Store<F> {
+    flash: F,
+    layout: Layout,
+    // The page in use, if any, and its sequence number
+    page: Option<u32>,
+    sequence: u32,
+    // Where the next record goes in the page
+    free: u32,
+    // Something half written is in the way, so the next save moves to a new page
+    dirty: bool,
+}
+
+impl<F: NorFlash> Store<F> {
+    pub fn new(flash: F, layout: Layout) -> Result<Self, Error<F::Error>> {
+        assert!(layout.pages >= 2 && (layout.page_size as usize).is_multiple_of(F::ERASE_SIZE));
+        assert!(Self::align(1) <= MAX_ALIGN);
+
+        let mut store = Store { flash, layout, page: None, sequence: 0, free: 0, dirty: true };
+        for page in 0..layout.pages {
+            if let Some(sequence) = store.sequence(page)? {
+                if store.page.is_none() || sequence.wrapping_sub(store.sequence) as i32 > 0 {
+                    store.page = Some(page);
+                    store.sequence = sequence;
+                }
+            }
+        }
+
+        if let Some(page) = store.page {
+            store.mount(page)?;
+        }
+        Ok(store)
+    }
+
+    /// Give back the flash
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// The latest value saved with this key, or nothing if there isn't one,
+    /// or it's an older version, or it's unreadable
+    pub fn load<T: DeserializeOwned>(&mut self, key: Key) -> Option<T> {
+        let page = self.page?;
+        let (offset, version, length) = self.latest(page).ok()?[key.id as usize]?;
+        if version != key.version {
+            return None;
+        }
+
+        let mut buffer = [0; BUFFER_SIZE];
+        let size = Self::align(RECORD_HEADER + length + CRC_SIZE);
+        self.flash.read(self.address(page, offset), &mut buffer[..size]).ok()?;
+        postcard::from_bytes(&buffer[RECORD_HEADER..RECORD_HEADER + length]).ok()
+    }
+
+    pub fn load_or_default<T: DeserializeOwned + Default>(&mut self, key: Key) -> T {
+        self.load(key).unwrap_or_default()
+    }
+
+    pub fn save<T: Serialize>(&mut self, key: Key, value: &T) -> Result<(), Error<F::Error>> {
+        let mut record = [ERASED; BUFFER_SIZE];
+        let length = postcard::to_slice(value, &mut record[RECORD_HEADER..RECORD_HEADER + MAX_SIZE])
+            .map_err(|_| Error::TooBig)?
+            .len();
+        record[0] = key.id;
+        record[1] = key.version;
+        record[2..RECORD_HEADER].copy_from_slice(&(length as u16).to_le_bytes());
+        let crc = crc16(&record[..RECORD_HEADER + length]);
+        record[RECORD_HEADER + length..RECORD_HEADER + length + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+        let size = Self::align(RECORD_HEADER + length + CRC_SIZE);
+
+        let page = match self.page {
+            Some(page) if !self.dirty => page,
+            _ => return self.compact(Some((key.id, &record[..size]))),
+        };
+
+        // Flash wears out, so don't write what's already there
+        if let Some((offset, _, _)) = self.latest(page)?[key.id as usize] {
+            let mut same = true;
+            self.read_chunks(page, offset, size, |position, byte| same &= byte == record[position])?;
+            if same {
+                return Ok(());
+            }
+        }
+
+        if self.free as usize + size > self.layout.page_size as usize {
+            return self.compact(Some((key.id, &record[..size])));
+        }
+
+        let offset = self.free;
+        // Even if this fails, it's in the way
+        self.free += size as u32;
+        self.flash.write(self.address(page, offset), &record[..size])?;
+        Ok(())
+    }
+
+    fn align(size: usize) -> usize {
+        let align = F::WRITE_SIZE.max(F::READ_SIZE).max(1);
+        size.div_ceil(align) * align
+    }
+
+    fn address(&self, page: u32, offset: u32) -> u32 {
+        self.layout.start + page * self.layout.page_size + offset
+    }
+
+    fn header_size() -> u32 {
+        Self::align(PAGE_HEADER) as u32
+    }
+
+    // The page's sequence number, if it has a good header
+    fn sequence(&mut self, page: u32) -> Result<Option<u32>, F::Error> {
+        let mut header = [0; PAGE_HEADER + MAX_ALIGN];
+        let size = Self::header_size() as usize;
+        self.flash.read(self.address(page, 0), &mut header[..size])?;
+        let magic = u16::from_le_bytes([header[0], header[1]]);
+        let crc = u16::from_le_bytes([header[6], header[7]]);
+        if magic != MAGIC || crc != crc16(&header[..6]) {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes([header[2], header[3], header[4], header[5]])))
+    }
+
+    // Find where the next record goes, and whether the rest of the page is
+    // really erased
+    fn mount(&mut self, page: u32) -> Result<(), F::Error> {
+        let mut offset = Self::header_size();
+        loop {
+            match self.entry(page, offset)? {
+                Entry::Record { size, .. } => offset += size,
+                Entry::End => break,
+                Entry::Garbage => {
+                    self.free = self.layout.page_size;
+                    self.dirty = true;
+                    return Ok(());
+                },
+            }
+        }
+
+        self.free = offset;
+        let mut erased = true;
+        self.read_chunks(page, offset, (self.layout.page_size - offset) as usize, |_, byte| erased &= byte == ERASED)?;
+        self.dirty = !erased;
+        Ok(())
+    }
+
+    // Pass each of `size` bytes from `offset` in the page to `f`, with its
+    // position, reading them a chunk at a time
+    fn read_chunks<G: FnMut(usize, u8)>(&mut self, page: u32, offset: u32, size: usize, mut f: G)
+        -> Result<(), F::Error> {
+        let mut chunk = [0; CHUNK_SIZE];
+        let mut done = 0;
+        while done < size {
+            let length = (size - done).min(CHUNK_SIZE);
+            self.flash.read(self.address(page, offset + done as u32), &mut chunk[..length])?;
+            for (i, byte) in chunk[..length].iter().enumerate() {
+                f(done + i, *byte);
+            }
+            done += length;
+        }
+        Ok(())
+    }
+
+    fn entry(&mut self, page: u32, offset: u32) -> Result<Entry, F::Error> {
+        let header = Self::align(RECORD_HEADER);
+        if offset as usize + header > self.layout.page_size as usize {
+            return Ok(Entry::End);
+        }
+
+        let mut buffer = [0; MAX_ALIGN];
+        self.flash.read(self.address(page, offset), &mut buffer[..header])?;
+        if buffer[..RECORD_HEADER].iter().all(|byte| *byte == ERASED) {
+            return Ok(Entry::End);
+        }
+
+        let (id, version) = (buffer[0], buffer[1]);
+        let length = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+        let size = Self::align(RECORD_HEADER + length + CRC_SIZE);
+        if length > MAX_SIZE || offset as usize + size > self.layout.page_size as usize {
+            return Ok(Entry::Garbage);
+        }
+
+        let valid = self.check_record(page, offset, length)? && (id as usize) < MAX_KEYS;
+        Ok(Entry::Record { id, version, length, size: size as u32, valid })
+    }
+
+    // Check the record's CRC
+    fn check_record(&mut self, page: u32, offset: u32, length: usize) -> Result<bool, F::Error> {
+        let end = RECORD_HEADER + length;
+        let mut crc = Crc16::new();
+        let mut stored = [0; CRC_SIZE];
+        self.read_chunks(page, offset, Self::align(end + CRC_SIZE), |position, byte| {
+            if position < end {
+                crc.update(byte);
+            } else if position < end + CRC_SIZE {
+                stored[position - end] = byte;
+            }
+        })?;
+        Ok(crc.value() == u16::from_le_bytes(stored))
+    }
+
+    fn latest(&mut self, page: u32) -> Result<Latest, F::Error> {
+        let mut latest = [None; MAX_KEYS];
+        let mut offset = Self::header_size();
+        while let Entry::Record { id, version, length, size, valid } = self.entry(page, offset)? {
+            if valid {
+                latest[id as usize] = Some((offset, version, length));
+            }
+            offset += size;
+        }
+        Ok(latest)
+    }
+
+    // Move to the next page, with the latest record for each id, replacing
+    // one with `record` if there is one
+    fn compact(&mut self, record: Option<(u8, &[u8])>) -> Result<(), Error<F::Error>> {
+        let target = match self.page {
+            Some(page) => (page + 1) % self.layout.pages,
+            None => 0,
+        };
+        let start = self.address(target, 0);
+        self.flash.erase(start, start + self.layout.page_size)?;
+
+        let mut chunk = [0; CHUNK_SIZE];
+        let mut offset = Self::header_size();
+        if let Some(page) = self.page {
+            let latest = self.latest(page)?;
+            for (id, latest) in latest.iter().enumerate() {
+                match (latest, record) {
+                    (Some(_), Some((replaced, _))) if replaced as usize == id => {},
+                    (Some((from, _, length)), _) => {
+                        let size = Self::align(RECORD_HEADER + length + CRC_SIZE);
+                        if offset as usize + size > self.layout.page_size as usize {
+                            return Err(Error::Full);
+                        }
+                        for start in (0..size as u32).step_by(CHUNK_SIZE) {
+                            let length = (size - start as usize).min(CHUNK_SIZE);
+                            self.flash.read(self.address(page, from + start), &mut chunk[..length])?;
+                            self.flash.write(self.address(target, offset + start), &chunk[..length])?;
+                        }
+                        offset += size as u32;
+                    },
+                    (None, _) => {},
+                }
+            }
+        }
+
+        if let Some((_, record)) = record {
+            if offset as usize + record.len() > self.layout.page_size as usize {
+                return Err(Error::Full);
+            }
+            self.flash.write(self.address(target, offset), record)?;
+            offset += record.len() as u32;
+        }
+
+        // Only now is the new page in use
+        let sequence = self.sequence.wrapping_add(1);
+        let mut header = [ERASED; PAGE_HEADER + MAX_ALIGN];
+        header[..2].copy_from_slice(&MAGIC.to_le_bytes());
+        header[2..6].copy_from_slice(&sequence.to_le_bytes());
+        let crc = crc16(&header[..6]);
+        header[6..8].copy_from_slice(&crc.to_le_bytes());
+        self.flash.write(start, &header[..Self::header_size() as usize])?;
+
+        self.page = Some(target);
+        self.sequence = sequence;
+        self.free = offset;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{ Deserialize, Serialize };
+
+    use super::*;
+    use ram::{ PowerLoss, RamFlash };
+
+    const LAYOUT: Layout = Layout { start: 0, page_size: ram::PAGE_SIZE as u32, pages: 3 };
+
+    #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+    struct Settings {
+        count: u32,
+        name: [u8; 8],
+    }
+
+    const SETTINGS: Key = Key::new(1, 1);
+    const OTHER: Key = Key::new(2, 1);
+
+    fn settings(count: u32) -> Settings {
+        Settings { count, name: *b"settings" }
+    }
+
+    fn store() -> Store<RamFlash> {
+        Store::new(RamFlash::new(LAYOUT.pages as usize), LAYOUT).unwrap()
+    }
+
+    fn power_cycle(store: Store<RamFlash>) -> Store<RamFlash> {
+        let mut flash = store.release();
+        flash.power_on();
+        Store::new(flash, LAYOUT).unwrap()
+    }
+
+    #[test]
+    fn empty() {
+        let mut store = store();
+        assert_eq!(store.load::<Settings>(SETTINGS), None);
+        assert_eq!(store.load_or_default::<Settings>(SETTINGS), Settings::default());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut store = store();
+        store.save(SETTINGS, &settings(1)).unwrap();
+        store.save(OTHER, &42u8).unwrap();
+        store.save(SETTINGS, &settings(2)).unwrap();
+        assert_eq!(store.load(SETTINGS), Some(settings(2)));
+        assert_eq!(store.load(OTHER), Some(42u8));
+
+        let mut store = power_cycle(store);
+        assert_eq!(store.load(SETTINGS), Some(settings(2)));
+        assert_eq!(store.load(OTHER), Some(42u8));
+    }
+
+    #[test]
+    fn old_versions_are_ignored() {
+        let mut store = store();
+        store.save(SETTINGS, &settings(1)).unwrap();
+        assert_eq!(store.load::<Settings>(Key::new(1, 2)), None);
+    }
+
+    #[test]
+    fn wear_is_levelled() {
+        let mut store = store();
+        store.save(OTHER, &42u8).unwrap();
+        for count in 0..1000 {
+            store.save(SETTINGS, &settings(count)).unwrap();
+            assert_eq!(store.load(SETTINGS), Some(settings(count)));
+        }
+        assert_eq!(store.load(OTHER), Some(42u8));
+
+        let erases = store.release().erases;
+        let (least, most) = (erases.iter().min().unwrap(), erases.iter().max().unwrap());
+        assert!(*least > 10 && most - least <= 1);
+    }
+
+    #[test]
+    fn unchanged_values_are_not_written() {
+        let mut store = store();
+        store.save(SETTINGS, &settings(1)).unwrap();
+        let free = store.free;
+        store.save(SETTINGS, &settings(1)).unwrap();
+        assert_eq!(store.free, free);
+    }
+
+    #[test]
+    fn too_big() {
+        let mut store = store();
+        assert_eq!(store.save(SETTINGS, &[[0u8; 32]; 17]), Err(Error::TooBig));
+    }
+
+    #[test]
+    fn corruption_falls_back() {
+        let mut store = store();
+        store.save(SETTINGS, &settings(1)).unwrap();
+        store.save(SETTINGS, &settings(2)).unwrap();
+
+        // Flip a bit in the value in the last record, on the first page
+        let free = store.free as usize;
+        let mut flash = store.release();
+        flash.data[free - 4] ^= 1;
+        let mut store = Store::new(flash, LAYOUT).unwrap();
+        assert_eq!(store.load(SETTINGS), Some(settings(1)));
+
+        // And it carries on working
+        store.save(SETTINGS, &settings(3)).unwrap();
+        assert_eq!(power_cycle(store).load(SETTINGS), Some(settings(3)));
+    }
+
+    #[test]
+    fn garbage_is_survived() {
+        let mut flash = RamFlash::new(LAYOUT.pages as usize);
+        for (i, byte) in flash.data.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        let mut store = Store::new(flash, LAYOUT).unwrap();
+        assert_eq!(store.load::<Settings>(SETTINGS), None);
+        store.save(SETTINGS, &settings(1)).unwrap();
+        assert_eq!(power_cycle(store).load(SETTINGS), Some(settings(1)));
+    }
+
+    // Lose power at every step of saving, including moving to a new page,
+    // and check the old value or the new one is always there afterwards
+    #[test]
+    fn power_loss() {
+        for saved in 0..40 {
+            for step in 0.. {
+                let mut store = store();
+                store.save(OTHER, &42u8).unwrap();
+                for count in 0..saved {
+                    store.save(SETTINGS, &settings(count)).unwrap();
+                }
+
+                let mut flash = store.release();
+                flash.power_loss = Some(PowerLoss { after: step });
+                let mut store = Store::new(flash, LAYOUT).unwrap();
+                let finished = store.save(SETTINGS, &settings(saved)).is_ok();
+
+                let mut store = power_cycle(store);
+                let loaded = store.load::<Settings>(SETTINGS);
+                if finished {
+                    assert_eq!(loaded, Some(settings(saved)));
+                } else if saved == 0 {
+                    assert!(loaded.is_none() || loaded == Some(settings(0)));
+                } else {
+                    assert!(loaded == Some(settings(saved - 1)) || loaded == Some(settings(saved)));
+                }
+                assert_eq!(store.load(OTHER), Some(42u8));
+
+                // And it carries on working
+                store.save(SETTINGS, &settings(1000)).unwrap();
+                assert_eq!(power_cycle(store).load(SETTINGS), Some(settings(1000)));
+
+                if finished {
+                    break;
+                }
+            }
+        }
+    }
+}
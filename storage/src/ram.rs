@@ -0,0 +1,118 @@
+// Flash simulated in RAM for the tests, which behaves like the real thing:
+// writes only go to erased flash, and power can be lost part way through.
+extern crate std;
+
+use std::{ vec, vec::Vec };
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+pub const PAGE_SIZE: usize = 256;
+const WORD: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerLost;
+
+impl NorFlashError for PowerLost {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+/// Power is lost after this many more words written or pages erased
+pub struct PowerLoss {
+    pub after: usize,
+}
+
+pub struct RamFlash {
+    pub data: Vec<u8>,
+    pub erases: Vec<u32>,
+    pub power_loss: Option<PowerLoss>,
+    powered: bool,
+}
+
+impl RamFlash {
+    pub fn new(pages: usize) -> Self {
+        RamFlash { data: vec![0xff; pages * PAGE_SIZE], erases: vec![0; pages], power_loss: None, powered: true }
+    }
+
+    pub fn power_on(&mut self) {
+        self.power_loss = None;
+        self.powered = true;
+    }
+
+    // Whether the next operation is interrupted
+    fn step(&mut self) -> Result<bool, PowerLost> {
+        if !self.powered {
+            return Err(PowerLost);
+        }
+        match &mut self.power_loss {
+            Some(PowerLoss { after: 0 }) => {
+                self.powered = false;
+                Ok(true)
+            },
+            Some(PowerLoss { after }) => {
+                *after -= 1;
+                Ok(false)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = PowerLost;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerLost> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = WORD;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerLost> {
+        let (from, to) = (from as usize, to as usize);
+        assert!(from.is_multiple_of(PAGE_SIZE) && to.is_multiple_of(PAGE_SIZE));
+        for page in (from..to).step_by(PAGE_SIZE) {
+            // Only half erased
+            if self.step()? {
+                self.data[page..page + PAGE_SIZE / 2].fill(0xff);
+                return Err(PowerLost);
+            }
+            self.data[page..page + PAGE_SIZE].fill(0xff);
+            self.erases[page / PAGE_SIZE] += 1;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerLost> {
+        let offset = offset as usize;
+        assert!(offset.is_multiple_of(WORD) && bytes.len().is_multiple_of(WORD));
+        for (i, word) in bytes.chunks(WORD).enumerate() {
+            let at = offset + i * WORD;
+            assert!(self.data[at..at + WORD].iter().all(|byte| *byte == 0xff), "writing unerased flash at {}", at);
+            // Only some of the bits written
+            if self.step()? {
+                for (byte, written) in self.data[at..at + WORD].iter_mut().zip(word) {
+                    *byte = written | 0x5a;
+                }
+                return Err(PowerLost);
+            }
+            self.data[at..at + WORD].copy_from_slice(word);
+        }
+        Ok(())
+    }
+}
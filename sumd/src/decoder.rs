@@ -0,0 +1,269 @@
+// A streaming decoder for SUMD frames, fed one byte at a time, e.g. from a
+// serial receive interrupt or from a radio payload.
+
+use core::convert::TryFrom;
+
+use heapless::Vec;
+
+use crate::{ Crc16, Status, MAX_CHANNELS, VENDOR_ID };
+
+/// A complete frame whose CRC has been checked.
+/// The values are exactly as they were on the wire, i.e. in the range
+/// `EXTENDED_LOW..=EXTENDED_HIGH` for a well behaved transmitter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub status: Status,
+    pub values: Vec<u16, MAX_CHANNELS>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The byte following the vendor id wasn't a known status
+    Status(u8),
+    /// The channel count wasn't in the range 1 to 32
+    ChannelCount(u8),
+    /// The CRC trailer didn't match the CRC of the rest of the frame
+    Crc { expected: u16, received: u16 },
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    VendorId,
+    Status,
+    ChannelCount,
+    // The high byte of a value, if it's been received
+    Value(Option<u8>),
+    // The high byte of the CRC, if it's been received
+    Crc(Option<u8>),
+}
+
+pub struct Decoder {
+    state: State,
+    crc: Crc16,
+    status: Status,
+    channels: usize,
+    values: Vec<u16, MAX_CHANNELS>,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            state: State::VendorId,
+            crc: Crc16(0),
+            status: Status::Live,
+            channels: 0,
+            values: Vec::new(),
+        }
+    }
+
+    /// Discard any partially decoded frame and wait for the next vendor id.
+    pub fn reset(&mut self) {
+        self.state = State::VendorId;
+    }
+
+    /// Feed the next byte of the stream to the decoder.
+    ///
+    /// Returns `WouldBlock` until a whole frame has been received. Bytes
+    /// are discarded until the vendor id is seen, so the decoder can be
+    /// started at any point in a stream and resynchronises after an error.
+    pub fn decode(&mut self, byte: u8) -> nb::Result<Frame, Error> {
+        match self.state {
+            State::VendorId => {
+                if byte == VENDOR_ID {
+                    self.start();
+                }
+            },
+
+            State::Status => {
+                match Status::try_from(byte) {
+                    Ok(status) => {
+                        self.crc.update(byte);
+                        self.status = status;
+                        self.state = State::ChannelCount;
+                    },
+                    // The vendor id we saw was noise, but this might be a real one
+                    Err(VENDOR_ID) => self.start(),
+                    Err(byte) => return self.fail(Error::Status(byte)),
+                }
+            },
+
+            State::ChannelCount => {
+                if byte == 0 || byte as usize > MAX_CHANNELS {
+                    let error = self.fail(Error::ChannelCount(byte));
+                    // The frame was cut short, and this might be the start of the next
+                    if byte == VENDOR_ID {
+                        self.start();
+                    }
+                    return error;
+                }
+                self.crc.update(byte);
+                self.channels = byte as usize;
+                self.state = State::Value(None);
+            },
+
+            State::Value(None) => {
+                self.crc.update(byte);
+                self.state = State::Value(Some(byte));
+            },
+
+            State::Value(Some(high)) => {
+                self.crc.update(byte);
+                // Can't overflow: the channel count has already been checked
+                let _ = self.values.push(u16::from_be_bytes([high, byte]));
+                self.state = if self.values.len() == self.channels {
+                    State::Crc(None)
+                } else {
+                    State::Value(None)
+                };
+            },
+
+            State::Crc(None) => {
+                self.state = State::Crc(Some(byte));
+            },
+
+            State::Crc(Some(high)) => {
+                self.state = State::VendorId;
+                let received = u16::from_be_bytes([high, byte]);
+                if received != self.crc.0 {
+                    return Err(nb::Error::Other(Error::Crc { expected: self.crc.0, received }));
+                }
+
+                return Ok(Frame {
+                    status: self.status,
+                    values: self.values.clone(),
+                });
+            },
+        }
+
+        Err(nb::Error::WouldBlock)
+    }
+
+    fn start(&mut self) {
+        self.crc = Crc16(0);
+        self.crc.update(VENDOR_ID);
+        self.values.clear();
+        self.state = State::Status;
+    }
+
+    fn fail(&mut self, error: Error) -> nb::Result<Frame, Error> {
+        self.state = State::VendorId;
+        Err(nb::Error::Other(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ scale, SumdBuffer };
+
+    fn decode_all(decoder: &mut Decoder, bytes: &[u8]) -> Option<Result<Frame, Error>> {
+        let mut result = None;
+        for byte in bytes {
+            match decoder.decode(*byte) {
+                Ok(frame) => result = Some(Ok(frame)),
+                Err(nb::Error::Other(error)) => result = Some(Err(error)),
+                Err(nb::Error::WouldBlock) => {},
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = [0, 0x8000, 0xffff, 0x1234];
+        let mut buffer = SumdBuffer::new();
+        buffer.encode(Status::FailSafe, &values);
+
+        let frame = decode_all(&mut Decoder::new(), &buffer.0).unwrap().unwrap();
+        assert_eq!(frame.status, Status::FailSafe);
+        assert_eq!(&frame.values[..], &values.iter().map(|v| scale(*v)).collect::<Vec<u16, 4>>()[..]);
+    }
+
+    #[test]
+    fn round_trip_all_channels() {
+        let values = [0x4000; MAX_CHANNELS];
+        let mut buffer = SumdBuffer::new();
+        buffer.encode(Status::Live, &values);
+
+        let frame = decode_all(&mut Decoder::new(), &buffer.0).unwrap().unwrap();
+        assert_eq!(frame.status, Status::Live);
+        assert_eq!(frame.values.len(), MAX_CHANNELS);
+    }
+
+    #[test]
+    fn encoder_link_states() {
+        use rc_output::{ Link, LinkState, RcOutput };
+
+        for (state, status) in [(LinkState::Live, Status::Live), (LinkState::Hold, Status::Live), (LinkState::FailSafe, Status::FailSafe)] {
+            let mut buffer = SumdBuffer::new();
+            let link = Link { state, quality: 50, rssi: 90 };
+            crate::Encoder.send(&mut buffer, &link, &[0x8000; 40]).unwrap();
+            let frame = decode_all(&mut Decoder::new(), &buffer.0).unwrap().unwrap();
+            assert_eq!(frame.status, status);
+            assert_eq!(frame.values.len(), MAX_CHANNELS);
+        }
+    }
+
+    #[test]
+    fn resynchronises_after_noise() {
+        let mut buffer = SumdBuffer::new();
+        buffer.encode(Status::Live, &[0x1000, 0x2000]);
+
+        let mut decoder = Decoder::new();
+        assert_eq!(decode_all(&mut decoder, &[0x00, 0x12, VENDOR_ID, VENDOR_ID]), None);
+        let frame = decode_all(&mut decoder, &buffer.0[1..]).unwrap().unwrap();
+        assert_eq!(&frame.values[..], &[scale(0x1000), scale(0x2000)]);
+    }
+
+    #[test]
+    fn consecutive_frames() {
+        let mut decoder = Decoder::new();
+        for i in 0..3 {
+            let mut buffer = SumdBuffer::new();
+            buffer.encode(Status::Live, &[i * 0x1000]);
+            let frame = decode_all(&mut decoder, &buffer.0).unwrap().unwrap();
+            assert_eq!(&frame.values[..], &[scale(i * 0x1000)]);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut buffer = SumdBuffer::new();
+        buffer.encode(Status::Live, &[0x1000, 0x2000]);
+        buffer.0[4] ^= 0x01;
+
+        match decode_all(&mut Decoder::new(), &buffer.0) {
+            Some(Err(Error::Crc { .. })) => {},
+            other => panic!("expected a CRC error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_status() {
+        assert_eq!(decode_all(&mut Decoder::new(), &[VENDOR_ID, 0x02]), Some(Err(Error::Status(0x02))));
+    }
+
+    #[test]
+    fn rejects_bad_channel_count() {
+        assert_eq!(decode_all(&mut Decoder::new(), &[VENDOR_ID, 0x01, 0]), Some(Err(Error::ChannelCount(0))));
+        assert_eq!(decode_all(&mut Decoder::new(), &[VENDOR_ID, 0x01, 33]), Some(Err(Error::ChannelCount(33))));
+    }
+
+    #[test]
+    fn frame_after_a_truncated_one() {
+        let mut buffer = SumdBuffer::new();
+        buffer.encode(Status::Live, &[0x1000, 0x2000]);
+
+        // The next frame's vendor id arrives where the channel count should be
+        let mut decoder = Decoder::new();
+        assert_eq!(decode_all(&mut decoder, &[VENDOR_ID, 0x01, buffer.0[0]]), Some(Err(Error::ChannelCount(VENDOR_ID))));
+        let frame = decode_all(&mut decoder, &buffer.0[1..]).unwrap().unwrap();
+        assert_eq!(&frame.values[..], &[scale(0x1000), scale(0x2000)]);
+    }
+}
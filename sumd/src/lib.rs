@@ -2,19 +2,21 @@
 // https://www.deviationtx.com/media/kunena/attachments/98/HoTT-SUMD-Spec-REV01-12062012-pdf.pdf
 #![no_std]
 
-use core::{
-    convert::From,
-    u16
-};
+use core::convert::{ From, TryFrom };
 
-use nb;
 use embedded_hal::serial::Write;
-use heapless::{ consts::*, Vec };
+use heapless::Vec;
+use rc_output::{ Link, LinkState, RcOutput };
+
+mod decoder;
+
+pub use decoder::{ Decoder, Error, Frame };
 
 // Each packet starts with the vendor id
 const VENDOR_ID : u8 = 0xa8;
 
 // Then a status
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Status {
     Live,
     FailSafe
@@ -30,7 +32,32 @@ impl From<Status> for u8 {
     }
 }
 
+// SUMD can't tell the flight controller the link is only holding the last
+// values, so that's still live
+impl From<LinkState> for Status {
+    fn from(state: LinkState) -> Status {
+        match state {
+            LinkState::Live | LinkState::Hold => Status::Live,
+            LinkState::FailSafe => Status::FailSafe,
+        }
+    }
+}
+
+impl TryFrom<u8> for Status {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Status, u8> {
+        match byte {
+            0x01 => Ok(Status::Live),
+            0x81 => Ok(Status::FailSafe),
+            _ => Err(byte),
+        }
+    }
+}
+
 // Then a byte specifying the number of channels, up to 32
+pub const MAX_CHANNELS: usize = 32;
+
 // Then the values themselves, which are unsigned 16 bits each, in network order, i.e. big-endian
 // There are some reference values
 
@@ -45,17 +72,14 @@ pub const HIGH: u16 = 0x3b60;
 // Extended high position (+150%), equivalent to 2100µs pulse length
 pub const EXTENDED_HIGH: u16 = 0x41a0;
 
-pub const SCALE: u16 = u16::MAX/(HIGH - LOW);
-pub const OFFSET: u16 = NEUTRAL - HIGH/2;
-pub const SCALE_EXTENDED: u16 = u16::MAX/(EXTENDED_HIGH - EXTENDED_LOW);
-pub const OFFSET_EXTENDED: u16 = NEUTRAL - EXTENDED_HIGH/2;
-
+/// Scale a value which uses the whole range of a u16 to the range LOW..=HIGH
 pub fn scale(value: u16) -> u16 {
-    value/SCALE + OFFSET
+    LOW + ((value as u32 * (HIGH - LOW) as u32 + 0x7fff) / 0xffff) as u16
 }
 
 // Finally a 16 bit CRC, of all the bytes preceding it.
 // This is defined in C in the specification, and this is a translation:
+#[derive(Clone, Copy)]
 struct Crc16(u16);
 
 impl Crc16 {
@@ -63,12 +87,12 @@ impl Crc16 {
 
     pub fn update(&mut self, value: u8 ) {
         let mut crc = self.0;
-        crc = crc ^ (value as u16) << 8;
+        crc ^= (value as u16) << 8;
         for _ in 0..8 {
             if (crc & 0x8000) != 0 {
                 crc = (crc << 1) ^ Self::CRC_POLYNOME;
             } else {
-                crc = crc << 1;
+                crc <<= 1;
             }
         }
 
@@ -86,7 +110,7 @@ pub fn send<Out: Write<u8>>(out: &mut Out, status: Status, values : &[u16]) -> n
     };
 
     write(VENDOR_ID)?;
-    write(status as u8)?;
+    write(u8::from(status))?;
     write(values.len() as u8)?;
     for value in values {
         for byte in &scale(*value).to_be_bytes() {
@@ -102,7 +126,17 @@ pub fn send<Out: Write<u8>>(out: &mut Out, status: Status, values : &[u16]) -> n
     Ok(())
 }
 
-pub struct SumdBuffer(pub Vec<u8, U69>);
+/// SUMD as an RcOutput, with up to 32 channels
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encoder;
+
+impl RcOutput for Encoder {
+    fn send<W: Write<u8>>(&self, out: &mut W, link: &Link, values: &[u16]) -> nb::Result<(), W::Error> {
+        send(out, Status::from(link.state), &values[..values.len().min(MAX_CHANNELS)])
+    }
+}
+
+pub struct SumdBuffer(pub Vec<u8, 69>);
 
 impl Write<u8> for SumdBuffer {
     type Error = u8;
@@ -119,6 +153,12 @@ impl Write<u8> for SumdBuffer {
     }
 }
 
+impl Default for SumdBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SumdBuffer {
     pub fn new() -> Self {
         Self(Vec::new())   
@@ -130,9 +170,19 @@ impl SumdBuffer {
 
 }
 
-
 // The whole message then, has a maximum size of 3 + 32*2 + 2 = 69 bytes.
 // It is transmitted on a 115200 baud serial link, 8N1, so the byte rate is
 // 12800B/s, so it takes ~5.4mS to transmit a packet.
 // generally, you would send a packet every 10mS.
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_reference_values() {
+        assert_eq!(scale(0), LOW);
+        assert_eq!(scale(0x8000), NEUTRAL);
+        assert_eq!(scale(0xffff), HIGH);
+    }
+}
@@ -0,0 +1,185 @@
+// Turns ADC readings into channel values.
+//
+// Each axis is calibrated with the readings at its minimum, center and
+// maximum, and mapped piecewise linearly: min to center onto the lower half
+// of a channel value, and center to max onto the upper half. The receiver
+// scales the whole range of a channel value onto its output's, e.g. 0 to
+// SUMD's LOW and 0xffff to its HIGH, so full deflection is full deflection
+// whatever the pots do.
+
+use serde::{ Deserialize, Serialize };
+
+use protocol::Value;
+
+use crate::input::{ Inputs, ADC_BITS, INPUTS };
+
+const ADC_MAX: u16 = (1 << ADC_BITS) - 1;
+const CENTER: Value = 0x8000;
+
+// Readings closer together than this are noise, not a calibration
+const MIN_TRAVEL: u16 = ADC_MAX / 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Axis {
+    pub min: u16,
+    pub center: u16,
+    pub max: u16,
+}
+
+impl Axis {
+    /// The whole range of the ADC
+    pub const DEFAULT: Axis = Axis { min: 0, center: ADC_MAX / 2 + 1, max: ADC_MAX };
+
+    pub fn apply(&self, raw: u16) -> Value {
+        let raw = raw.max(self.min).min(self.max);
+        if raw < self.center {
+            CENTER - interpolate(self.center - raw, self.center - self.min, CENTER as u32)
+        } else {
+            CENTER + interpolate(raw - self.center, self.max - self.center, u16::MAX as u32 - CENTER as u32)
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.min < self.center && self.center < self.max
+    }
+}
+
+// `distance` of `range` as a fraction of `full`
+fn interpolate(distance: u16, range: u16, full: u32) -> Value {
+    match range {
+        0 => 0,
+        _ => (distance as u32 * full / range as u32) as Value,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub axes: [Axis; INPUTS],
+}
+
+/// The most a calibration takes in the store: three readings an axis, each up
+/// to three bytes
+pub const MAX_STORED_SIZE: usize = INPUTS * 3 * 3;
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration { axes: [Axis::DEFAULT; INPUTS] }
+    }
+}
+
+impl Calibration {
+    pub fn apply(&self, raw: &Inputs) -> [Value; INPUTS] {
+        let mut values = [CENTER; INPUTS];
+        for ((value, axis), raw) in values.iter_mut().zip(self.axes.iter()).zip(raw.iter()) {
+            *value = axis.apply(*raw);
+        }
+        values
+    }
+
+    /// Whether every axis has its center between its ends, as a calibration
+    /// loaded from flash should
+    pub fn is_valid(&self) -> bool {
+        self.axes.iter().all(Axis::is_valid)
+    }
+}
+
+/// Calibration mode. The pilot moves each stick around its full travel, then
+/// lets go, and the sticks which spring back give their centers.
+pub struct Calibrator {
+    min: Inputs,
+    max: Inputs,
+}
+
+impl Calibrator {
+    pub fn new(raw: &Inputs) -> Self {
+        Calibrator { min: *raw, max: *raw }
+    }
+
+    pub fn update(&mut self, raw: &Inputs) {
+        for ((min, max), raw) in self.min.iter_mut().zip(self.max.iter_mut()).zip(raw.iter()) {
+            *min = (*min).min(*raw);
+            *max = (*max).max(*raw);
+        }
+    }
+
+    /// The calibration, with the sticks where they are now as the centers.
+    /// An axis which didn't move far enough keeps its old calibration, and
+    /// one which isn't centered, like the throttle, gets the middle of its travel.
+    pub fn finish(&self, raw: &Inputs, old: &Calibration) -> Calibration {
+        let mut calibration = *old;
+        for (i, axis) in calibration.axes.iter_mut().enumerate() {
+            let (min, max) = (self.min[i], self.max[i]);
+            if max - min < MIN_TRAVEL {
+                continue;
+            }
+
+            let margin = (max - min) / 8;
+            let center = if raw[i] > min + margin && raw[i] < max - margin {
+                raw[i]
+            } else {
+                min + (max - min) / 2
+            };
+            *axis = Axis { min, center, max };
+        }
+        calibration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_full_range() {
+        assert_eq!(Calibration::default().apply(&[0, 0x800, 0xfff, 0x400, 0, 0xfff]),
+            [0, 0x8000, 0xffff, 0x4000, 0, 0xffff]);
+    }
+
+    #[test]
+    fn fits_in_the_store() {
+        let calibration = Calibration { axes: [Axis { min: u16::MAX, center: u16::MAX, max: u16::MAX }; INPUTS] };
+        let mut buffer = [0; 2 * MAX_STORED_SIZE];
+        assert!(postcard::to_slice(&calibration, &mut buffer).unwrap().len() <= MAX_STORED_SIZE);
+    }
+
+    #[test]
+    fn piecewise_linear() {
+        let axis = Axis { min: 1000, center: 1500, max: 3500 };
+        assert_eq!(axis.apply(1000), 0);
+        assert_eq!(axis.apply(1250), 0x4000);
+        assert_eq!(axis.apply(1500), 0x8000);
+        assert_eq!(axis.apply(2500), 0xbfff);
+        assert_eq!(axis.apply(3500), 0xffff);
+
+        // Beyond the calibrated travel
+        assert_eq!(axis.apply(0), 0);
+        assert_eq!(axis.apply(4095), 0xffff);
+    }
+
+    #[test]
+    fn calibrate() {
+        let old = Calibration::default();
+        let mut calibrator = Calibrator::new(&[2000, 2000, 2000, 2000, 2000, 2000]);
+        for raw in [[500, 300, 100, 2010, 0, 2000], [3600, 3900, 4000, 2020, 4000, 2000]].iter() {
+            calibrator.update(raw);
+        }
+
+        // The throttle stays at the bottom, and the last stick never moved.
+        // Pots don't spring back, so their centers are the middle.
+        let calibration = calibrator.finish(&[2100, 2000, 100, 2000, 4000, 2000], &old);
+        assert_eq!(calibration.axes[0], Axis { min: 500, center: 2100, max: 3600 });
+        assert_eq!(calibration.axes[1], Axis { min: 300, center: 2000, max: 3900 });
+        assert_eq!(calibration.axes[2], Axis { min: 100, center: 2050, max: 4000 });
+        assert_eq!(calibration.axes[3], old.axes[3]);
+        assert_eq!(calibration.axes[4], Axis { min: 0, center: 2000, max: 4000 });
+        assert_eq!(calibration.axes[5], old.axes[5]);
+    }
+
+    #[test]
+    fn valid() {
+        assert!(Calibration::default().is_valid());
+        let mut calibration = Calibration::default();
+        calibration.axes[2] = Axis { min: 0x800, center: 0x800, max: 0xffe };
+        assert!(!calibration.is_valid());
+    }
+}
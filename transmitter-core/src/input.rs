@@ -0,0 +1,70 @@
+// Where the transmitter's inputs come from: the sticks, and whatever else the
+// board has, read by the ADC, and its switches.
+
+use serde::{ Deserialize, Serialize };
+
+/// Two sticks, two axes each
+pub const STICKS: usize = 4;
+/// The auxiliary pots, after the sticks
+pub const POTS: usize = 2;
+/// The number of analogue inputs
+pub const INPUTS: usize = STICKS + POTS;
+
+/// The STM32's ADC is 12 bits
+pub const ADC_BITS: u32 = 12;
+
+/// The ADC reading of each input
+pub type Inputs = [u16; INPUTS];
+
+pub trait InputSource {
+    type Error;
+
+    /// The latest reading, or `WouldBlock` if a scan is in progress
+    fn read(&mut self) -> nb::Result<Inputs, Self::Error>;
+}
+
+/// The number of switches
+pub const SWITCHES: usize = 4;
+
+/// Where a switch is. Two position switches are only ever up or down.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Position {
+    #[default]
+    Up,
+    Middle,
+    Down,
+}
+
+impl Position {
+    /// A three position switch closes one contact at each end, and neither in
+    /// the middle. A two position switch only has the down contact.
+    pub fn from_contacts(up: bool, down: bool) -> Self {
+        match (up, down) {
+            (_, true) => Position::Down,
+            (true, false) => Position::Up,
+            (false, false) => Position::Middle,
+        }
+    }
+
+    pub fn two_position(down: bool) -> Self {
+        if down { Position::Down } else { Position::Up }
+    }
+}
+
+pub type Switches = [Position; SWITCHES];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switch_positions() {
+        assert_eq!(Position::from_contacts(true, false), Position::Up);
+        assert_eq!(Position::from_contacts(false, false), Position::Middle);
+        assert_eq!(Position::from_contacts(false, true), Position::Down);
+        // Both can't be closed at once, unless the switch is broken
+        assert_eq!(Position::from_contacts(true, true), Position::Down);
+        assert_eq!(Position::two_position(false), Position::Up);
+        assert_eq!(Position::two_position(true), Position::Down);
+    }
+}
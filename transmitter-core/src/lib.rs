@@ -0,0 +1,408 @@
+// The transmitter's application logic, independent of the board it runs on.
+// Each time the timer fires, the inputs go through the pipeline
+//
+//     InputSource -> calibration -> model -> Transmitter -> Link
+//
+// and the receiver's telemetry comes back in the acknowledgement. The
+// firmware provides the InputSource and the Link, and keeps the model
+// memories.
+//
+// The transmitter only sends the channel values once the receiver has
+// reported the model ID the selected model was bound to. Until then it sends
+// the failsafe positions, so flying the wrong model with the wrong settings
+// is impossible.
+#![no_std]
+
+pub mod calibration;
+pub mod input;
+pub mod link;
+pub mod mixer;
+pub mod model;
+pub mod shaping;
+pub mod trim;
+
+pub use calibration::{ Calibration, Calibrator };
+pub use input::{ InputSource, Inputs, Position, Switches };
+pub use link::{ Link, Payload };
+pub use model::{ Model, Pairing, MODELS };
+pub use trim::Trimmer;
+
+use protocol::{
+    Value, CHANNELS, PAYLOAD_SIZE, ReceiverMessage, TransmitterMessage,
+    auth::{ self, Authenticator },
+    bind::{ self, BindInfo, ModelId, TxBinder },
+    channels::Packer,
+    failsafe::DEFAULT_POSITIONS,
+    hop::{ self, HopSequence },
+    telemetry::Telemetry,
+};
+
+// In messages, i.e. 1s
+const TELEMETRY_TIMEOUT: u32 = 100;
+// Percent
+const LOW_LINK_QUALITY: u8 = 75;
+// In messages, i.e. 1s
+const FAILSAFE_INTERVAL: u32 = 100;
+
+// Channel values centered on zero, for the arithmetic between the calibration
+// and the receiver
+fn centered(value: Value) -> i32 {
+    value as i32 - 0x8000
+}
+
+fn uncentered(value: i32) -> Value {
+    (value.clamp(-0x8000, 0x7fff) + 0x8000) as Value
+}
+
+/// The channel values for these ADC readings and switches
+pub fn channels(raw: &Inputs, switches: &Switches, calibration: &Calibration, model: &Model) -> [Value; CHANNELS] {
+    model.apply(&calibration.apply(raw), switches)
+}
+
+pub struct Transmitter<L> {
+    link: L,
+    correlation_id: u32,
+    telemetry: Telemetry,
+    // Messages sent since the last telemetry arrived
+    telemetry_age: u32,
+    failsafe: [Value; CHANNELS],
+    // Each of them sends the slow channels a group at a time
+    values_packer: Packer,
+    failsafe_packer: Packer,
+    // Only while binding
+    binder: Option<TxBinder>,
+    // Only once bound
+    hops: Option<HopSequence>,
+    authenticator: Option<Authenticator>,
+    model_id: Option<ModelId>,
+    // The receiver has reported the model ID we expect
+    armed: bool,
+}
+
+impl<L: Link> Transmitter<L> {
+    fn new(link: L) -> Self {
+        Transmitter {
+            link,
+            correlation_id: 0,
+            telemetry: Telemetry::new(),
+            telemetry_age: 0,
+            failsafe: DEFAULT_POSITIONS,
+            values_packer: Packer::new(),
+            failsafe_packer: Packer::new(),
+            binder: None,
+            hops: None,
+            authenticator: None,
+            model_id: None,
+            armed: false,
+        }
+    }
+
+    /// A transmitter which was bound earlier, starting its `session`th session
+    pub fn bound(link: L, pairing: &Pairing, session: u8) -> Self {
+        let mut transmitter = Self::new(link);
+        transmitter.pair(pairing, session);
+        transmitter
+    }
+
+    /// A transmitter offering this bind info to any receiver listening
+    pub fn binding(link: L, info: BindInfo) -> Self {
+        let mut transmitter = Self::new(link);
+        transmitter.bind(info);
+        transmitter
+    }
+
+    /// Talk to the receiver in another model, starting its `session`th session
+    pub fn pair(&mut self, pairing: &Pairing, session: u8) {
+        let _ = self.link.set_address(&pairing.info.address);
+        // Carry on from where the last session left off, so the receiver
+        // can tell our messages from recordings of them
+        self.correlation_id = auth::session_start(session);
+        self.restart(None, Some(pairing.model_id));
+        self.hops = Some(HopSequence::new(pairing.info.hop_seed, &hop::BLACKLIST));
+        self.authenticator = Some(Authenticator::new(pairing.info.key));
+    }
+
+    /// Offer this bind info to any receiver listening
+    pub fn bind(&mut self, info: BindInfo) {
+        let _ = self.link.set_address(&bind::BIND_ADDRESS);
+        self.correlation_id = 0;
+        self.restart(Some(TxBinder::new(info)), None);
+        self.hops = None;
+        self.authenticator = None;
+    }
+
+    fn restart(&mut self, binder: Option<TxBinder>, model_id: Option<ModelId>) {
+        self.telemetry = Telemetry::new();
+        self.telemetry_age = 0;
+        self.binder = binder;
+        self.model_id = model_id;
+        self.armed = false;
+        // The receiver may be a different one, which needs every channel
+        self.values_packer = Packer::new();
+        self.failsafe_packer = Packer::new();
+    }
+
+    /// The positions for the receiver to output when the link is lost
+    pub fn set_failsafe(&mut self, positions: [Value; CHANNELS]) {
+        self.failsafe = positions;
+    }
+
+    pub fn link(&self) -> &L {
+        &self.link
+    }
+
+    pub fn link_mut(&mut self) -> &mut L {
+        &mut self.link
+    }
+
+    pub fn is_bound(&self) -> bool {
+        self.binder.is_none()
+    }
+
+    /// Whether the receiver is the one the model was bound to, so the
+    /// channel values are being sent
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    pub fn telemetry(&self) -> &Telemetry {
+        &self.telemetry
+    }
+
+    /// Messages sent since the last telemetry arrived
+    pub fn telemetry_age(&self) -> u32 {
+        self.telemetry_age
+    }
+
+    /// The pilot should be warned when the link is poor, there's no telemetry
+    /// at all, or it's from the wrong model
+    pub fn warning(&self) -> bool {
+        self.telemetry_age > TELEMETRY_TIMEOUT || self.telemetry.link.quality < LOW_LINK_QUALITY || !self.armed
+    }
+
+    /// Send the channel values, or while binding, the bind info. Returns the
+    /// pairing once the receiver has the bind info, for the firmware to store
+    /// in the model.
+    pub fn transmit(&mut self, values: [Value; CHANNELS]) -> Result<Option<Pairing>, L::Error> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let channel = match &self.hops {
+            Some(hops) => hops.channel(self.correlation_id),
+            None => bind::BIND_FREQUENCY,
+        };
+
+        let body = match &self.binder {
+            Some(binder) => binder.message(),
+            None if self.armed && !self.correlation_id.is_multiple_of(FAILSAFE_INTERVAL) =>
+                TransmitterMessage::ChannelValues(self.values_packer.pack(&values)),
+            None => TransmitterMessage::Failsafe(self.failsafe_packer.pack(&self.failsafe)),
+        };
+        let message = protocol::Transmitter::new(self.correlation_id, body);
+        let mut buffer = [0; PAYLOAD_SIZE];
+        let payload = match &self.authenticator {
+            Some(authenticator) => authenticator.sign(&message, &mut buffer),
+            None => message.encode(&mut buffer),
+        };
+
+        // The payloads are sized so this can't fail
+        let ack = match payload {
+            Ok(payload) => self.link.send(channel, payload)?,
+            Err(_) => None,
+        };
+
+        // The receiver's telemetry arrives in the acknowledgement
+        self.telemetry_age += 1;
+        let mut bound = None;
+        if let Some(Ok(protocol::Receiver { version: protocol::VERSION, body, .. })) =
+            ack.map(|ack| protocol::Receiver::decode(&ack)) {
+            if let Some(binder) = &mut self.binder {
+                if binder.acknowledge(&body) {
+                    bound = binder.model_id().map(|model_id| Pairing { info: binder.info(), model_id });
+                }
+            }
+            // The receiver's model ID comes with the link telemetry. Losing it
+            // doesn't disarm, since only the acknowledgements might be lost.
+            if let ReceiverMessage::Link(link) = &body {
+                self.armed = self.model_id == Some(link.model_id);
+            }
+            self.telemetry.update(body);
+            self.telemetry_age = 0;
+        }
+
+        // The receiver has the bind info, so move to the new address
+        if let Some(pairing) = bound {
+            self.link.set_address(&pairing.info.address)?;
+            self.hops = Some(HopSequence::new(pairing.info.hop_seed, &hop::BLACKLIST));
+            self.authenticator = Some(Authenticator::new(pairing.info.key));
+            self.binder = None;
+            self.model_id = Some(pairing.model_id);
+        }
+        Ok(bound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use protocol::{ channels::{ self, Packed }, telemetry::Link as LinkTelemetry };
+
+    const MODEL_ID: ModelId = 42;
+
+    // Remembers what was sent, and acknowledges with whatever it's given
+    #[derive(Default)]
+    struct FakeLink {
+        sent: Vec<(u8, [u8; 5], Vec<u8>)>,
+        address: [u8; 5],
+        ack: Option<ReceiverMessage>,
+    }
+
+    impl Link for FakeLink {
+        type Error = ();
+
+        fn send(&mut self, channel: u8, payload: &[u8]) -> Result<Option<Payload>, ()> {
+            self.sent.push((channel, self.address, payload.to_vec()));
+            Ok(self.ack.clone().map(|body| {
+                let mut buffer = [0; PAYLOAD_SIZE];
+                protocol::Receiver::new(0, body).encode(&mut buffer).unwrap();
+                buffer
+            }))
+        }
+
+        fn set_address(&mut self, address: &[u8; 5]) -> Result<(), ()> {
+            self.address = *address;
+            Ok(())
+        }
+    }
+
+    const VALUES: [Value; CHANNELS] = [0x1000; CHANNELS];
+    const FAILSAFE: [Value; CHANNELS] = [0x5000; CHANNELS];
+
+    // Whether every channel the message carries has this value
+    fn all(packed: &Packed, value: Value) -> bool {
+        let expected = [channels::quantize(value); CHANNELS];
+        let mut values = expected;
+        packed.unpack(&mut values).is_ok() && values == expected
+    }
+
+    fn info() -> BindInfo {
+        BindInfo::generate([5, 6, 7, 8])
+    }
+
+    fn pairing() -> Pairing {
+        Pairing { info: info(), model_id: MODEL_ID }
+    }
+
+    fn link(model_id: ModelId) -> Option<ReceiverMessage> {
+        Some(ReceiverMessage::Link(LinkTelemetry { quality: 100, rssi: 100, voltage: 5000, model_id }))
+    }
+
+    fn sent(transmitter: &Transmitter<FakeLink>) -> Vec<TransmitterMessage> {
+        let mut receiver = Authenticator::new(info().key);
+        transmitter.link().sent.iter().map(|(_, _, payload)| receiver.verify(payload).unwrap().body).collect()
+    }
+
+    #[test]
+    fn pipeline() {
+        let mut calibration = Calibration::default();
+        calibration.axes[0] = calibration::Axis { min: 100, center: 200, max: 300 };
+        let switches = [Position::Up; input::SWITCHES];
+        let mut expected = [0x8000; CHANNELS];
+        expected[..input::INPUTS].copy_from_slice(&[0, 0x8000, 0xffff, 0x4000, 0x8000, 0x8000]);
+        // The switches, all up
+        expected[input::INPUTS..input::INPUTS + input::SWITCHES].copy_from_slice(&[0; input::SWITCHES]);
+        assert_eq!(channels(&[0, 0x800, 0xfff, 0x400, 0x800, 0x800], &switches, &calibration, &Model::new(0)), expected);
+    }
+
+    #[test]
+    fn hops_and_signs() {
+        let mut transmitter = Transmitter::bound(FakeLink::default(), &pairing(), 3);
+        transmitter.link_mut().ack = link(MODEL_ID);
+        let hops = HopSequence::new(info().hop_seed, &hop::BLACKLIST);
+        let mut receiver = Authenticator::new(info().key);
+        for _ in 0..20 {
+            assert_eq!(transmitter.transmit(VALUES), Ok(None));
+        }
+
+        let mut correlation_id = auth::session_start(3);
+        for (i, (channel, address, payload)) in transmitter.link().sent.iter().enumerate() {
+            let message = receiver.verify(payload).unwrap();
+            correlation_id += 1;
+            assert_eq!(message.correlation_id, correlation_id);
+            // Until the receiver has reported its model ID
+            match (i, &message.body) {
+                (0, TransmitterMessage::Failsafe(positions)) => {
+                    // The defaults, with the throttle closed
+                    let expected = DEFAULT_POSITIONS.map(channels::quantize);
+                    let mut unpacked = expected;
+                    positions.unpack(&mut unpacked).unwrap();
+                    assert_eq!(unpacked, expected);
+                    assert_eq!(unpacked[protocol::failsafe::THROTTLE], 0);
+                },
+                (1.., TransmitterMessage::ChannelValues(values)) => assert!(all(values, VALUES[0])),
+                (_, body) => panic!("{:?} at {}", body, i),
+            }
+            assert_eq!(*channel, hops.channel(correlation_id));
+            assert_eq!(*address, info().address);
+        }
+    }
+
+    #[test]
+    fn binds_when_acknowledged() {
+        let mut transmitter = Transmitter::binding(FakeLink::default(), info());
+        assert_eq!(transmitter.transmit([0; CHANNELS]), Ok(None));
+        let (channel, address, payload) = &transmitter.link().sent[0];
+        assert_eq!((*channel, *address), (bind::BIND_FREQUENCY, bind::BIND_ADDRESS));
+        assert_eq!(protocol::Transmitter::decode(payload).unwrap().body, TransmitterMessage::Bind(info()));
+
+        transmitter.link_mut().ack = Some(ReceiverMessage::Bound(info(), MODEL_ID));
+        assert_eq!(transmitter.transmit([0; CHANNELS]), Ok(Some(pairing())));
+        assert!(transmitter.is_bound());
+        assert_eq!(transmitter.link().address, info().address);
+
+        // Then it's the same as any other session
+        transmitter.link_mut().ack = link(MODEL_ID);
+        transmitter.transmit([0; CHANNELS]).unwrap();
+        assert!(transmitter.is_armed());
+    }
+
+    #[test]
+    fn refuses_to_arm_with_the_wrong_model() {
+        let mut transmitter = Transmitter::bound(FakeLink::default(), &pairing(), 0);
+        transmitter.set_failsafe(FAILSAFE);
+        transmitter.link_mut().ack = link(MODEL_ID + 1);
+        for _ in 0..10 {
+            transmitter.transmit(VALUES).unwrap();
+        }
+        assert!(!transmitter.is_armed());
+        assert!(transmitter.warning());
+        assert!(sent(&transmitter).iter().all(|body| matches!(body, TransmitterMessage::Failsafe(positions) if all(positions, FAILSAFE[0]))));
+
+        // The right model, and the failsafe positions are only sent now and then
+        transmitter.link_mut().ack = link(MODEL_ID);
+        transmitter.link_mut().sent.clear();
+        for _ in 0..2 * FAILSAFE_INTERVAL {
+            transmitter.transmit(VALUES).unwrap();
+        }
+        assert!(transmitter.is_armed());
+        let failsafes = sent(&transmitter).iter().filter(|body| matches!(body, TransmitterMessage::Failsafe(_))).count();
+        assert_eq!(failsafes, 2 + 1);
+    }
+
+    #[test]
+    fn warns_without_telemetry() {
+        let mut transmitter = Transmitter::bound(FakeLink::default(), &pairing(), 0);
+        transmitter.link_mut().ack = link(MODEL_ID);
+        transmitter.transmit([0; CHANNELS]).unwrap();
+        assert_eq!(transmitter.telemetry().link.voltage, 5000);
+        assert!(!transmitter.warning());
+
+        transmitter.link_mut().ack = None;
+        for _ in 0..=TELEMETRY_TIMEOUT {
+            transmitter.transmit([0; CHANNELS]).unwrap();
+        }
+        assert!(transmitter.warning());
+    }
+}
@@ -0,0 +1,16 @@
+// What the transmitter needs from its radio. The firmware implements it for
+// the nRF24L01, and the simulator for its simulated air.
+
+use protocol::PAYLOAD_SIZE;
+
+pub type Payload = [u8; PAYLOAD_SIZE];
+
+pub trait Link {
+    type Error;
+
+    /// Send the payload on the channel, and wait until it's acknowledged or
+    /// given up on. Returns the payload the acknowledgement carried, if any.
+    fn send(&mut self, channel: u8, payload: &[u8]) -> Result<Option<Payload>, Self::Error>;
+
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), Self::Error>;
+}
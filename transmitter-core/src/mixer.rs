@@ -0,0 +1,392 @@
+// The mixer makes each channel a weighted sum of sources: the sticks, the
+// switches, the trims and a constant. A mix takes one source through a curve,
+// weighs it, offsets it, and adds it to its channel, or replaces whatever was
+// added before, so a throttle cut comes after the throttle. A mix with a
+// condition only counts while its switch is in that position.
+//
+// The mixes are a table, worked through in order. Channels are centered on
+// zero here, with full travel at ±0x8000, and a channel is centered until a
+// mix adds something to it.
+
+use serde::{ Deserialize, Serialize };
+
+use protocol::CHANNELS;
+
+use crate::{ input::{ Position, INPUTS, SWITCHES }, shaping };
+
+/// The most mixes a model can have
+pub const MIXES: usize = 16;
+
+const FULL: i32 = 0x8000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    /// A stick axis, after its expo, rate and trim
+    Input(u8),
+    /// A switch: -100% up, 0 in the middle and +100% down
+    Switch(u8),
+    /// A stick axis's trim on its own
+    Trim(u8),
+    /// Always +100%
+    Max,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// Percent, as for the sticks
+    Expo(u8),
+    /// Only the source above center, e.g. for differential ailerons
+    Positive,
+    /// Only the source below center
+    Negative,
+    /// How far the source is from center, either way
+    Absolute,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Add,
+    /// Replace everything mixed into the channel so far
+    Replace,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub switch: u8,
+    pub position: Position,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Mix {
+    pub source: Source,
+    pub target: u8,
+    /// Percent of the source above center, and negative to reverse
+    pub high: i8,
+    /// Percent of the source below center
+    pub low: i8,
+    /// Percent of full travel, added after the weight
+    pub offset: i8,
+    pub curve: Curve,
+    /// Only while the switch is in the position
+    pub condition: Option<Condition>,
+    pub operation: Operation,
+}
+
+pub type Mixes = [Option<Mix>; MIXES];
+
+/// What the mixes mix
+pub struct Sources<'a> {
+    /// The sticks, shaped and trimmed
+    pub inputs: &'a [i32; INPUTS],
+    pub trims: &'a [i16; INPUTS],
+    pub switches: &'a [Position],
+}
+
+impl Sources<'_> {
+    fn value(&self, source: Source) -> Option<i32> {
+        match source {
+            Source::Input(input) => self.inputs.get(input as usize).copied(),
+            Source::Switch(switch) => self.switches.get(switch as usize).map(|position| match position {
+                Position::Up => -FULL,
+                Position::Middle => 0,
+                Position::Down => FULL,
+            }),
+            Source::Trim(input) => self.trims.get(input as usize).map(|trim| *trim as i32),
+            Source::Max => Some(FULL),
+        }
+    }
+}
+
+impl Mix {
+    /// Add `weight` percent of the source to the channel
+    pub const fn new(source: Source, target: u8, weight: i8) -> Self {
+        Mix {
+            source,
+            target,
+            high: weight,
+            low: weight,
+            offset: 0,
+            curve: Curve::Linear,
+            condition: None,
+            operation: Operation::Add,
+        }
+    }
+
+    // None if the mix doesn't count at the moment
+    fn value(&self, sources: &Sources) -> Option<i32> {
+        if let Some(condition) = &self.condition {
+            if sources.switches.get(condition.switch as usize) != Some(&condition.position) {
+                return None;
+            }
+        }
+
+        let source = sources.value(self.source)?;
+        let curved = match self.curve {
+            Curve::Linear => source,
+            Curve::Expo(percent) => shaping::expo(source, percent),
+            Curve::Positive => source.max(0),
+            Curve::Negative => source.min(0),
+            Curve::Absolute => source.abs(),
+        };
+        let weight = if source < 0 { self.low } else { self.high };
+        Some(curved * weight as i32 / 100 + self.offset as i32 * FULL / 100)
+    }
+}
+
+pub fn mix(sources: &Sources, mixes: &[Option<Mix>]) -> [i32; CHANNELS] {
+    let mut channels = [0; CHANNELS];
+    for mix in mixes.iter().flatten() {
+        if let (Some(value), Some(channel)) = (mix.value(sources), channels.get_mut(mix.target as usize)) {
+            match mix.operation {
+                Operation::Add => *channel += value,
+                Operation::Replace => *channel = value,
+            }
+        }
+    }
+    channels
+}
+
+/// There's no room left in the table
+#[derive(Debug, PartialEq)]
+pub struct Full;
+
+/// Add the mixes after the last one in the table, or none of them if there
+/// isn't room for them all
+pub fn add(mixes: &mut Mixes, new: &[Mix]) -> Result<(), Full> {
+    let used = mixes.iter().rposition(Option::is_some).map_or(0, |last| last + 1);
+    if used + new.len() > MIXES {
+        return Err(Full);
+    }
+    for (slot, mix) in mixes[used..].iter_mut().zip(new.iter()) {
+        *slot = Some(*mix);
+    }
+    Ok(())
+}
+
+/// Each input straight through to the channel with the same number, and
+/// then each switch to the next channels
+pub fn straight() -> Mixes {
+    let mut mixes = [None; MIXES];
+    for (i, mix) in mixes.iter_mut().take((INPUTS + SWITCHES).min(CHANNELS)).enumerate() {
+        let source = match i.checked_sub(INPUTS) {
+            None => Source::Input(i as u8),
+            Some(switch) => Source::Switch(switch as u8),
+        };
+        *mix = Some(Mix::new(source, i as u8, 100));
+    }
+    mixes
+}
+
+/// Elevons, for a flying wing or a delta: each takes half the aileron, in
+/// opposite directions, and half the elevator
+pub fn elevon(aileron: Source, elevator: Source, left: u8, right: u8) -> [Mix; 4] {
+    [
+        Mix::new(aileron, left, 50),
+        Mix::new(elevator, left, 50),
+        Mix::new(aileron, right, -50),
+        Mix::new(elevator, right, 50),
+    ]
+}
+
+/// A V-tail: each ruddervator takes half the elevator, and half the rudder,
+/// in opposite directions
+pub fn v_tail(elevator: Source, rudder: Source, left: u8, right: u8) -> [Mix; 4] {
+    [
+        Mix::new(elevator, left, 50),
+        Mix::new(rudder, left, 50),
+        Mix::new(elevator, right, 50),
+        Mix::new(rudder, right, -50),
+    ]
+}
+
+/// Flaperons: ailerons which also droop together as flaps
+pub fn flaperon(aileron: Source, flap: Source, left: u8, right: u8) -> [Mix; 4] {
+    [
+        Mix::new(aileron, left, 50),
+        Mix::new(flap, left, 50),
+        Mix::new(aileron, right, -50),
+        Mix::new(flap, right, 50),
+    ]
+}
+
+/// Hold the throttle right down while the switch is in the position, whatever
+/// the stick says. It only works after the throttle's other mixes.
+pub fn throttle_cut(throttle: u8, switch: u8, position: Position) -> Mix {
+    Mix {
+        condition: Some(Condition { switch, position }),
+        operation: Operation::Replace,
+        ..Mix::new(Source::Max, throttle, -100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Switches;
+
+    const HALF: i32 = FULL / 2;
+    const SWITCHED: Switches = [Position::Up, Position::Middle, Position::Down, Position::Up];
+    const TRIMS: [i16; INPUTS] = [0x100, 0, 0, 0, 0, 0];
+
+    fn table(new: &[Mix]) -> Mixes {
+        let mut mixes = [None; MIXES];
+        add(&mut mixes, new).unwrap();
+        mixes
+    }
+
+    // The inputs left out are centered
+    fn mixed(values: &[i32], switches: &Switches, mixes: &Mixes) -> [i32; CHANNELS] {
+        let mut inputs = [0; INPUTS];
+        inputs[..values.len()].copy_from_slice(values);
+        mix(&Sources { inputs: &inputs, trims: &TRIMS, switches }, mixes)
+    }
+
+    #[test]
+    fn straight_through() {
+        let inputs = [-FULL, -HALF, HALF, FULL, 0, HALF];
+        let channels = mixed(&inputs, &SWITCHED, &straight());
+        assert_eq!(channels[..INPUTS], inputs);
+        assert_eq!(channels[INPUTS..INPUTS + SWITCHES], [-FULL, 0, FULL, -FULL]);
+        assert!(channels[INPUTS + SWITCHES..].iter().all(|channel| *channel == 0));
+    }
+
+    #[test]
+    fn single_mixes() {
+        let input = |input, weight| Mix::new(Source::Input(input), 0, weight);
+        let down = Some(Condition { switch: 2, position: Position::Down });
+        let up = Some(Condition { switch: 2, position: Position::Up });
+
+        // Each mix on its own onto channel 0, with input 0 half up and input
+        // 1 half down
+        let cases = [
+            (input(0, 100), HALF),
+            (input(0, -50), -HALF / 2),
+            (input(1, 100), -HALF),
+            (Mix { offset: 10, ..input(0, 100) }, HALF + FULL / 10),
+            (Mix { offset: -100, ..input(0, 0) }, -FULL),
+            (Mix { low: 0, ..input(0, 100) }, HALF),
+            (Mix { high: 25, ..input(0, 100) }, HALF / 4),
+            (Mix { low: 25, ..input(1, 100) }, -HALF / 4),
+            (Mix { high: 0, ..input(1, 100) }, -HALF),
+            (Mix::new(Source::Max, 0, 100), FULL),
+            (Mix::new(Source::Switch(0), 0, 100), -FULL),
+            (Mix::new(Source::Switch(1), 0, 100), 0),
+            (Mix::new(Source::Switch(2), 0, 100), FULL),
+            (Mix::new(Source::Trim(0), 0, 100), 0x100),
+            (Mix { curve: Curve::Expo(100), ..input(0, 100) }, 0x1000),
+            (Mix { curve: Curve::Positive, ..input(0, 100) }, HALF),
+            (Mix { curve: Curve::Positive, ..input(1, 100) }, 0),
+            (Mix { curve: Curve::Negative, ..input(1, 100) }, -HALF),
+            (Mix { curve: Curve::Absolute, ..input(1, 100) }, HALF),
+            (Mix { condition: down, ..input(0, 100) }, HALF),
+            (Mix { condition: up, ..input(0, 100) }, 0),
+            // Sources and switches which don't exist
+            (input(INPUTS as u8, 100), 0),
+            (Mix::new(Source::Switch(SWITCHES as u8), 0, 100), 0),
+            (Mix { condition: Some(Condition { switch: SWITCHES as u8, position: Position::Up }), ..input(0, 100) }, 0),
+        ];
+        for (i, (mix, expected)) in cases.iter().enumerate() {
+            let channels = mixed(&[HALF, -HALF], &SWITCHED, &table(&[*mix]));
+            assert_eq!(channels[0], *expected, "case {}", i);
+            assert!(channels[1..].iter().all(|channel| *channel == 0), "case {}", i);
+        }
+    }
+
+    #[test]
+    fn adds_up_in_order() {
+        let mixes = table(&[
+            Mix::new(Source::Input(0), 3, 50),
+            Mix::new(Source::Input(3), 3, 100),
+            Mix { operation: Operation::Replace, ..Mix::new(Source::Input(1), 0, 100) },
+            Mix::new(Source::Input(2), 0, 10),
+            // Off the end of the channels
+            Mix::new(Source::Input(0), CHANNELS as u8, 100),
+        ]);
+        let channels = mixed(&[1000, 2000, 3000, 4000], &SWITCHED, &mixes);
+        assert_eq!(channels[..4], [2300, 0, 0, 4500]);
+        assert!(channels[4..].iter().all(|channel| *channel == 0));
+    }
+
+    // Two inputs, then the two channels they're mixed into
+    type Case = ((i32, i32), (i32, i32));
+
+    fn assert_mixes(mixes: &Mixes, cases: &[Case]) {
+        for (inputs, expected) in cases {
+            let channels = mixed(&[inputs.0, inputs.1], &SWITCHED, mixes);
+            assert_eq!((channels[0], channels[1]), *expected, "inputs {:?}", inputs);
+        }
+    }
+
+    #[test]
+    fn elevons() {
+        // Aileron and elevator, to left and right
+        assert_mixes(&table(&elevon(Source::Input(0), Source::Input(1), 0, 1)), &[
+            ((0, 0), (0, 0)),
+            ((FULL, 0), (HALF, -HALF)),
+            ((-FULL, 0), (-HALF, HALF)),
+            ((0, FULL), (HALF, HALF)),
+            ((FULL, FULL), (FULL, 0)),
+            ((-FULL, FULL), (0, FULL)),
+            ((-HALF, -HALF), (-HALF, 0)),
+        ]);
+    }
+
+    #[test]
+    fn v_tails() {
+        // Elevator and rudder, to left and right
+        assert_mixes(&table(&v_tail(Source::Input(0), Source::Input(1), 0, 1)), &[
+            ((0, 0), (0, 0)),
+            ((FULL, 0), (HALF, HALF)),
+            ((0, FULL), (HALF, -HALF)),
+            ((0, -FULL), (-HALF, HALF)),
+            ((FULL, FULL), (FULL, 0)),
+            ((-FULL, FULL), (0, -FULL)),
+        ]);
+    }
+
+    #[test]
+    fn flaperons() {
+        // Aileron and flap, to left and right
+        assert_mixes(&table(&flaperon(Source::Input(0), Source::Input(1), 0, 1)), &[
+            ((0, 0), (0, 0)),
+            ((FULL, 0), (HALF, -HALF)),
+            ((0, FULL), (HALF, HALF)),
+            ((FULL, FULL), (FULL, 0)),
+            ((HALF, -FULL), (-HALF / 2, -FULL + HALF / 2)),
+        ]);
+    }
+
+    #[test]
+    fn throttle_cuts() {
+        let mut mixes = straight();
+        add(&mut mixes, &[throttle_cut(2, 3, Position::Down)]).unwrap();
+        let inputs = [0, 0, HALF];
+
+        assert_eq!(mixed(&inputs, &SWITCHED, &mixes)[2], HALF);
+        let mut cut = SWITCHED;
+        cut[3] = Position::Down;
+        assert_eq!(mixed(&inputs, &cut, &mixes)[2], -FULL);
+        // The other channels carry on
+        assert_eq!(mixed(&[HALF; INPUTS], &cut, &mixes)[..4], [HALF, HALF, -FULL, HALF]);
+    }
+
+    #[test]
+    fn tables_fill_up() {
+        let mut mixes = [None; MIXES];
+        mixes[1] = Some(Mix::new(Source::Max, 0, 100));
+        let flying_wing = elevon(Source::Input(0), Source::Input(1), 0, 1);
+        add(&mut mixes, &flying_wing).unwrap();
+        assert_eq!(mixes[0], None);
+        assert_eq!(&mixes[2..6], &flying_wing.map(Some)[..]);
+
+        for _ in 0..2 {
+            add(&mut mixes, &flying_wing).unwrap();
+        }
+        assert_eq!(add(&mut mixes, &flying_wing), Err(Full));
+        assert_eq!(mixes[14], None);
+        add(&mut mixes, &[Mix::new(Source::Max, 0, 100); 2]).unwrap();
+        assert_eq!(add(&mut mixes, &[]), Ok(()));
+        assert_eq!(add(&mut mixes, &[Mix::new(Source::Max, 0, 100)]), Err(Full));
+    }
+}
@@ -0,0 +1,156 @@
+// A model memory: everything the transmitter keeps for one model, so one
+// transmitter can fly several, each with its own receiver.
+//
+// Each stick is shaped by its expo or curve and its rate, and nudged by its
+// trim, then the mixes make the channels out of the sticks and switches, and
+// last the subtrims nudge the channels.
+
+use serde::{ Deserialize, Serialize };
+
+use protocol::{ Value, CHANNELS, bind::{ BindInfo, ModelId }, failsafe::DEFAULT_POSITIONS };
+
+use crate::{
+    centered, uncentered,
+    input::{ Switches, INPUTS },
+    mixer::{ self, Mixes, Sources },
+    shaping::{ Curve, Shaping },
+    trim::Trimming,
+};
+
+/// The number of model memories
+pub const MODELS: usize = 8;
+/// The most a model takes in the store. They all have to fit in a page.
+pub const MAX_STORED_SIZE: usize = 400;
+/// The number of curves each model has for its sticks
+pub const CURVES: usize = 4;
+pub const NAME_SIZE: usize = 8;
+
+/// What a model remembers about the receiver it's bound to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Pairing {
+    pub info: BindInfo,
+    /// The receiver's model ID, which it reported when it bound
+    pub model_id: ModelId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Model {
+    pub name: [u8; NAME_SIZE],
+    /// Only once it's bound
+    pub pairing: Option<Pairing>,
+    pub shaping: [Shaping; INPUTS],
+    pub curves: [Curve; CURVES],
+    /// Added to each stick, in channel value units
+    pub trims: [i16; INPUTS],
+    pub trimming: Trimming,
+    pub mixes: Mixes,
+    /// Added to each channel after mixing
+    pub subtrims: [i16; CHANNELS],
+    /// What the receiver outputs when the link is lost
+    pub failsafe: [Value; CHANNELS],
+}
+
+impl Model {
+    /// The `index`th model memory, before it's set up: unbound, and with
+    /// each input straight through to the channel with the same number
+    pub fn new(index: usize) -> Self {
+        let mut name = *b"MODEL   ";
+        name[6] = b'1' + index as u8;
+
+        Model {
+            name,
+            pairing: None,
+            shaping: [Shaping::LINEAR; INPUTS],
+            curves: [Curve::LINEAR; CURVES],
+            trims: [0; INPUTS],
+            trimming: Trimming::default(),
+            mixes: mixer::straight(),
+            subtrims: [0; CHANNELS],
+            failsafe: DEFAULT_POSITIONS,
+        }
+    }
+
+    /// The channel values for these calibrated inputs and switches
+    pub fn apply(&self, inputs: &[Value; INPUTS], switches: &Switches) -> [Value; CHANNELS] {
+        let mut values = [0; CHANNELS];
+        for ((value, channel), subtrim) in values.iter_mut().zip(self.mix(inputs, switches).iter()).zip(self.subtrims.iter()) {
+            *value = uncentered(channel + *subtrim as i32);
+        }
+        values
+    }
+
+    /// Move the trims into the subtrims, so the trims are centered again
+    /// with the model flying just the same
+    pub fn trims_to_subtrims(&mut self, switches: &Switches) {
+        let center = [0x8000; INPUTS];
+        let trimmed = self.mix(&center, switches);
+        self.trims = [0; INPUTS];
+        let untrimmed = self.mix(&center, switches);
+        for ((subtrim, trimmed), untrimmed) in self.subtrims.iter_mut().zip(trimmed.iter()).zip(untrimmed.iter()) {
+            *subtrim = (*subtrim as i32 + trimmed - untrimmed).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+    }
+
+    fn mix(&self, inputs: &[Value; INPUTS], switches: &Switches) -> [i32; CHANNELS] {
+        let mut sticks = [0; INPUTS];
+        for (stick, ((input, shaping), trim)) in sticks.iter_mut()
+            .zip(inputs.iter().zip(self.shaping.iter()).zip(self.trims.iter())) {
+            *stick = shaping.apply(centered(*input), switches, &self.curves) + *trim as i32;
+        }
+
+        let sources = Sources { inputs: &sticks, trims: &self.trims, switches };
+        mixer::mix(&sources, &self.mixes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ input::Position, mixer::{ Mix, Source } };
+
+    const SWITCHES: Switches = [Position::Up; crate::input::SWITCHES];
+
+    #[test]
+    fn straight_through() {
+        let values = [0, 0x8000, 0xffff, 0x4000, 0x1000, 0xc000];
+        let channels = Model::new(0).apply(&values, &SWITCHES);
+        assert_eq!(channels[..INPUTS], values);
+        // The switches are all up, and nothing's mixed into the rest
+        assert_eq!(channels[INPUTS..INPUTS + SWITCHES.len()], [0; 4]);
+        assert!(channels[INPUTS + SWITCHES.len()..].iter().all(|channel| *channel == 0x8000));
+        assert_eq!(&Model::new(2).name, b"MODEL 3 ");
+    }
+
+    #[test]
+    fn fits_in_the_store() {
+        let mut model = Model::new(0);
+        model.pairing = Some(Pairing { info: BindInfo::generate([1, 2, 3, 4]), model_id: 0xff });
+        model.mixes = [Some(mixer::throttle_cut(0, 0, Position::Down)); mixer::MIXES];
+        model.trims = [i16::MIN; INPUTS];
+        let mut buffer = [0; 2 * storage::MAX_SIZE];
+        let size = postcard::to_slice(&model, &mut buffer).unwrap().len();
+        assert!(size <= MAX_STORED_SIZE, "{} bytes", size);
+    }
+
+    #[test]
+    fn the_whole_pipeline() {
+        let mut model = Model::new(0);
+        model.mixes = [None; mixer::MIXES];
+        mixer::add(&mut model.mixes, &[
+            Mix::new(Source::Input(2), 0, 100),
+            Mix::new(Source::Input(3), 0, 100),
+            Mix::new(Source::Input(1), 1, 100),
+            Mix::new(Source::Input(0), 2, 100),
+            Mix::new(Source::Input(3), 3, 100),
+        ]).unwrap();
+        model.shaping[1].rates[0].rate = 50;
+        model.shaping[0].rates[0].expo = 100;
+        model.trims[3] = -0x80;
+        model.subtrims[3] = -0x80;
+
+        // A mix or a trim can't take a channel past the end of its travel
+        let values = model.apply(&[0x4000, 0x0000, 0xffff, 0xc000, 0x8000, 0x8000], &SWITCHES);
+        assert_eq!(values[..4], [0xffff, 0x4000, 0x7000, 0xbf00]);
+        assert!(values[4..].iter().all(|value| *value == 0x8000));
+    }
+}
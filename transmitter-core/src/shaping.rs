@@ -0,0 +1,283 @@
+// Shapes a stick's response before it's mixed: expo softens it around
+// center, for finer control there, or a curve reshapes it altogether, and the
+// rate scales it. A switch can choose between two or three rates, each with
+// its own expo. It's fixed point, since the F1 has no FPU. Values are centered
+// on zero, with full travel at ±0x8000.
+
+use serde::{ Deserialize, Serialize };
+
+use crate::input::Position;
+
+const FULL: i64 = 0x8000;
+
+/// The highest rate, in percent
+pub const MAX_RATE: u8 = 150;
+/// The furthest a stick can be shaped from center: 150%, which SUMD calls
+/// EXTENDED_LOW and EXTENDED_HIGH
+pub const EXTENDED: i32 = FULL as i32 * MAX_RATE as i32 / 100;
+
+/// The most points a curve can have
+pub const POINTS: usize = 9;
+
+/// `percent` of the way from linear to cubic
+pub fn expo(value: i32, percent: u8) -> i32 {
+    let (value, percent) = (value as i64, percent.min(100) as i64);
+    let cubic = value * value * value / (FULL * FULL);
+    ((value * (100 - percent) + cubic * percent) / 100) as i32
+}
+
+/// `percent` of full travel, up to MAX_RATE
+pub fn rate(value: i32, percent: u8) -> i32 {
+    value * percent.min(MAX_RATE) as i32 / 100
+}
+
+/// A curve through points spaced evenly across the stick's travel, with
+/// straight lines between them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Curve {
+    /// At least two, and at most POINTS
+    pub count: u8,
+    /// Percent of full travel, from full low to full high
+    pub points: [i8; POINTS],
+}
+
+impl Curve {
+    /// Straight through
+    pub const LINEAR: Curve = Curve { count: 2, points: [-100, 100, 0, 0, 0, 0, 0, 0, 0] };
+
+    /// A curve through these points
+    pub fn new(points: &[i8]) -> Self {
+        let mut curve = Curve { count: points.len().clamp(2, POINTS) as u8, points: [0; POINTS] };
+        for (point, value) in curve.points.iter_mut().zip(points.iter()) {
+            *point = *value;
+        }
+        curve
+    }
+
+    fn points(&self) -> &[i8] {
+        &self.points[..(self.count as usize).clamp(2, POINTS)]
+    }
+
+    /// Whether pushing the stick further never moves the output back
+    pub fn is_monotonic(&self) -> bool {
+        let points = self.points();
+        points.windows(2).all(|pair| pair[0] <= pair[1]) || points.windows(2).all(|pair| pair[0] >= pair[1])
+    }
+
+    pub fn apply(&self, value: i32) -> i32 {
+        let points = self.points();
+        let segments = points.len() as i64 - 1;
+        let width = 2 * FULL;
+        // How far along the curve, in units of a segment's width
+        let along = (value as i64 + FULL).clamp(0, width) * segments;
+        let segment = (along / width).min(segments - 1);
+        let (from, to) = (points[segment as usize] as i64, points[segment as usize + 1] as i64);
+        let percent = from * width + (to - from) * (along - segment * width);
+        (percent * FULL / (100 * width)) as i32
+    }
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Curve::LINEAR
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    /// Percent of full travel, up to MAX_RATE
+    pub rate: u8,
+    /// Percent: 0 is linear, and 100 cubic
+    pub expo: u8,
+}
+
+impl Rate {
+    pub const FULL: Rate = Rate { rate: 100, expo: 0 };
+}
+
+/// How a stick is shaped
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Shaping {
+    /// For the switch up, in the middle and down. Without a switch it's always
+    /// the first, and a two position switch only uses the first and last.
+    pub rates: [Rate; 3],
+    pub switch: Option<u8>,
+    /// One of the model's curves, instead of the expo
+    pub curve: Option<u8>,
+}
+
+impl Shaping {
+    /// Straight through
+    pub const LINEAR: Shaping = Shaping { rates: [Rate::FULL; 3], switch: None, curve: None };
+
+    /// The rate the switch has chosen
+    pub fn rate(&self, switches: &[Position]) -> Rate {
+        let position = self.switch.and_then(|switch| switches.get(switch as usize));
+        match position {
+            None | Some(Position::Up) => self.rates[0],
+            Some(Position::Middle) => self.rates[1],
+            Some(Position::Down) => self.rates[2],
+        }
+    }
+
+    pub fn apply(&self, value: i32, switches: &[Position], curves: &[Curve]) -> i32 {
+        let chosen = self.rate(switches);
+        let shaped = match self.curve.and_then(|curve| curves.get(curve as usize)) {
+            Some(curve) => curve.apply(value),
+            None => expo(value, chosen.expo),
+        };
+        rate(shaped, chosen.rate).clamp(-EXTENDED, EXTENDED)
+    }
+}
+
+impl Default for Shaping {
+    fn default() -> Self {
+        Shaping::LINEAR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::random::XorShift32;
+
+    const MIN: i32 = -FULL as i32;
+    const MAX: i32 = FULL as i32 - 1;
+
+    // Every stick position, a step at a time
+    fn sweep(step: usize) -> impl Iterator<Item = i32> {
+        (MIN..=MAX).step_by(step).chain(core::iter::once(MAX))
+    }
+
+    fn random_percent(random: &mut XorShift32, max: u32) -> u8 {
+        (random.next_u32() % (max + 1)) as u8
+    }
+
+    fn random_curve(random: &mut XorShift32) -> Curve {
+        let count = 2 + random.next_u32() as usize % (POINTS - 1);
+        let mut points = [0; POINTS];
+        for point in points.iter_mut().take(count) {
+            *point = random.next_u32() as i8;
+        }
+        Curve { count: count as u8, points }
+    }
+
+    fn assert_monotonic_and_bounded(shape: impl Fn(i32) -> i32, monotonic: bool, what: &dyn core::fmt::Debug) {
+        let mut last = None;
+        for value in sweep(61) {
+            let shaped = shape(value);
+            assert!((-EXTENDED..=EXTENDED).contains(&shaped), "{:?} at {} is {}", what, value, shaped);
+            if let (true, Some(last)) = (monotonic, last) {
+                assert!(shaped >= last, "{:?} goes back at {}", what, value);
+            }
+            last = Some(shaped);
+        }
+    }
+
+    #[test]
+    fn expo_keeps_the_ends() {
+        for percent in [0, 30, 100].iter() {
+            assert_eq!(expo(0, *percent), 0);
+            assert_eq!(expo(0x8000, *percent), 0x8000);
+            assert_eq!(expo(-0x8000, *percent), -0x8000);
+        }
+        assert_eq!(expo(0x4000, 0), 0x4000);
+        assert_eq!(expo(0x4000, 100), 0x1000);
+        assert_eq!(expo(-0x4000, 50), -0x2800);
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(rate(0x8000, 100), 0x8000);
+        assert_eq!(rate(-0x8000, 50), -0x4000);
+        assert_eq!(rate(-0x8000, 200), -EXTENDED);
+    }
+
+    #[test]
+    fn curves() {
+        let curve = Curve::new(&[-100, -20, 0, 60, 80]);
+        let cases = [
+            (MIN, -0x8000),
+            (-0x6000, -0x4ccc),
+            (-0x4000, -0x1999),
+            (0, 0),
+            (0x2000, 0x2666),
+            (0x4000, 0x4ccc),
+            (0x6000, 0x5999),
+            (0x8000, 0x6666),
+        ];
+        for (value, expected) in cases.iter() {
+            assert_eq!(curve.apply(*value), *expected, "at {}", value);
+        }
+        assert!(curve.is_monotonic());
+        assert!(!Curve::new(&[0, 100, 0]).is_monotonic());
+        assert!(Curve::new(&[100, 0, -100]).is_monotonic());
+
+        for value in sweep(997) {
+            assert_eq!(Curve::LINEAR.apply(value), value);
+        }
+    }
+
+    #[test]
+    fn switched_rates() {
+        let shaping = Shaping {
+            rates: [Rate { rate: 100, expo: 0 }, Rate { rate: 50, expo: 0 }, Rate { rate: 25, expo: 100 }],
+            switch: Some(1),
+            curve: None,
+        };
+        let mut switches = [Position::Down, Position::Up];
+        assert_eq!(shaping.apply(0x4000, &switches, &[]), 0x4000);
+        switches[1] = Position::Middle;
+        assert_eq!(shaping.apply(0x4000, &switches, &[]), 0x2000);
+        switches[1] = Position::Down;
+        assert_eq!(shaping.apply(0x4000, &switches, &[]), 0x400);
+
+        // Without the switch, it's the first rate
+        assert_eq!(shaping.apply(0x4000, &switches[..1], &[]), 0x4000);
+        assert_eq!(Shaping { switch: None, ..shaping }.apply(0x4000, &switches, &[]), 0x4000);
+    }
+
+    #[test]
+    fn curves_replace_the_expo() {
+        let curves = [Curve::LINEAR, Curve::new(&[-50, 0, 50])];
+        let rates = [Rate { rate: 50, expo: 100 }; 3];
+        let shaping = Shaping { rates, switch: None, curve: Some(1) };
+        assert_eq!(shaping.apply(0x8000, &[], &curves), 0x2000);
+        // A curve the model doesn't have is left out
+        assert_eq!(Shaping { curve: Some(2), ..shaping }.apply(0x4000, &[], &curves), 0x800);
+    }
+
+    #[test]
+    fn expo_and_rates_are_monotonic_and_bounded() {
+        let mut random = XorShift32::new(18);
+        for _ in 0..200 {
+            let chosen = Rate { rate: random_percent(&mut random, 255), expo: random_percent(&mut random, 255) };
+            let shaping = Shaping { rates: [chosen; 3], ..Shaping::LINEAR };
+            assert_monotonic_and_bounded(|value| shaping.apply(value, &[], &[]), true, &chosen);
+        }
+    }
+
+    #[test]
+    fn curves_are_bounded_and_monotonic_when_their_points_are() {
+        let mut random = XorShift32::new(18);
+        let mut monotonic = 0;
+        for _ in 0..500 {
+            let mut curve = random_curve(&mut random);
+            // Half of them sorted, so they're monotonic
+            if random.next_u32().is_multiple_of(2) {
+                curve.points[..curve.count as usize].sort_unstable();
+            }
+            let rate = random_percent(&mut random, MAX_RATE as u32);
+            let shaping = Shaping { rates: [Rate { rate, expo: 0 }; 3], switch: None, curve: Some(0) };
+            if curve.is_monotonic() {
+                monotonic += 1;
+            }
+
+            // Falling curves are turned over, so they should rise
+            let rising = curve.points[curve.count as usize - 1] >= curve.points[0];
+            let sign = if rising { 1 } else { -1 };
+            assert_monotonic_and_bounded(|value| sign * shaping.apply(value, &[], &[curve]), curve.is_monotonic(), &curve);
+        }
+        assert!(monotonic > 250);
+    }
+}
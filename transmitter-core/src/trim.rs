@@ -0,0 +1,199 @@
+// Digital trims: a pair of buttons for each stick nudges its trim a step at a
+// time, and repeats while it's held. A trim stops at center on the way
+// through, so it's easy to find again, and at its limit. Holding both buttons
+// of a pair moves all the trims into the subtrims.
+//
+// Each step gives some feedback, for the firmware to flash or beep, and the
+// model should be stored once the buttons are all released.
+
+use serde::{ Deserialize, Serialize };
+
+use crate::{ input::{ Switches, STICKS }, model::Model };
+
+// In ticks, i.e. 10mS
+const REPEAT_DELAY: u16 = 50;
+const REPEAT_INTERVAL: u16 = 10;
+const TRANSFER_HOLD: u16 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Trimming {
+    /// In channel value units, where full travel is 0x8000
+    pub step: u16,
+    /// The furthest a trim goes either way
+    pub limit: u16,
+}
+
+impl Default for Trimming {
+    // A quarter of full travel, in 32 steps
+    fn default() -> Self {
+        Trimming { step: 0x100, limit: 0x2000 }
+    }
+}
+
+/// The trim buttons for one stick
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Buttons {
+    pub down: bool,
+    pub up: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feedback {
+    Step,
+    Center,
+    Limit,
+    /// The trims went into the subtrims
+    Transfer,
+}
+
+/// Move the trim a step, stopping at center and at the limit
+pub fn step(trim: &mut i16, up: bool, trimming: &Trimming) -> Feedback {
+    let (old, step, limit) = (*trim as i32, trimming.step as i32, trimming.limit.min(i16::MAX as u16) as i32);
+    let mut new = if up { old + step } else { old - step }.clamp(-limit, limit);
+    if old != 0 && new.signum() != old.signum() {
+        new = 0;
+    }
+    *trim = new as i16;
+
+    if new == 0 {
+        Feedback::Center
+    } else if new.abs() == limit {
+        Feedback::Limit
+    } else {
+        Feedback::Step
+    }
+}
+
+#[derive(Default)]
+pub struct Trimmer {
+    // How many ticks each stick's buttons have been held
+    held: [u16; STICKS],
+    changed: bool,
+}
+
+impl Trimmer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trim the model with the buttons, once a tick
+    pub fn update(&mut self, buttons: &[Buttons; STICKS], model: &mut Model, switches: &Switches) -> Option<Feedback> {
+        let mut feedback = None;
+        for (stick, (buttons, held)) in buttons.iter().zip(self.held.iter_mut()).enumerate() {
+            if !buttons.up && !buttons.down {
+                *held = 0;
+                continue;
+            }
+            *held = held.saturating_add(1);
+
+            let repeat = *held == 1 || (*held > REPEAT_DELAY && (*held - REPEAT_DELAY).is_multiple_of(REPEAT_INTERVAL));
+            let event = match (buttons.down, buttons.up) {
+                (true, true) if *held == TRANSFER_HOLD => {
+                    model.trims_to_subtrims(switches);
+                    Some(Feedback::Transfer)
+                },
+                (true, true) => None,
+                (down, _) if repeat => Some(step(&mut model.trims[stick], !down, &model.trimming)),
+                _ => None,
+            };
+            self.changed |= event.is_some();
+            feedback = feedback.or(event);
+        }
+        feedback
+    }
+
+    /// Whether the trims have changed, and the buttons are all released, so
+    /// it's time to store the model. Only true once for each change.
+    pub fn settled(&mut self) -> bool {
+        let settled = self.changed && self.held.iter().all(|held| *held == 0);
+        self.changed &= !settled;
+        settled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{ Position, INPUTS, SWITCHES };
+
+    const NO_SWITCHES: Switches = [Position::Up; SWITCHES];
+    const RELEASED: [Buttons; STICKS] = [Buttons { down: false, up: false }; STICKS];
+
+    fn pressing(stick: usize, down: bool, up: bool) -> [Buttons; STICKS] {
+        let mut buttons = RELEASED;
+        buttons[stick] = Buttons { down, up };
+        buttons
+    }
+
+    #[test]
+    fn steps() {
+        let trimming = Trimming { step: 10, limit: 25 };
+        let cases = [
+            (0, true, 10, Feedback::Step),
+            (10, true, 20, Feedback::Step),
+            (20, true, 25, Feedback::Limit),
+            (25, true, 25, Feedback::Limit),
+            (25, false, 15, Feedback::Step),
+            (5, false, 0, Feedback::Center),
+            (10, false, 0, Feedback::Center),
+            (0, false, -10, Feedback::Step),
+            (-5, true, 0, Feedback::Center),
+            (-20, false, -25, Feedback::Limit),
+        ];
+        for (trim, up, expected, feedback) in cases.iter() {
+            let mut trimmed = *trim;
+            assert_eq!(step(&mut trimmed, *up, &trimming), *feedback, "from {}", trim);
+            assert_eq!(trimmed, *expected, "from {}", trim);
+        }
+    }
+
+    #[test]
+    fn repeats_while_held() {
+        let mut model = Model::new(0);
+        let mut trimmer = Trimmer::new();
+        let mut steps = 0;
+        for _ in 0..100 {
+            if trimmer.update(&pressing(1, false, true), &mut model, &NO_SWITCHES).is_some() {
+                steps += 1;
+            }
+            assert!(!trimmer.settled());
+        }
+
+        // Once straight away, and every 100mS after half a second
+        assert_eq!(steps, 6);
+        assert_eq!(model.trims, [0, 6 * 0x100, 0, 0, 0, 0]);
+
+        assert_eq!(trimmer.update(&RELEASED, &mut model, &NO_SWITCHES), None);
+        assert!(trimmer.settled());
+        assert!(!trimmer.settled());
+    }
+
+    #[test]
+    fn trims_move_the_channels() {
+        let mut model = Model::new(0);
+        let mut trimmer = Trimmer::new();
+        for _ in 0..2 {
+            trimmer.update(&pressing(2, true, false), &mut model, &NO_SWITCHES);
+            trimmer.update(&RELEASED, &mut model, &NO_SWITCHES);
+        }
+        assert_eq!(model.apply(&[0x8000; INPUTS], &NO_SWITCHES)[..STICKS], [0x8000, 0x8000, 0x7e00, 0x8000]);
+    }
+
+    #[test]
+    fn transfers_to_the_subtrims() {
+        let mut model = Model::new(0);
+        model.trims = [0x100, 0, -0x200, 0, 0, 0];
+        let centered = [0x8000; INPUTS];
+        let before = model.apply(&centered, &NO_SWITCHES);
+
+        let mut trimmer = Trimmer::new();
+        let mut feedback = None;
+        for _ in 0..TRANSFER_HOLD {
+            feedback = feedback.or(trimmer.update(&pressing(0, true, true), &mut model, &NO_SWITCHES));
+        }
+        assert_eq!(feedback, Some(Feedback::Transfer));
+        assert_eq!(model.trims, [0; INPUTS]);
+        assert_eq!(model.subtrims[..STICKS], [0x100, 0, -0x200, 0]);
+        assert_eq!(model.apply(&centered, &NO_SWITCHES), before);
+    }
+}
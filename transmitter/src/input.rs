@@ -0,0 +1,53 @@
+// The joysticks and the pots are read by the ADC, scanning all six channels
+// by DMA: the sticks on PA0 to PA3, and the pots on PA4 and PB1.
+
+use core::convert::Infallible;
+
+use stm32f1xx_hal::{
+    adc::{ self, Adc, AdcDma, Scan, SetChannels },
+    gpio::{ Analog, gpioa::{ PA0, PA1, PA2, PA3, PA4 }, gpiob::PB1 },
+    stm32::ADC1,
+};
+
+use transmitter_core::{ InputSource, Inputs, input::INPUTS };
+
+pub struct JoystickAdcPins(
+    pub PA0<Analog>, pub PA1<Analog>, pub PA2<Analog>, pub PA3<Analog>,
+    pub PA4<Analog>, pub PB1<Analog>,
+);
+
+// The ADC channel of each pin, in the order of the inputs
+const SEQUENCE: [u8; INPUTS] = [0, 1, 2, 3, 4, 9];
+
+impl SetChannels<JoystickAdcPins> for Adc<ADC1> {
+    fn set_samples(&mut self) {
+        for channel in SEQUENCE.iter() {
+            self.set_channel_sample_time(*channel, adc::SampleTime::T_28);
+        }
+    }
+
+    fn set_sequence(&mut self) {
+        self.set_regular_sequence(&SEQUENCE);
+    }
+}
+
+pub struct Joysticks(Option<(AdcDma<JoystickAdcPins, Scan>, &'static mut Inputs)>);
+
+impl Joysticks {
+    pub fn new(scan: AdcDma<JoystickAdcPins, Scan>, buffer: &'static mut Inputs) -> Self {
+        Joysticks(Some((scan, buffer)))
+    }
+}
+
+impl InputSource for Joysticks {
+    type Error = Infallible;
+
+    // A scan takes a few µS, so it's quicker to wait for it than to come back
+    fn read(&mut self) -> nb::Result<Inputs, Infallible> {
+        let (scan, buffer) = self.0.take().unwrap();
+        let (buffer, scan) = scan.read(buffer).wait();
+        let inputs = *buffer;
+        self.0 = Some((scan, buffer));
+        Ok(inputs)
+    }
+}
@@ -5,34 +5,42 @@
 
 extern crate panic_semihosting;
 
-use core::{
-	option::Option,
-	convert::Infallible,
-};
+use core::convert::Infallible;
 
 use cortex_m::{ singleton };
-use sumd::{ self, SumdBuffer, Status };
+use protocol::bind::{ self, BindInfo };
+use storage::Store;
+use transmitter_core::{
+    Calibration, Calibrator, InputSource, Model, Transmitter, Trimmer, MODELS,
+    input::INPUTS, trim::Feedback,
+};
 
 use embedded_nrf24l01::{
-    NRF24L01, StandbyMode, Configuration, DataRate, CrcMode
+    NRF24L01, Configuration, DataRate, CrcMode
 };
 
 use stm32f1xx_hal::{
 	self,
     prelude::*,
-    adc::{ self, Adc, AdcDma, Scan, SetChannels },
+    adc::{ self, Adc },
     pac,
     gpio::{ 
-        Alternate, Analog, Floating, Input, Output, PushPull, State,
-        gpioa::{ PA0, PA1, PA2, PA3, 
+        Alternate, Floating, Input, Output, PullUp, PushPull, State,
+        gpioa::{
             PA5, // SCLK 
             PA6, // MISO
-            PA7  // MOSI
+            PA7, // MOSI
+            PA8, // CSN
+            // PA0 to PA4 are the sticks and the first pot, and PA9, PA10 and
+            // PA15 are switches
         },
         gpiob::{
             PB0,  // CE
-            PB1,  // CSN
+            // PB1 is the second pot, and PB3, PB4 and PB15 are switches
             PB12, // LED
+            PB13, // Bind button: held down at power on to bind, pressed later to calibrate,
+                  // and held later to select the next model
+            // PB5 to PB11 and PB14 are the trim buttons
         },
     },
     spi::{ Mode, Phase, Polarity, Spi, Spi1NoRemap },
@@ -42,7 +50,7 @@ use stm32f1xx_hal::{
 
 
 type RadioCe = PB0<Output<PushPull>>;
-type RadioCsn = PB1<Output<PushPull>>;
+type RadioCsn = PA8<Output<PushPull>>;
 
 type RadioSpi = Spi<SPI1, Spi1NoRemap, 
     (PA5<Alternate<PushPull>>, 
@@ -51,29 +59,46 @@ type RadioSpi = Spi<SPI1, Spi1NoRemap,
 
 type Radio = NRF24L01<Infallible, RadioCe, RadioCsn, RadioSpi>;
 
-pub struct JoystickAdcPins(PA0<Analog>, PA1<Analog>, PA2<Analog>, PA3<Analog>);
-
-impl SetChannels<JoystickAdcPins> for Adc<ADC1> {
-    fn set_samples(&mut self) {
-        self.set_channel_sample_time(0, adc::SampleTime::T_28);
-        self.set_channel_sample_time(1, adc::SampleTime::T_28);
-        self.set_channel_sample_time(2, adc::SampleTime::T_28);
-        self.set_channel_sample_time(3, adc::SampleTime::T_28);
-    }
+// Ticks are 10mS, so holding the button for 2S selects the next model
+const LONG_PRESS: u32 = 200;
 
-    fn set_sequence(&mut self) {
-        self.set_regular_sequence(&[0, 1, 2, 3]);
-    }
-}
+mod input;
+mod radio;
+mod settings;
+mod switches;
+mod trims;
 
+use input::{ JoystickAdcPins, Joysticks };
+use radio::Nrf24;
+use settings::Flash;
+use switches::SwitchPins;
+use trims::TrimButtons;
 
 #[rtic::app(device = stm32f1xx_hal::pac, peripherals=true)]
 const APP: () = {
     struct Resources {
-        radio: Option<StandbyMode<Radio>>,
-        joystick_scan: Option<(AdcDma<JoystickAdcPins, Scan>,&'static mut [u16; 4])>,
+        transmitter: Transmitter<Nrf24<Radio>>,
+        joysticks: Joysticks,
+        calibration: Calibration,
+        // Only while calibrating
+        calibrator: Option<Calibrator>,
+        bind_button: PB13<Input<PullUp>>,
+        // How many ticks the button has been down for
+        button_held: u32,
+        ticks: u32,
+        model: Model,
+        // The index of the model memory in use
+        selected: usize,
+        // For binding models later, when the ADC belongs to the joysticks
+        entropy: [u32; 4],
         timer: CountDownTimer<pac::TIM1>,
-        led: PB12<Output<PushPull>>
+        led: PB12<Output<PushPull>>,
+        store: Store<Flash>,
+        trim_buttons: TrimButtons,
+        trimmer: Trimmer,
+        switches: SwitchPins,
+        // Ticks left of flashing the LED, for the trims
+        flash: u32,
     }
 
     #[init]
@@ -92,12 +117,28 @@ const APP: () = {
 
         let mut gpiob = cx.device.GPIOB.split(&mut rcc.apb2);
         let mut led = gpiob.pb12.into_push_pull_output_with_state(&mut gpiob.crh, State::Low);
+        let bind_button = gpiob.pb13.into_pull_up_input(&mut gpiob.crh);
+        let trim_buttons = TrimButtons([
+            (gpiob.pb5.into_pull_up_input(&mut gpiob.crl).downgrade(), gpiob.pb6.into_pull_up_input(&mut gpiob.crl).downgrade()),
+            (gpiob.pb7.into_pull_up_input(&mut gpiob.crl).downgrade(), gpiob.pb8.into_pull_up_input(&mut gpiob.crh).downgrade()),
+            (gpiob.pb9.into_pull_up_input(&mut gpiob.crh).downgrade(), gpiob.pb10.into_pull_up_input(&mut gpiob.crh).downgrade()),
+            (gpiob.pb11.into_pull_up_input(&mut gpiob.crh).downgrade(), gpiob.pb14.into_pull_up_input(&mut gpiob.crh).downgrade()),
+        ]);
 
         // Prepare the GPIO peripherals
         let mut gpioa = cx.device.GPIOA.split(&mut rcc.apb2);
 
         let ce = gpiob.pb0.into_push_pull_output(&mut gpiob.crl);
-        let csn = gpiob.pb1.into_push_pull_output(&mut gpiob.crl);
+        let csn = gpioa.pa8.into_push_pull_output(&mut gpioa.crh);
+
+        // SB and SC are on JTAG pins, which leaves SWD for the debugger
+        let (pa15, pb3, pb4) = afio.mapr.disable_jtag(gpioa.pa15, gpiob.pb3, gpiob.pb4);
+        let switches = SwitchPins {
+            sa: (gpioa.pa9.into_pull_up_input(&mut gpioa.crh), gpioa.pa10.into_pull_up_input(&mut gpioa.crh)),
+            sb: (pa15.into_pull_up_input(&mut gpioa.crh), pb3.into_pull_up_input(&mut gpiob.crl)),
+            sc: pb4.into_pull_up_input(&mut gpiob.crl),
+            sd: gpiob.pb15.into_pull_up_input(&mut gpiob.crh),
+        };
 
         let spi_pins = (
             gpioa.pa5.into_alternate_push_pull(&mut gpioa.crl),
@@ -120,23 +161,53 @@ const APP: () = {
             &mut rcc.apb2
         );
 
+	    let mut joystick_adc = adc::Adc::adc1(cx.device.ADC1, &mut rcc.apb2, clocks);
+
+        // The transmitter sets the frequency for each message, and the address
         let mut radio = NRF24L01::new(ce, csn, spi).unwrap();
-        radio.set_frequency(protocol::FREQUENCY).unwrap();
-        radio.set_rx_addr(0, &protocol::RX_ADDRESS).unwrap();
-        radio.set_tx_addr(&protocol::RX_ADDRESS).unwrap();
+        radio.set_frequency(bind::BIND_FREQUENCY).unwrap();
         radio.set_rf(DataRate::R250Kbps, 0).unwrap();
         radio.set_auto_retransmit(0b0100, 15).unwrap();
+        radio.set_auto_ack(&[ true; 6 ]).unwrap();
+        radio.set_ack_payloads(true).unwrap();
         radio.set_crc(Some(CrcMode::TwoBytes)).unwrap();
         radio.set_pipes_rx_lengths(&[ None; 6]).unwrap();
         radio.flush_tx().unwrap();
         radio.flush_rx().unwrap();
 
-	    let joystick_adc = adc::Adc::adc1(cx.device.ADC1, &mut rcc.apb2, clocks);
+        let mut store = settings::open(flash);
+        let selected = store.load_or_default::<u8>(settings::SELECTED) as usize % MODELS;
+        let model = store.load(settings::model(selected))
+            .unwrap_or_else(|| Model::new(selected));
+
+        let entropy = [
+            entropy(&mut joystick_adc),
+            entropy(&mut joystick_adc),
+            entropy(&mut joystick_adc),
+            entropy(&mut joystick_adc),
+        ];
+
+        // Bind if the button is held down, or the model has no receiver to talk to
+        let mut transmitter = match model.pairing {
+            Some(pairing) if bind_button.is_high().unwrap() => {
+                match settings::next_session(&mut store, selected) {
+                    Ok(Some(session)) => Transmitter::bound(Nrf24::new(radio), &pairing, session),
+                    // Out of sessions, so it needs a new key, or the session
+                    // couldn't be saved, and the next power up would repeat it
+                    Ok(None) | Err(_) => Transmitter::binding(Nrf24::new(radio), BindInfo::generate(entropy)),
+                }
+            },
+            _ => Transmitter::binding(Nrf24::new(radio), BindInfo::generate(entropy)),
+        };
+        transmitter.set_failsafe(model.failsafe);
+
     	let joystick_channels = JoystickAdcPins(
         	gpioa.pa0.into_analog(&mut gpioa.crl),
         	gpioa.pa1.into_analog(&mut gpioa.crl),
         	gpioa.pa2.into_analog(&mut gpioa.crl),
-        	gpioa.pa3.into_analog(&mut gpioa.crl)
+        	gpioa.pa3.into_analog(&mut gpioa.crl),
+        	gpioa.pa4.into_analog(&mut gpioa.crl),
+        	gpiob.pb1.into_analog(&mut gpiob.crl),
     	);
 
 	    let dma_ch1 = cx.device.DMA1.split(&mut rcc.ahb).1;
@@ -147,51 +218,178 @@ const APP: () = {
         timer.listen(Event::Update);
         led.toggle().unwrap();
 
+        let calibration = store.load(settings::CALIBRATION)
+            .filter(Calibration::is_valid)
+            .unwrap_or_default();
+
         init::LateResources { 
-            radio: Some(radio),
-            joystick_scan: Some((joystick_scan, singleton!(: [u16; 4] = [0; 4]).unwrap())),
+            transmitter,
+            joysticks: Joysticks::new(joystick_scan, singleton!(: [u16; INPUTS] = [0; INPUTS]).unwrap()),
+            calibration,
+            calibrator: None,
+            // It may still be held down from binding, which isn't a press
+            button_held: if bind_button.is_low().unwrap() { LONG_PRESS + 1 } else { 0 },
+            bind_button,
+            ticks: 0,
+            model,
+            selected,
+            entropy,
             timer: timer,
             led: led,
+            store,
+            trim_buttons,
+            trimmer: Trimmer::new(),
+            switches,
+            flash: 0,
         }
     }
 
     #[task(binds = TIM1_UP, priority = 1, 
-        resources = [ joystick_scan, timer ],
-        spawn = [ transmit ])]
+        resources = [ joysticks, calibration, calibrator, bind_button, button_held, ticks, led, timer, model, entropy,
+            trim_buttons, trimmer, flash, switches ],
+        spawn = [ transmit, store_calibration, select, store_model ])]
     fn update(c: update::Context) {
-	   	let (joystick_scan, dma_buffer) = c.resources.joystick_scan.take().unwrap();
-        let (dma_buffer, joystick_scan) = joystick_scan.read(dma_buffer).wait();
-        let mut scaled : [u16; 4] = [0; 4];
-        for i in 0..4 {
-            scaled[i] = dma_buffer[i]<<4;
-        }
+        *c.resources.ticks = c.resources.ticks.wrapping_add(1);
+
+        // A press starts calibrating, and another finishes. A press only
+        // counts once it's released, because holding it selects the next model.
+        let down = c.resources.bind_button.is_low().unwrap();
+        let held = *c.resources.button_held;
+        let pressed = !down && held > 0 && held < LONG_PRESS;
+        *c.resources.button_held = if down { held.saturating_add(1) } else { 0 };
+        *c.resources.flash = c.resources.flash.saturating_sub(1);
+
+        if let Ok(inputs) = c.resources.joysticks.read() {
+            // Stir the noise in the least significant bits into the pool
+            let entropy = c.resources.entropy;
+            let tick = *c.resources.ticks as usize;
+            entropy[tick % 4] = entropy[tick % 4].rotate_left(5) ^ (inputs[tick % inputs.len()] as u32 & 0xf);
+
+            let calibrator = c.resources.calibrator;
+            match calibrator {
+                Some(calibrating) if pressed => {
+                    *c.resources.calibration = calibrating.finish(&inputs, c.resources.calibration);
+                    let _ = c.spawn.store_calibration(*c.resources.calibration);
+                    *calibrator = None;
+                },
+
+                // Nothing is sent while calibrating, so the receiver fails safe
+                // rather than the model following the sticks around
+                Some(calibrating) => {
+                    calibrating.update(&inputs);
+                    if *c.resources.ticks % 25 == 0 {
+                        c.resources.led.toggle().unwrap();
+                    }
+                },
+
+                None if pressed => *calibrator = Some(Calibrator::new(&inputs)),
+
+                None if down && held == LONG_PRESS - 1 => {
+                    let _ = c.spawn.select();
+                },
 
-        match c.spawn.transmit(scaled) {
-            Ok(_) => {},
-            Err(_) => {} // Don't care if the transmit queue is full - just throw away,
-                         // Maybe set an error status later
+                None => {
+                    let switches = c.resources.switches.read();
+                    let buttons = c.resources.trim_buttons.read();
+                    if let Some(feedback) = c.resources.trimmer.update(&buttons, c.resources.model, &switches) {
+                        *c.resources.flash = flash_ticks(feedback);
+                    }
+                    if c.resources.trimmer.settled() {
+                        let _ = c.spawn.store_model();
+                    }
+
+                    match c.spawn.transmit(transmitter_core::channels(&inputs, &switches, c.resources.calibration, c.resources.model)) {
+                        Ok(_) => {},
+                        Err(_) => {} // Don't care if the transmit queue is full - just throw away,
+                                     // Maybe set an error status later
+                    }
+                },
+            }
         }
-        *c.resources.joystick_scan = Some((joystick_scan, dma_buffer));
         c.resources.timer.clear_update_interrupt_flag();
     }
 
-    #[task(resources = [ radio, led ])]
-    fn transmit(c: transmit::Context, values: [u16; 4]) {
-		let mut standby = c.resources.radio.take().unwrap();
-        standby.flush_tx().unwrap();
-        standby.flush_rx().unwrap();
-    	let mut tx = standby.tx().unwrap();
-        let mut buf = SumdBuffer::new();
-        buf.encode(Status::Live, &values);
-		tx.send(&buf.0).unwrap();
-		match tx.wait_empty() {
-            Ok(_) => {},
-            Err(_) => {} // If we can't transmit this time, perhaps we can next time...
+    #[task(resources = [ store ])]
+    fn store_calibration(c: store_calibration::Context, calibration: Calibration) {
+        let _ = c.resources.store.save(settings::CALIBRATION, &calibration);
+    }
+
+    #[task(resources = [ store, model, selected ])]
+    fn store_model(c: store_model::Context) {
+        let _ = c.resources.store.save(settings::model(*c.resources.selected), &*c.resources.model);
+    }
+
+    // Switch to the next model memory, and bind it if it's never been bound
+    #[task(resources = [ transmitter, store, model, selected, entropy ])]
+    fn select(c: select::Context) {
+        let index = (*c.resources.selected + 1) % MODELS;
+        let store = c.resources.store;
+        let model = store.load(settings::model(index)).unwrap_or_else(|| Model::new(index));
+
+        let session = match model.pairing {
+            Some(_) => match settings::next_session(store, index) {
+                Ok(session) => session,
+                // Rather than pair with a session the receiver may have seen
+                // already, stay with the current model
+                Err(_) => return,
+            },
+            None => None,
+        };
+        let _ = store.save(settings::SELECTED, &(index as u8));
+
+        let transmitter = c.resources.transmitter;
+        match (model.pairing, session) {
+            (Some(pairing), Some(session)) => transmitter.pair(&pairing, session),
+            // Never bound, or out of sessions and needing a new key
+            _ => transmitter.bind(BindInfo::generate(*c.resources.entropy)),
+        }
+        transmitter.set_failsafe(model.failsafe);
+
+        *c.resources.selected = index;
+        *c.resources.model = model;
+    }
+
+    #[task(resources = [ transmitter, led, store, model, selected, flash ])]
+    fn transmit(c: transmit::Context, values: [protocol::Value; protocol::CHANNELS]) {
+        // The receiver has the bind info, so remember it with the model, and
+        // that binding was session 0 of the new key
+        if let Ok(Some(pairing)) = c.resources.transmitter.transmit(values) {
+            let selected = *c.resources.selected;
+            c.resources.model.pairing = Some(pairing);
+            let _ = c.resources.store.save(settings::model(selected), &*c.resources.model);
+            let _ = c.resources.store.save(settings::session(selected), &0u8);
+        }
+
+        // Light the LED to warn the pilot when the link is poor, or there's no telemetry at all,
+        // and flash it the other way when the trims move
+        if c.resources.transmitter.warning() != (*c.resources.flash > 0) {
+            c.resources.led.set_high().unwrap();
+        } else {
+            c.resources.led.set_low().unwrap();
         }
-        *c.resources.radio = Some(tx.standby().unwrap());
     }
 
     extern "C" {
         fn USART2();
     }
 };
+
+// How long the LED flashes for each trim step, in ticks. There's no buzzer
+// on this board.
+fn flash_ticks(feedback: Feedback) -> u32 {
+    match feedback {
+        Feedback::Step => 3,
+        Feedback::Center => 20,
+        Feedback::Limit => 50,
+        Feedback::Transfer => 100,
+    }
+}
+
+// The least significant bit of the internal reference is mostly noise
+fn entropy(adc: &mut Adc<ADC1>) -> u32 {
+    let mut entropy = 0;
+    for _ in 0..32 {
+        entropy = (entropy << 1) | (adc.read_vref() as u32 & 1);
+    }
+    entropy
+}
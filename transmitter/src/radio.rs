@@ -0,0 +1,53 @@
+// transmitter-core's link is the nRF24L01. It waits in standby between
+// messages, so the frequency can be changed.
+
+use embedded_nrf24l01::{ Configuration, Device, StandbyMode };
+
+use transmitter_core::{ Link, Payload };
+
+pub struct Nrf24<D: Device>(Option<StandbyMode<D>>);
+
+impl<D: Device> Nrf24<D> {
+    pub fn new(standby: StandbyMode<D>) -> Self {
+        Nrf24(Some(standby))
+    }
+}
+
+impl<D: Device> Link for Nrf24<D> {
+    type Error = D::Error;
+
+    fn send(&mut self, channel: u8, payload: &[u8]) -> Result<Option<Payload>, Self::Error> {
+        // Only if the radio stopped working
+        let mut standby = match self.0.take() {
+            Some(standby) => standby,
+            None => return Ok(None),
+        };
+        standby.flush_tx()?;
+        standby.flush_rx()?;
+        standby.set_frequency(channel)?;
+
+        let mut tx = standby.tx().map_err(|(_, error)| error)?;
+        tx.send(payload)?;
+        // If we can't transmit this time, perhaps we can next time...
+        let _ = tx.wait_empty();
+
+        let mut ack = None;
+        while let Ok(Some(_)) = tx.can_read() {
+            let received = tx.read()?;
+            let mut payload = [0; protocol::PAYLOAD_SIZE];
+            payload[..received.len()].copy_from_slice(&received);
+            ack = Some(payload);
+        }
+
+        self.0 = Some(tx.standby()?);
+        Ok(ack)
+    }
+
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), Self::Error> {
+        if let Some(standby) = &mut self.0 {
+            standby.set_rx_addr(0, address)?;
+            standby.set_tx_addr(address)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+// Settings which survive a power cycle are kept in the store, in the last 8K
+// of flash, which memory.x keeps out of the program's way.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use stm32f1xx_hal::flash::{ self, FlashSize, SectorSize };
+
+use storage::{ Key, Layout, Store, record_size };
+use transmitter_core::{ calibration, model, MODELS };
+
+// Keys 0 and 1 were the bind info and session, from before model memories.
+// Version 1 of the calibration had no pots.
+pub const CALIBRATION: Key = Key::new(2, 2);
+// The index of the model memory in use
+pub const SELECTED: Key = Key::new(3, 1);
+
+const LAYOUT: Layout = storage::layout::STM32F103X8;
+
+// Moving to a new page copies the latest of every setting, so they all have to
+// fit in one: each model and its session, the calibration, and which model
+const _: () = assert!(
+    model::MAX_STORED_SIZE <= storage::MAX_SIZE
+        && MODELS * (record_size(model::MAX_STORED_SIZE) + record_size(1))
+            + record_size(calibration::MAX_STORED_SIZE) + record_size(1)
+            <= storage::capacity(&LAYOUT));
+
+/// The STM32F103's flash, a 1K page at a time
+pub struct Flash(flash::Parts);
+
+#[derive(Debug)]
+pub struct Error(flash::Error);
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        let writer = self.0.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        bytes.copy_from_slice(writer.read(offset, bytes.len()).map_err(Error)?);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        64 * 1024
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 2;
+    const ERASE_SIZE: usize = 1024;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        let mut writer = self.0.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        writer.erase(from, (to - from) as usize).map_err(Error)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        let mut writer = self.0.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        writer.write(offset, bytes).map_err(Error)
+    }
+}
+
+pub fn open(flash: flash::Parts) -> Store<Flash> {
+    // Reading only fails outside the flash
+    Store::new(Flash(flash), LAYOUT).unwrap()
+}
+
+// Version 1 had no mixer, version 2 no curves, version 3 no subtrims, and
+// version 4 only four channels
+pub fn model(index: usize) -> Key {
+    Key::new(8 + index as u8, 5)
+}
+
+// The number of sessions since the model was bound
+pub fn session(index: usize) -> Key {
+    Key::new(16 + index as u8, 1)
+}
+
+/// Count another session for the model, and return its number. After the
+/// 255th, the correlation ids would start again from ones the receiver has
+/// already seen, so there's `None`, and the model has to be bound again with
+/// a new key. The session must be saved before it starts, or the next one
+/// would repeat it.
+pub fn next_session(store: &mut Store<Flash>, index: usize) -> Result<Option<u8>, storage::Error<Error>> {
+    match store.load_or_default::<u8>(self::session(index)).checked_add(1) {
+        Some(session) => {
+            store.save(self::session(index), &session)?;
+            Ok(Some(session))
+        },
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,32 @@
+// The switches: SA and SB are three position, with a contact at each end, and
+// SC and SD two position. Each contact pulls its pin low while it's closed.
+// SB and SC share pins with JTAG, which has to be turned off to free them.
+
+use embedded_hal::digital::v2::InputPin;
+use stm32f1xx_hal::gpio::{
+    Input, PullUp,
+    gpioa::{ PA9, PA10, PA15 },
+    gpiob::{ PB3, PB4, PB15 },
+};
+
+use transmitter_core::{ Position, Switches };
+
+pub struct SwitchPins {
+    /// Up, then down
+    pub sa: (PA9<Input<PullUp>>, PA10<Input<PullUp>>),
+    pub sb: (PA15<Input<PullUp>>, PB3<Input<PullUp>>),
+    /// Down
+    pub sc: PB4<Input<PullUp>>,
+    pub sd: PB15<Input<PullUp>>,
+}
+
+impl SwitchPins {
+    pub fn read(&self) -> Switches {
+        [
+            Position::from_contacts(self.sa.0.is_low().unwrap(), self.sa.1.is_low().unwrap()),
+            Position::from_contacts(self.sb.0.is_low().unwrap(), self.sb.1.is_low().unwrap()),
+            Position::two_position(self.sc.is_low().unwrap()),
+            Position::two_position(self.sd.is_low().unwrap()),
+        ]
+    }
+}
@@ -0,0 +1,22 @@
+// The trim buttons: a pair for each stick, on port B, each pulling its pin
+// low while it's pressed.
+
+use embedded_hal::digital::v2::InputPin;
+use stm32f1xx_hal::gpio::{ Input, PullUp, gpiob::PBx };
+
+use transmitter_core::{ input::STICKS, trim::Buttons };
+
+type Button = PBx<Input<PullUp>>;
+
+/// For each stick, its down button and then its up
+pub struct TrimButtons(pub [(Button, Button); STICKS]);
+
+impl TrimButtons {
+    pub fn read(&self) -> [Buttons; STICKS] {
+        let mut buttons = [Buttons::default(); STICKS];
+        for (buttons, (down, up)) in buttons.iter_mut().zip(self.0.iter()) {
+            *buttons = Buttons { down: down.is_low().unwrap(), up: up.is_low().unwrap() };
+        }
+        buttons
+    }
+}